@@ -0,0 +1,172 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use fver_proto::{verify_consistency, SignedCheckpoint};
+
+use crate::config::write_atomic;
+use crate::error::Error;
+use crate::error::Error::{InvalidCheckpoint, RollbackDetected, UntrustedServer};
+use crate::remotestorage::RemoteStorage;
+
+/// Fetches the current checkpoint and checks it against the pinned server key
+/// and the last checkpoint seen by this client. The flag is set when the server
+/// key was seen for the first time and pinned now.
+pub fn fetch_trusted(
+    storage: &mut RemoteStorage,
+    dir: &Path,
+) -> Result<(SignedCheckpoint, bool), Error> {
+    let signed = storage.get_checkpoint()?;
+    let key = &signed.key;
+    if !signed.verify()? {
+        return Err(InvalidCheckpoint);
+    }
+
+    // A new key is only pinned once the checkpoint has been checked
+    let key_path = dir.join("server_key");
+    let pinned = !key_path.exists();
+    if !pinned {
+        let mut pinned_key = Vec::new();
+        File::open(&key_path)?.read_to_end(&mut pinned_key)?;
+        if &pinned_key != key {
+            return Err(UntrustedServer);
+        }
+    }
+    let checkpoint = &signed.checkpoint;
+
+    let checkpoint_path = dir.join("checkpoint");
+    if checkpoint_path.exists() {
+        let mut file = File::open(&checkpoint_path)?;
+        let mut size = [0; 8];
+        file.read_exact(&mut size)?;
+        let size = u64::from_le_bytes(size);
+        let mut root = [0; 32];
+        file.read_exact(&mut root)?;
+        let mut timestamp = [0; 8];
+        file.read_exact(&mut timestamp)?;
+        let timestamp = u64::from_le_bytes(timestamp);
+
+        if checkpoint.size < size || checkpoint.timestamp < timestamp {
+            return Err(RollbackDetected);
+        }
+        let proof = if checkpoint.size > size {
+            storage.get_consistency_proof(size, checkpoint.size)?
+        } else {
+            Vec::new()
+        };
+        if !verify_consistency(size, checkpoint.size, &proof, &root, &checkpoint.root) {
+            return Err(RollbackDetected);
+        }
+    }
+
+    if pinned {
+        write_atomic(dir, "server_key", key)?;
+    }
+    let mut data = Vec::new();
+    data.extend_from_slice(&checkpoint.size.to_le_bytes());
+    data.extend_from_slice(&checkpoint.root);
+    data.extend_from_slice(&checkpoint.timestamp.to_le_bytes());
+    write_atomic(dir, "checkpoint", &data)?;
+    Ok((signed, pinned))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+    use crate::testserver::{key, test_dir, TestServer};
+    use std::fs::{read, remove_dir_all};
+
+    fn sign(server: &TestServer, identity: &Identity, count: u8) {
+        let mut state = server.state.lock().unwrap();
+        for n in 0..count {
+            state.sign(identity, [n; 32]);
+        }
+    }
+
+    #[test]
+    fn first_use_pins_key() {
+        let server = TestServer::start("checkpoint-pin");
+        let dir = test_dir("checkpoint-pin");
+        sign(&server, &Identity::generate("alice").unwrap(), 2);
+
+        // Nothing is pinned from a checkpoint that does not verify
+        let mut forged = server.state.lock().unwrap().signed_checkpoint();
+        forged.checkpoint.size = 1;
+        server.state.lock().unwrap().checkpoint = Some(forged);
+        let result = fetch_trusted(&mut server.connect(), &dir);
+        assert!(matches!(result, Err(InvalidCheckpoint)));
+        assert!(!dir.join("server_key").exists());
+        assert!(!dir.join("checkpoint").exists());
+
+        server.state.lock().unwrap().checkpoint = None;
+        let (signed, pinned) = fetch_trusted(&mut server.connect(), &dir).unwrap();
+        assert!(pinned);
+        assert_eq!(signed.checkpoint.size, 2);
+        assert_eq!(read(dir.join("server_key")).unwrap(), signed.key);
+        let (_, pinned) = fetch_trusted(&mut server.connect(), &dir).unwrap();
+        assert!(!pinned);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn other_server_key_is_rejected() {
+        let server = TestServer::start("checkpoint-key");
+        let dir = test_dir("checkpoint-key");
+        sign(&server, &Identity::generate("alice").unwrap(), 1);
+        let (signed, _) = fetch_trusted(&mut server.connect(), &dir).unwrap();
+        let checkpoint = read(dir.join("checkpoint")).unwrap();
+
+        server.state.lock().unwrap().key = key();
+        let result = fetch_trusted(&mut server.connect(), &dir);
+        assert!(matches!(result, Err(UntrustedServer)));
+        assert_eq!(read(dir.join("server_key")).unwrap(), signed.key);
+        assert_eq!(read(dir.join("checkpoint")).unwrap(), checkpoint);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rollback_is_rejected() {
+        let server = TestServer::start("checkpoint-rollback");
+        let dir = test_dir("checkpoint-rollback");
+        sign(&server, &Identity::generate("alice").unwrap(), 3);
+        server.state.lock().unwrap().timestamp = 10;
+        fetch_trusted(&mut server.connect(), &dir).unwrap();
+
+        server.state.lock().unwrap().log.pop();
+        let result = fetch_trusted(&mut server.connect(), &dir);
+        assert!(matches!(result, Err(RollbackDetected)));
+
+        sign(&server, &Identity::generate("bob").unwrap(), 1);
+        server.state.lock().unwrap().timestamp = 9;
+        let result = fetch_trusted(&mut server.connect(), &dir);
+        assert!(matches!(result, Err(RollbackDetected)));
+
+        // A log of the same size has to have the same root
+        server.state.lock().unwrap().timestamp = 10;
+        let result = fetch_trusted(&mut server.connect(), &dir);
+        assert!(matches!(result, Err(RollbackDetected)));
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn bad_consistency_proof_is_rejected() {
+        let server = TestServer::start("checkpoint-proof");
+        let dir = test_dir("checkpoint-proof");
+        let alice = Identity::generate("alice").unwrap();
+        sign(&server, &alice, 3);
+        fetch_trusted(&mut server.connect(), &dir).unwrap();
+        let checkpoint = read(dir.join("checkpoint")).unwrap();
+
+        sign(&server, &alice, 2);
+        server.state.lock().unwrap().consistency_proof = Some(vec![[7; 32]; 3]);
+        let result = fetch_trusted(&mut server.connect(), &dir);
+        assert!(matches!(result, Err(RollbackDetected)));
+        assert_eq!(read(dir.join("checkpoint")).unwrap(), checkpoint);
+
+        server.state.lock().unwrap().consistency_proof = None;
+        let (signed, _) = fetch_trusted(&mut server.connect(), &dir).unwrap();
+        assert_eq!(signed.checkpoint.size, 5);
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,793 @@
+use crate::checkpoint::fetch_trusted;
+use crate::config::{host, pin_ca, pinned_ca, server_dir, split_address};
+use crate::error::Error;
+use crate::error::Error::{
+    KeyMismatch, ReasonTooLong, StaleChainHead, UnknownSignature, UnknownUser, UnsupportedRequest,
+    UntrustedServer,
+};
+use crate::identity::Identity;
+use crate::keyring::Keyring;
+use crate::remotestorage::RemoteStorage;
+use fver_proto::{
+    capability, leaf_hash, verify_inclusion, AddSigStatus, Checkpoint, Connection, KeyRotation,
+    Revocation, Signature, User, Withdrawal, MAX_FIELD_LEN,
+};
+use openssl::hash::{Hasher, MessageDigest};
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use openssl::x509::X509;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
+
+const SIGN_ATTEMPTS: u32 = 10;
+
+/// Connection to a server whose checkpoint was checked against the pinned
+/// server key and the last checkpoint seen by this client
+pub struct Client {
+    dir: PathBuf,
+    storage: RemoteStorage,
+    server_key: Vec<u8>,
+    checkpoint: Checkpoint,
+    pinned: bool,
+    keyring: Keyring,
+}
+
+pub struct VerificationReport {
+    pub object: [u8; 32],
+    /// Checkpoint the inclusion proofs were checked against
+    pub checkpoint: Checkpoint,
+    pub signatures: Vec<SignatureReport>,
+}
+
+pub struct SignatureReport {
+    pub hash: [u8; 32],
+    /// `None` if the server listed the signature but does not have it
+    pub signature: Option<Signature>,
+    /// `None` if the signing user is not known to the server
+    pub signer: Option<Signer>,
+    /// Signature verifies against the signer's key
+    pub valid: bool,
+    pub inclusion: Inclusion,
+    pub trust: Trust,
+    /// Returned fields that do not belong to the queried object and signature
+    pub mismatches: Vec<Mismatch>,
+    /// Log position from which the signing key is revoked, if the revocation
+    /// covers this signature
+    pub revoked: Option<u64>,
+    /// Reason given by the signer for withdrawing the signature, empty if
+    /// they gave none
+    pub withdrawn: Option<String>,
+}
+
+pub struct Signer {
+    pub username: String,
+    /// Key the signature was made with, the one current at its log position
+    pub key: Vec<u8>,
+    /// Every key of the user, oldest first, linked by cross-signed rotations.
+    /// Empty if the user never rotated their key.
+    pub keys: Vec<Vec<u8>>,
+}
+
+impl Signer {
+    pub fn fingerprint(&self) -> [u8; 32] {
+        sha256(&self.key)
+    }
+}
+
+/// Signer key compared to the local keyring
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trust {
+    /// Added with `fver trust add`
+    Trusted,
+    /// Pinned on first sight earlier
+    Pinned,
+    /// Seen for the first time with a valid signature and pinned now
+    NewlyPinned,
+    /// The server returned a different key than the one in the keyring
+    Mismatch { pinned: [u8; 32] },
+    /// Unknown signer, or a first seen key whose signature does not verify
+    Unknown,
+}
+
+/// Field returned by the server that contradicts what it was asked for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mismatch {
+    /// The signature does not hash to the hash it was requested by
+    SignatureHash,
+    /// The signature is of another object than the one verified
+    Object,
+    /// The username of the returned user does not hash to `sig.user`
+    User,
+    /// The key rotations of the user do not lead to their current key
+    KeyHistory,
+}
+
+pub enum Inclusion {
+    /// Position of the signature in the transparency log of `size` entries
+    Included {
+        index: u64,
+        size: u64,
+    },
+    NotIncluded,
+    InvalidProof,
+}
+
+impl Client {
+    /// `server` is `host:port`, or `tls://host:port` for TLS checked against
+    /// the pinned CA or the system trust store
+    pub fn connect(server: &str) -> Result<Self, Error> {
+        Self::connect_with_ca(server, None)
+    }
+
+    /// Like `connect`, with TLS certificates checked against the PEM `ca`, which
+    /// is pinned once the connection succeeds. Fails with `UntrustedServer` if a
+    /// different CA is pinned already.
+    pub fn connect_with_ca(server: &str, ca: Option<&[u8]>) -> Result<Self, Error> {
+        let dir = server_dir(server)?;
+        let pinned = pinned_ca(&dir)?;
+        let ca = match ca {
+            None => None,
+            Some(pem) => {
+                let ca = X509::from_pem(pem)?;
+                if let Some(pinned) = &pinned {
+                    if pinned.to_der()? != ca.to_der()? {
+                        return Err(UntrustedServer);
+                    }
+                }
+                Some(ca)
+            }
+        };
+        let conn = match split_address(server) {
+            (true, addr) => {
+                Connection::connect_tls(addr, host(addr), ca.as_ref().or(pinned.as_ref()))?
+            }
+            (false, addr) => Connection::connect(addr)?,
+        };
+        let client = Self::open(RemoteStorage::new(conn)?, dir)?;
+        if let (Some(ca), None, (true, _)) = (&ca, &pinned, split_address(server)) {
+            pin_ca(&client.dir, ca)?;
+        }
+        Ok(client)
+    }
+
+    // `dir` holds what is pinned for the server
+    fn open(mut storage: RemoteStorage, dir: PathBuf) -> Result<Self, Error> {
+        let (signed, newly_pinned) = fetch_trusted(&mut storage, &dir)?;
+        Ok(Self {
+            keyring: Keyring::load(&dir)?,
+            dir,
+            storage,
+            server_key: signed.key,
+            checkpoint: signed.checkpoint,
+            pinned: newly_pinned,
+        })
+    }
+
+    pub fn server_key(&self) -> &[u8] {
+        &self.server_key
+    }
+
+    /// Whether the server key was seen for the first time by this connection
+    pub fn newly_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    /// Protocol version agreed on with the server
+    pub fn protocol_version(&self) -> u16 {
+        self.storage.version()
+    }
+
+    pub fn checkpoint(&self) -> &Checkpoint {
+        &self.checkpoint
+    }
+
+    /// Current key of a user as returned by the server, not checked against the keyring
+    pub fn signer(&mut self, username: &str) -> Result<Option<Signer>, Error> {
+        Ok(self
+            .storage
+            .get_user_by_username(username)?
+            .map(|u| Signer {
+                username: String::from_utf8_lossy(&u.username).into_owned(),
+                key: u.key,
+                keys: Vec::new(),
+            }))
+    }
+
+    pub fn is_registered(&mut self, username: &str) -> Result<bool, Error> {
+        Ok(self.storage.get_user_by_username(username)?.is_some())
+    }
+
+    /// Registers the identity unless it already is, returns true if it was registered now.
+    /// Fails with `KeyMismatch` if the username belongs to a different key.
+    pub fn register(&mut self, identity: &Identity) -> Result<bool, Error> {
+        let key = identity.public_key()?;
+        match self.storage.get_user_by_username(identity.username())? {
+            None => {
+                let challenge = self.storage.get_challenge()?;
+                let proof = identity.prove(&challenge)?;
+                let u = User {
+                    key,
+                    username: identity.username().as_bytes().to_vec(),
+                };
+                self.storage.set_user(u, &proof)?;
+                Ok(true)
+            }
+            Some(u) if u.key == key => Ok(false),
+            Some(_) => Err(KeyMismatch),
+        }
+    }
+
+    pub fn sign_file<P: AsRef<Path>>(
+        &mut self,
+        identity: &Identity,
+        path: P,
+    ) -> Result<Signature, Error> {
+        self.sign_reader(identity, File::open(path)?)
+    }
+
+    /// Signs the SHA-256 of everything read from `reader` and pushes the signature to the server
+    pub fn sign_reader<R: Read>(
+        &mut self,
+        identity: &Identity,
+        mut reader: R,
+    ) -> Result<Signature, Error> {
+        if !self.storage.has_capability(capability::SIGN_REQUEST) {
+            return Err(UnsupportedRequest);
+        }
+        let obj = hash_reader(&mut reader)?;
+
+        // Someone else may sign between reading the head and pushing,
+        // the signature then has to be made again over the new head
+        let mut prev_sig = self.storage.get_head()?;
+        for attempt in 0..SIGN_ATTEMPTS {
+            if attempt > 0 {
+                backoff(attempt)?;
+            }
+            let sig = Signature {
+                obj,
+                user: identity.user_hash(),
+                prev_sig,
+                signature: identity.sign(&obj, &prev_sig)?,
+            };
+            let resp = self.storage.add_sig(prev_sig, sig.clone())?;
+            if resp.status == AddSigStatus::Accepted {
+                return Ok(sig);
+            }
+            prev_sig = resp.head;
+        }
+        Err(StaleChainHead)
+    }
+
+    /// Replaces the key of `identity` on the server with the key of `next`.
+    /// Signatures made so far stay verifiable with the old key.
+    pub fn rotate_key(
+        &mut self,
+        identity: &Identity,
+        next: &Identity,
+    ) -> Result<KeyRotation, Error> {
+        if !self.storage.has_capability(capability::KEY_ROTATION) {
+            return Err(UnsupportedRequest);
+        }
+        // The rotation takes effect at the current log size, it has to be
+        // signed again if someone signs in the meantime
+        for attempt in 0..SIGN_ATTEMPTS {
+            if attempt > 0 {
+                backoff(attempt)?;
+            }
+            let mut rotation = KeyRotation {
+                username: identity.username.as_bytes().to_vec(),
+                old_key: identity.public_key()?,
+                new_key: next.public_key()?,
+                since: self.storage.get_tree_head()?.size,
+                old_signature: Vec::new(),
+                new_signature: Vec::new(),
+            };
+            rotation.old_signature = identity.sign_statement(&rotation.signed_data())?;
+            rotation.new_signature = next.sign_statement(&rotation.signed_data())?;
+            if self.storage.rotate_key(rotation.clone())? {
+                return Ok(rotation);
+            }
+        }
+        Err(StaleChainHead)
+    }
+
+    /// Revokes `key`, the current or a previous key of `identity`, for
+    /// signatures from log position `since` on
+    pub fn revoke_key(
+        &mut self,
+        identity: &Identity,
+        key: &[u8],
+        since: u64,
+    ) -> Result<Revocation, Error> {
+        if !self.storage.has_capability(capability::KEY_REVOCATION) {
+            return Err(UnsupportedRequest);
+        }
+        let mut revocation = Revocation {
+            username: identity.username.as_bytes().to_vec(),
+            key: key.to_vec(),
+            since,
+            signature: Vec::new(),
+        };
+        revocation.signature = identity.sign_statement(&revocation.signed_data())?;
+        self.storage.revoke_key(revocation.clone())?;
+        Ok(revocation)
+    }
+
+    /// Every key of the user, oldest first. Fails with `UnknownUser` if the user
+    /// does not exist and `KeyMismatch` if their rotations do not verify.
+    pub fn keys(&mut self, username: &str) -> Result<Vec<Vec<u8>>, Error> {
+        let u = self
+            .storage
+            .get_user_by_username(username)?
+            .ok_or(UnknownUser)?;
+        let rotations = self.key_history(&u)?.ok_or(KeyMismatch)?;
+        Ok(all_keys(&u, &rotations))
+    }
+
+    /// Revocations of the user's keys that are signed by the revoked key or one
+    /// that replaced it. Fails with `UnknownUser` if the user does not exist.
+    pub fn revocations(&mut self, username: &str) -> Result<Vec<Revocation>, Error> {
+        let u = self
+            .storage
+            .get_user_by_username(username)?
+            .ok_or(UnknownUser)?;
+        let rotations = self.key_history(&u)?.unwrap_or_default();
+        self.verified_revocations(&u, &all_keys(&u, &rotations))
+    }
+
+    /// Withdraws every signature `identity` made of the file that is not withdrawn yet
+    pub fn withdraw_file<P: AsRef<Path>>(
+        &mut self,
+        identity: &Identity,
+        path: P,
+        reason: &str,
+    ) -> Result<Vec<Withdrawal>, Error> {
+        if !self.storage.has_capability(capability::SIG_WITHDRAWAL) {
+            return Err(UnsupportedRequest);
+        }
+        if reason.len() > MAX_FIELD_LEN {
+            return Err(ReasonTooLong);
+        }
+        let object = hash_reader(&mut File::open(path)?)?;
+        let withdrawn = self.storage.get_withdrawals(object)?;
+        let mut withdrawals = Vec::new();
+        for hash in self.storage.get_obj(object)? {
+            if withdrawn.iter().any(|w| w.sig == hash) {
+                continue;
+            }
+            match self.storage.get_sig(hash)? {
+                Some(sig) if sig.user == identity.user_hash() => {
+                    withdrawals.push(self.withdraw(identity, hash, reason)?)
+                }
+                _ => {}
+            }
+        }
+        Ok(withdrawals)
+    }
+
+    /// Withdraws a signature made by `identity`, signing the withdrawal with its
+    /// current key. The signature stays in the log, `verify` reports it as withdrawn.
+    pub fn withdraw(
+        &mut self,
+        identity: &Identity,
+        hash: [u8; 32],
+        reason: &str,
+    ) -> Result<Withdrawal, Error> {
+        if !self.storage.has_capability(capability::SIG_WITHDRAWAL) {
+            return Err(UnsupportedRequest);
+        }
+        if reason.len() > MAX_FIELD_LEN {
+            return Err(ReasonTooLong);
+        }
+        let sig = self.storage.get_sig(hash)?.ok_or(UnknownSignature)?;
+        let mut withdrawal = Withdrawal {
+            sig: hash,
+            obj: sig.obj,
+            user: identity.user_hash(),
+            reason: reason.as_bytes().to_vec(),
+            signature: Vec::new(),
+        };
+        withdrawal.signature = identity.sign_statement(&withdrawal.signed_data())?;
+        self.storage.withdraw_sig(withdrawal.clone())?;
+        Ok(withdrawal)
+    }
+
+    pub fn verify_file<P: AsRef<Path>>(&mut self, path: P) -> Result<VerificationReport, Error> {
+        let hash = hash_reader(&mut File::open(path)?)?;
+        self.verify_hash(hash)
+    }
+
+    /// Checks every signature of the object against the signer's key and the
+    /// transparency log, using a freshly fetched checkpoint
+    pub fn verify_hash(&mut self, object: [u8; 32]) -> Result<VerificationReport, Error> {
+        let (signed, _) = fetch_trusted(&mut self.storage, &self.dir)?;
+        self.checkpoint = signed.checkpoint;
+
+        let withdrawals = if self.storage.has_capability(capability::SIG_WITHDRAWAL) {
+            self.storage.get_withdrawals(object)?
+        } else {
+            Vec::new()
+        };
+        let mut signatures = Vec::new();
+        for hash in self.storage.get_obj(object)? {
+            signatures.push(self.verify_sig(object, hash, &withdrawals)?);
+        }
+        if signatures.iter().any(|s| s.trust == Trust::NewlyPinned) {
+            self.keyring.save()?;
+        }
+        Ok(VerificationReport {
+            object,
+            checkpoint: self.checkpoint.clone(),
+            signatures,
+        })
+    }
+
+    // Nothing the server returns is trusted, every field is checked against
+    // what it was requested by
+    fn verify_sig(
+        &mut self,
+        object: [u8; 32],
+        hash: [u8; 32],
+        withdrawals: &[Withdrawal],
+    ) -> Result<SignatureReport, Error> {
+        let inclusion = match self
+            .storage
+            .get_inclusion_proof(hash, self.checkpoint.size)?
+        {
+            None => Inclusion::NotIncluded,
+            Some(proof) => {
+                if proof.root == self.checkpoint.root
+                    && verify_inclusion(
+                        &leaf_hash(&hash),
+                        proof.index,
+                        proof.size,
+                        &proof.path,
+                        &proof.root,
+                    )
+                {
+                    Inclusion::Included {
+                        index: proof.index,
+                        size: proof.size,
+                    }
+                } else {
+                    Inclusion::InvalidProof
+                }
+            }
+        };
+
+        let signature = self.storage.get_sig(hash)?;
+        let mut signer = None;
+        let mut valid = false;
+        let mut mismatches = Vec::new();
+        let mut revoked = None;
+        let mut withdrawn = None;
+        if let Some(sig) = &signature {
+            if sig.hash() != hash {
+                mismatches.push(Mismatch::SignatureHash);
+            }
+            if sig.obj != object {
+                mismatches.push(Mismatch::Object);
+            }
+            if let Some(u) = self.storage.get_user(sig.user)? {
+                if sha256(&u.username) != sig.user {
+                    mismatches.push(Mismatch::User);
+                }
+                let rotations = self.key_history(&u)?;
+                if rotations.is_none() {
+                    mismatches.push(Mismatch::KeyHistory);
+                }
+                let rotations = rotations.unwrap_or_default();
+                let all = all_keys(&u, &rotations);
+                let revocations = self.verified_revocations(&u, &all)?;
+                let key = match inclusion {
+                    Inclusion::Included { index, .. } => key_at(&u, &rotations, index),
+                    // Without a position only the current key can be assumed
+                    _ => u.key.clone(),
+                };
+                valid = sig.verify(&key)?;
+                // A signature of unknown position is treated as made after
+                // any revocation of its key
+                revoked = revocations
+                    .iter()
+                    .filter(|r| match inclusion {
+                        Inclusion::Included { index, .. } => r.covers(&key, index),
+                        _ => r.key == key,
+                    })
+                    .map(|r| r.since)
+                    .min();
+                // Made with the signing key or one that replaced it
+                let signers: Vec<_> = all.iter().skip_while(|k| **k != key).collect();
+                withdrawn = withdrawals
+                    .iter()
+                    .find(|w| {
+                        w.sig == hash
+                            && w.obj == object
+                            && w.user == sig.user
+                            && signers.iter().any(|k| w.verify(k).unwrap_or(false))
+                    })
+                    .map(|w| String::from_utf8_lossy(&w.reason).into_owned());
+                let mut keys = Vec::new();
+                if let Some(first) = rotations.first() {
+                    keys.push(first.old_key.clone());
+                    keys.extend(rotations.into_iter().map(|r| r.new_key));
+                }
+                signer = Some(Signer {
+                    username: String::from_utf8_lossy(&u.username).into_owned(),
+                    key,
+                    keys,
+                });
+            }
+        }
+
+        let trust = match &signer {
+            None => Trust::Unknown,
+            Some(signer) => {
+                self.check_key(signer, valid && mismatches.is_empty() && revoked.is_none())
+            }
+        };
+
+        Ok(SignatureReport {
+            hash,
+            signature,
+            signer,
+            valid,
+            inclusion,
+            trust,
+            mismatches,
+            revoked,
+            withdrawn,
+        })
+    }
+
+    // None if the rotations do not lead from one key to the next, each
+    // signed by both keys, ending at the current key of the user
+    fn key_history(&mut self, user: &User) -> Result<Option<Vec<KeyRotation>>, Error> {
+        if !self.storage.has_capability(capability::KEY_ROTATION) {
+            return Ok(Some(Vec::new()));
+        }
+        let rotations = match self.storage.get_key_history(sha256(&user.username))? {
+            Some(rotations) => rotations,
+            None => return Ok(None),
+        };
+        let linked = rotations
+            .windows(2)
+            .all(|w| w[0].new_key == w[1].old_key && w[0].since <= w[1].since);
+        let signed = rotations
+            .iter()
+            .all(|r| r.username == user.username && r.verify().unwrap_or(false));
+        let current = rotations.last().is_none_or(|r| r.new_key == user.key);
+        Ok(if linked && signed && current {
+            Some(rotations)
+        } else {
+            None
+        })
+    }
+
+    // Revocations the server could have made up on its own are dropped
+    fn verified_revocations(
+        &mut self,
+        user: &User,
+        keys: &[Vec<u8>],
+    ) -> Result<Vec<Revocation>, Error> {
+        if !self.storage.has_capability(capability::KEY_REVOCATION) {
+            return Ok(Vec::new());
+        }
+        let revocations = self
+            .storage
+            .get_revocations(sha256(&user.username))?
+            .unwrap_or_default();
+        Ok(revocations
+            .into_iter()
+            .filter(|r| {
+                r.username == user.username
+                    && keys
+                        .iter()
+                        .position(|k| *k == r.key)
+                        .is_some_and(|pos| keys[pos..].iter().any(|k| r.verify(k).unwrap_or(false)))
+            })
+            .collect())
+    }
+
+    // Pins keys on first sight, only if they made a valid signature. A pinned
+    // key still matches after the user rotated it.
+    fn check_key(&mut self, signer: &Signer, valid: bool) -> Trust {
+        let fingerprint = signer.fingerprint();
+        let known = |pinned: &[u8; 32]| {
+            *pinned == fingerprint || signer.keys.iter().any(|k| sha256(k) == *pinned)
+        };
+        match self.keyring.get(&signer.username) {
+            Some(e) if !known(&e.fingerprint) => Trust::Mismatch {
+                pinned: e.fingerprint,
+            },
+            Some(e) if e.manual => Trust::Trusted,
+            Some(_) => Trust::Pinned,
+            None if valid => match self.keyring.add(&signer.username, fingerprint, false) {
+                Ok(()) => Trust::NewlyPinned,
+                // A username the keyring can't hold stays unknown
+                Err(_) => Trust::Unknown,
+            },
+            None => Trust::Unknown,
+        }
+    }
+}
+
+// Key current at log position `index`
+fn key_at(user: &User, rotations: &[KeyRotation], index: u64) -> Vec<u8> {
+    let mut key = rotations.first().map_or(&user.key, |r| &r.old_key);
+    for r in rotations.iter().take_while(|r| r.since <= index) {
+        key = &r.new_key;
+    }
+    key.clone()
+}
+
+// Every key of the user, oldest first
+fn all_keys(user: &User, rotations: &[KeyRotation]) -> Vec<Vec<u8>> {
+    match rotations.first() {
+        None => vec![user.key.clone()],
+        Some(first) => std::iter::once(first.old_key.clone())
+            .chain(rotations.iter().map(|r| r.new_key.clone()))
+            .collect(),
+    }
+}
+
+// Random delay growing with the attempt, so that clients racing
+// for the same head don't collide again
+fn backoff(attempt: u32) -> Result<(), Error> {
+    let mut r = [0; 2];
+    rand_bytes(&mut r)?;
+    let ms = u64::from(u16::from_le_bytes(r)) % (20 << attempt.min(5));
+    sleep(Duration::from_millis(ms));
+    Ok(())
+}
+
+fn hash_reader<R: Read>(reader: &mut R) -> Result<[u8; 32], Error> {
+    let mut hasher = Hasher::new(MessageDigest::sha256())?;
+    io::copy(reader, &mut hasher)?;
+    Ok(hasher.finish()?[..].try_into()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testserver::{test_dir, TestServer};
+    use std::fs::remove_dir_all;
+
+    #[test]
+    fn withdrawn_signature_still_verifies() {
+        let server = TestServer::start("withdrawn");
+        let alice = Identity::generate("alice").unwrap();
+        let reason = vec![b'x'; MAX_FIELD_LEN];
+        let hash = {
+            let mut state = server.state.lock().unwrap();
+            state.register(&alice);
+            state.sign(&alice, [2; 32]);
+            let hash = state.sign(&alice, [1; 32]);
+            state.withdraw(&alice, hash, &reason);
+            hash
+        };
+
+        let dir = test_dir("withdrawn");
+        let mut client = Client::open(server.connect(), dir.clone()).unwrap();
+        let report = client.verify_hash([1; 32]).unwrap();
+        remove_dir_all(&dir).unwrap();
+        assert_eq!(report.signatures.len(), 1);
+        let s = &report.signatures[0];
+        assert_eq!(s.hash, hash);
+        assert!(s.valid && s.mismatches.is_empty());
+        assert!(matches!(
+            s.inclusion,
+            Inclusion::Included { index: 1, size: 2 }
+        ));
+        assert_eq!(s.withdrawn.as_deref().map(str::len), Some(MAX_FIELD_LEN));
+    }
+
+    fn user(key: &[u8]) -> User {
+        User {
+            username: b"alice".to_vec(),
+            key: key.to_vec(),
+        }
+    }
+
+    fn rotation(old_key: &[u8], new_key: &[u8], since: u64) -> KeyRotation {
+        KeyRotation {
+            username: b"alice".to_vec(),
+            old_key: old_key.to_vec(),
+            new_key: new_key.to_vec(),
+            since,
+            old_signature: Vec::new(),
+            new_signature: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn keys_by_log_position() {
+        assert_eq!(key_at(&user(b"a"), &[], 7), b"a");
+        assert_eq!(all_keys(&user(b"a"), &[]), [b"a"]);
+
+        let rotations = [rotation(b"a", b"b", 2), rotation(b"b", b"c", 5)];
+        let user = user(b"c");
+        let keys: Vec<_> = (0..7).map(|i| key_at(&user, &rotations, i)).collect();
+        assert_eq!(keys, [b"a", b"a", b"b", b"b", b"b", b"c", b"c"]);
+        assert_eq!(all_keys(&user, &rotations), [b"a", b"b", b"c"]);
+    }
+
+    #[test]
+    fn signatures_around_rotation() {
+        let server = TestServer::start("rotation");
+        let old = Identity::generate("alice").unwrap();
+        let new = Identity::generate("alice").unwrap();
+        {
+            let mut state = server.state.lock().unwrap();
+            state.register(&old);
+            state.sign(&old, [1; 32]);
+            state.rotate(&old, &new);
+            state.sign(&new, [1; 32]);
+            // The old key is no longer current at this position
+            state.sign(&old, [1; 32]);
+        }
+
+        let dir = test_dir("rotation");
+        let mut client = Client::open(server.connect(), dir.clone()).unwrap();
+        let report = client.verify_hash([1; 32]).unwrap();
+        let s = &report.signatures;
+        assert_eq!(s.len(), 3);
+        let old_key = old.public_key().unwrap();
+        let new_key = new.public_key().unwrap();
+        assert!(s[0].valid);
+        assert_eq!(s[0].signer.as_ref().unwrap().key, old_key);
+        assert_eq!(s[0].trust, Trust::NewlyPinned);
+        assert!(s[1].valid);
+        assert_eq!(s[1].signer.as_ref().unwrap().key, new_key);
+        assert_eq!(
+            s[1].signer.as_ref().unwrap().keys,
+            [old_key.clone(), new_key.clone()]
+        );
+        // The key pinned before the rotation still matches
+        assert_eq!(s[1].trust, Trust::Pinned);
+        assert!(!s[2].valid);
+        assert_eq!(s[2].signer.as_ref().unwrap().key, new_key);
+
+        // A key the rotations do not lead to is a mismatch
+        client.keyring.add("alice", [9; 32], false).unwrap();
+        let report = client.verify_hash([1; 32]).unwrap();
+        remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            report.signatures[1].trust,
+            Trust::Mismatch { pinned } if pinned == [9; 32]
+        ));
+    }
+
+    #[test]
+    fn signatures_around_revocation() {
+        let server = TestServer::start("revocation");
+        let old = Identity::generate("alice").unwrap();
+        let new = Identity::generate("alice").unwrap();
+        let eve = Identity::generate("alice").unwrap();
+        {
+            let mut state = server.state.lock().unwrap();
+            state.register(&old);
+            for _ in 0..3 {
+                state.sign(&old, [1; 32]);
+            }
+            state.rotate(&old, &new);
+            state.sign(&new, [1; 32]);
+            // Revoked by the key that replaced it
+            state.revoke(&new, &old.public_key().unwrap(), 1);
+            // Made up by the server, signed by neither key
+            state.revoke(&eve, &new.public_key().unwrap(), 0);
+        }
+
+        let dir = test_dir("revocation");
+        let mut client = Client::open(server.connect(), dir.clone()).unwrap();
+        let report = client.verify_hash([1; 32]).unwrap();
+        remove_dir_all(&dir).unwrap();
+        let revoked: Vec<_> = report.signatures.iter().map(|s| s.revoked).collect();
+        assert_eq!(revoked, [None, Some(1), Some(1), None]);
+        assert!(report.signatures.iter().all(|s| s.valid));
+    }
+}
@@ -0,0 +1,80 @@
+use crate::error::Error;
+use crate::error::Error::NoDataDirectory;
+use dirs::data_dir;
+use openssl::x509::X509;
+use std::env::var;
+use std::fs::{create_dir_all, read, rename, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+pub const DEFAULT_SERVER: &str = "localhost:37687";
+
+const TLS_SCHEME: &str = "tls://";
+
+/// The `fver` data directory, created if it does not exist yet
+pub fn config_dir() -> Result<PathBuf, Error> {
+    let mut config_path = data_dir().ok_or(NoDataDirectory)?;
+    config_path.push("fver");
+    create_dir_all(&config_path)?;
+    Ok(config_path)
+}
+
+// Pinned server key, CA and last checkpoint are kept separately for every server
+pub(crate) fn server_dir(server: &str) -> Result<PathBuf, Error> {
+    let mut path = config_dir()?;
+    path.push("servers");
+    path.push(split_address(server).1.replace(':', "_"));
+    create_dir_all(&path)?;
+    Ok(path)
+}
+
+/// Server address from `FVER_SERVER`, the `server` file in the data directory
+/// or `DEFAULT_SERVER`, in that order
+pub fn default_server() -> Result<String, Error> {
+    if let Ok(server) = var("FVER_SERVER") {
+        return Ok(server);
+    }
+    let config_file = config_dir()?.join("server");
+    if config_file.exists() {
+        let mut server = String::new();
+        File::open(config_file)?.read_to_string(&mut server)?;
+        return Ok(server.trim().to_string());
+    }
+    Ok(String::from(DEFAULT_SERVER))
+}
+
+/// Splits `tls://host:port` into whether to use TLS and the address
+pub(crate) fn split_address(server: &str) -> (bool, &str) {
+    match server.strip_prefix(TLS_SCHEME) {
+        Some(addr) => (true, addr),
+        None => (false, server),
+    }
+}
+
+/// Host part of `host:port`, the server certificate is checked against it
+pub(crate) fn host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+pub(crate) fn pin_ca(dir: &Path, ca: &X509) -> Result<(), Error> {
+    write_atomic(dir, "ca.pem", &ca.to_pem()?)
+}
+
+pub(crate) fn pinned_ca(dir: &Path) -> Result<Option<X509>, Error> {
+    let path = dir.join("ca.pem");
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(X509::from_pem(&read(path)?)?))
+}
+
+/// Replaces `dir/name` with `data` in one step
+pub(crate) fn write_atomic(dir: &Path, name: &str, data: &[u8]) -> Result<(), Error> {
+    // Other fver processes may be reading it at the same time
+    let tmp_path = dir.join(format!("{}.{}.tmp", name, process::id()));
+    File::create(&tmp_path)?.write_all(data)?;
+    rename(tmp_path, dir.join(name))?;
+    Ok(())
+}
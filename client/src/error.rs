@@ -1,5 +1,4 @@
 use crate::error::Error::{CorruptedMessage, EncodingError, IOError, NetworkError, OpenSSLError};
-use simpletcp;
 use simpletcp::simpletcp::MessageError;
 use std::array::TryFromSliceError;
 use std::convert::Infallible;
@@ -7,6 +6,7 @@ use std::fmt::{Debug, Formatter};
 use std::string::FromUtf8Error;
 use std::{fmt, io};
 
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     NetworkError(simpletcp::simpletcp::Error),
     CorruptedMessage,
@@ -15,6 +15,30 @@ pub enum Error {
     EncodingError(FromUtf8Error),
     ServerError,
     NoDataDirectory,
+    UnknownUser,
+    StaleChainHead,
+    InvalidSignature,
+    UntrustedServer,
+    InvalidCheckpoint,
+    RollbackDetected,
+    KeyMismatch,
+    /// Range of protocol versions the server speaks, `None` for servers
+    /// predating version negotiation
+    IncompatibleVersion(Option<(u16, u16)>),
+    UnsupportedRequest,
+    CorruptedKeyring,
+    WrongPassphrase,
+    /// Imported keys have to be secp384r1, public ones a PEM SubjectPublicKeyInfo
+    UnsupportedKey,
+    RevokedKey,
+    /// The key is not one the user ever had
+    UnknownKey,
+    UnknownSignature,
+    AlreadyWithdrawn,
+    /// Withdrawal reasons can be at most `MAX_FIELD_LEN` bytes long
+    ReasonTooLong,
+    /// Usernames with control characters can't be stored in the keyring
+    InvalidUsername,
 }
 
 impl From<simpletcp::simpletcp::Error> for Error {
@@ -23,6 +47,15 @@ impl From<simpletcp::simpletcp::Error> for Error {
     }
 }
 
+impl From<fver_proto::Error> for Error {
+    fn from(e: fver_proto::Error) -> Self {
+        match e {
+            fver_proto::Error::Unsupported => Error::UnsupportedRequest,
+            _ => CorruptedMessage,
+        }
+    }
+}
+
 impl From<MessageError> for Error {
     fn from(_: MessageError) -> Self {
         CorruptedMessage
@@ -69,6 +102,33 @@ impl Debug for Error {
             EncodingError(e) => f.write_fmt(format_args!("EncodingError: {:?}", e)),
             Error::ServerError => f.write_str("ServerError"),
             Error::NoDataDirectory => f.write_str("NoDataDirectory"),
+            Error::UnknownUser => f.write_str("UnknownUser"),
+            Error::StaleChainHead => f.write_str("StaleChainHead"),
+            Error::InvalidSignature => f.write_str("InvalidSignature"),
+            Error::UntrustedServer => f.write_str("UntrustedServer"),
+            Error::InvalidCheckpoint => f.write_str("InvalidCheckpoint"),
+            Error::RollbackDetected => f.write_str("RollbackDetected"),
+            Error::KeyMismatch => f.write_str("KeyMismatch"),
+            Error::IncompatibleVersion(Some((min, max))) => f.write_fmt(format_args!(
+                "IncompatibleVersion: server speaks protocol {}-{}, client {}-{}",
+                min,
+                max,
+                fver_proto::MIN_PROTOCOL_VERSION,
+                fver_proto::PROTOCOL_VERSION
+            )),
+            Error::IncompatibleVersion(None) => {
+                f.write_str("IncompatibleVersion: server does not support version negotiation")
+            }
+            Error::UnsupportedRequest => f.write_str("UnsupportedRequest"),
+            Error::CorruptedKeyring => f.write_str("CorruptedKeyring"),
+            Error::WrongPassphrase => f.write_str("WrongPassphrase"),
+            Error::UnsupportedKey => f.write_str("UnsupportedKey"),
+            Error::RevokedKey => f.write_str("RevokedKey"),
+            Error::UnknownKey => f.write_str("UnknownKey"),
+            Error::UnknownSignature => f.write_str("UnknownSignature"),
+            Error::AlreadyWithdrawn => f.write_str("AlreadyWithdrawn"),
+            Error::ReasonTooLong => f.write_str("ReasonTooLong"),
+            Error::InvalidUsername => f.write_str("InvalidUsername"),
         }
     }
 }
@@ -0,0 +1,324 @@
+use crate::config::config_dir;
+use crate::error::Error;
+use crate::error::Error::{UnsupportedKey, WrongPassphrase};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{HasPublic, PKey, PKeyRef, Private};
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+use openssl::symm::Cipher;
+use std::fs::{read, remove_file, rename, File, OpenOptions};
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Signing key and username of the local user
+pub struct Identity {
+    pub(crate) key: PKey<Private>,
+    pub(crate) username: String,
+    encrypted: bool,
+}
+
+impl Identity {
+    /// Generates a new key for `username`, it is not saved until `save` is called
+    pub fn generate(username: &str) -> Result<Self, Error> {
+        let key = PKey::from_ec_key(EcKey::generate(
+            EcGroup::from_curve_name(Nid::SECP384R1)?.as_ref(),
+        )?)?;
+        Ok(Self {
+            key,
+            username: username.to_string(),
+            encrypted: false,
+        })
+    }
+
+    /// Identity from a PEM private key, `passphrase` is only asked for if it is encrypted
+    pub fn from_pem<F: FnOnce() -> Result<String, Error>>(
+        username: &str,
+        pem: &[u8],
+        passphrase: F,
+    ) -> Result<Self, Error> {
+        let text = String::from_utf8_lossy(pem);
+        let key = if text.contains("ENCRYPTED") {
+            PKey::private_key_from_pem_passphrase(pem, passphrase()?.as_bytes())
+                .map_err(|_| WrongPassphrase)?
+        } else {
+            PKey::private_key_from_pem(pem)?
+        };
+        check_curve(&key)?;
+        Ok(Self {
+            key,
+            username: username.to_string(),
+            encrypted: false,
+        })
+    }
+
+    /// Whether there is an identity in the data directory, without decrypting it
+    pub fn exists() -> Result<bool, Error> {
+        Ok(config_dir()?.join("key").exists())
+    }
+
+    /// Loads the identity from the data directory, `None` if there is none yet.
+    /// `passphrase` is only asked for if the key is encrypted, keys saved by
+    /// older versions are plain DER.
+    pub fn load<F: FnOnce() -> Result<String, Error>>(
+        passphrase: F,
+    ) -> Result<Option<Self>, Error> {
+        Self::load_from(&config_dir()?, passphrase)
+    }
+
+    pub(crate) fn load_from<F: FnOnce() -> Result<String, Error>>(
+        config_path: &Path,
+        passphrase: F,
+    ) -> Result<Option<Self>, Error> {
+        if !config_path.join("key").exists() {
+            return Ok(None);
+        }
+        let der = read(config_path.join("key"))?;
+        let (key, encrypted) = match EcKey::private_key_from_der(&der) {
+            Ok(key) => (PKey::from_ec_key(key)?, false),
+            Err(_) => {
+                let key = PKey::private_key_from_pkcs8_passphrase(&der, passphrase()?.as_bytes())
+                    .map_err(|_| WrongPassphrase)?;
+                (key, true)
+            }
+        };
+        let mut username_file = File::open(config_path.join("username"))?;
+        let mut username_vec = Vec::new();
+        username_file.read_to_end(&mut username_vec)?;
+        let username = String::from_utf8(username_vec)?;
+        Ok(Some(Self {
+            key,
+            username,
+            encrypted,
+        }))
+    }
+
+    /// Saves the key as PKCS#8 encrypted with `passphrase`, readable only by the owner
+    pub fn save(&mut self, passphrase: &str) -> Result<(), Error> {
+        self.save_to(&config_dir()?, passphrase)
+    }
+
+    pub(crate) fn save_to(&mut self, config_path: &Path, passphrase: &str) -> Result<(), Error> {
+        // Replacing the key in place could lose it if interrupted
+        let tmp_path = config_path.join("key.tmp");
+        self.save_as(&tmp_path, passphrase)?;
+        rename(tmp_path, config_path.join("key"))?;
+
+        let mut username_file = File::create(config_path.join("username"))?;
+        username_file.write_all(self.username.as_bytes())?;
+        Ok(())
+    }
+
+    /// Saves the key as `key.next` without replacing the current one, it is
+    /// made current by `promote_next` once the server accepted it
+    pub fn save_next(&mut self, passphrase: &str) -> Result<(), Error> {
+        self.save_as(&config_dir()?.join("key.next"), passphrase)
+    }
+
+    pub fn promote_next() -> Result<(), Error> {
+        let config_path = config_dir()?;
+        rename(config_path.join("key.next"), config_path.join("key"))?;
+        Ok(())
+    }
+
+    pub fn discard_next() -> Result<(), Error> {
+        remove_file(config_dir()?.join("key.next"))?;
+        Ok(())
+    }
+
+    fn save_as(&mut self, path: &Path, passphrase: &str) -> Result<(), Error> {
+        let der = self
+            .key
+            .private_key_to_pkcs8_passphrase(Cipher::aes_256_cbc(), passphrase.as_bytes())?;
+        write_private(path, &der)?;
+        self.encrypted = true;
+        Ok(())
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Private key as PEM encoded PKCS#8 encrypted with `passphrase`
+    pub fn private_key_pem(&self, passphrase: &str) -> Result<Vec<u8>, Error> {
+        Ok(self
+            .key
+            .private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), passphrase.as_bytes())?)
+    }
+
+    /// Public key as PEM encoded SubjectPublicKeyInfo
+    pub fn public_key_pem(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.key.public_key_to_pem()?)
+    }
+
+    /// SHA-256 of the public key, as shown for signers by verify
+    pub fn fingerprint(&self) -> Result<[u8; 32], Error> {
+        Ok(sha256(&self.public_key()?))
+    }
+
+    /// False for keys saved unencrypted by older versions
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    pub fn public_key(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.key.public_key_to_der()?)
+    }
+
+    pub(crate) fn user_hash(&self) -> [u8; 32] {
+        sha256(self.username.as_bytes())
+    }
+
+    // Proof of key possession sent when registering
+    pub(crate) fn prove(&self, challenge: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.write_all(challenge)?;
+        signer.write_all(self.username.as_bytes())?;
+        Ok(signer.sign_to_vec()?)
+    }
+
+    /// Signs the `signed_data` of a rotation or revocation
+    pub(crate) fn sign_statement(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.write_all(data)?;
+        Ok(signer.sign_to_vec()?)
+    }
+
+    pub(crate) fn sign(&self, obj: &[u8; 32], prev_sig: &[u8; 32]) -> Result<Vec<u8>, Error> {
+        let mut signer = Signer::new_without_digest(&self.key)?;
+        signer.write_all(obj)?;
+        signer.write_all(prev_sig)?;
+        Ok(signer.sign_to_vec()?)
+    }
+}
+
+// Signatures are verified as secp384r1 by everyone else
+pub(crate) fn check_curve<T: HasPublic>(key: &PKeyRef<T>) -> Result<(), Error> {
+    let curve = key.ec_key().ok().and_then(|k| k.group().curve_name());
+    if curve != Some(Nid::SECP384R1) {
+        return Err(UnsupportedKey);
+    }
+    Ok(())
+}
+
+fn write_private(path: &Path, data: &[u8]) -> Result<(), Error> {
+    // An existing file would keep its permissions
+    if path.exists() {
+        remove_file(path)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testserver::test_dir;
+    use std::fs::{read_dir, remove_dir_all, write};
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    fn passphrase(p: &str) -> impl FnOnce() -> Result<String, Error> + '_ {
+        move || Ok(p.to_string())
+    }
+
+    #[test]
+    fn private_key_pem_roundtrip() {
+        let identity = Identity::generate("alice").unwrap();
+        let fingerprint = identity.fingerprint().unwrap();
+        let pem = identity.private_key_pem("secret").unwrap();
+
+        let imported = Identity::from_pem("bob", &pem, passphrase("secret")).unwrap();
+        assert_eq!(imported.fingerprint().unwrap(), fingerprint);
+        assert_eq!(imported.username(), "bob");
+        let result = Identity::from_pem("bob", &pem, passphrase("wrong"));
+        assert!(matches!(result, Err(WrongPassphrase)));
+
+        // Unencrypted keys don't ask for a passphrase
+        let pem = identity.key.private_key_to_pem_pkcs8().unwrap();
+        let imported = Identity::from_pem("bob", &pem, || panic!("asked for passphrase")).unwrap();
+        assert_eq!(imported.fingerprint().unwrap(), fingerprint);
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let other = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let pem = other.private_key_to_pem_pkcs8().unwrap();
+        let result = Identity::from_pem("bob", &pem, passphrase("secret"));
+        assert!(matches!(result, Err(UnsupportedKey)));
+    }
+
+    fn assert_private(path: &Path) {
+        #[cfg(unix)]
+        assert_eq!(path.metadata().unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = test_dir("identity-save");
+        assert!(Identity::load_from(&dir, || panic!("asked for passphrase"))
+            .unwrap()
+            .is_none());
+        let mut identity = Identity::generate("alice").unwrap();
+        identity.save_to(&dir, "secret").unwrap();
+        assert!(identity.is_encrypted());
+        assert_private(&dir.join("key"));
+
+        let loaded = Identity::load_from(&dir, passphrase("secret"))
+            .unwrap()
+            .unwrap();
+        assert!(loaded.is_encrypted());
+        assert_eq!(loaded.username(), "alice");
+        assert_eq!(
+            loaded.fingerprint().unwrap(),
+            identity.fingerprint().unwrap()
+        );
+        let result = Identity::load_from(&dir, passphrase("wrong"));
+        assert!(matches!(result, Err(WrongPassphrase)));
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn migration_leaves_no_plaintext_key() {
+        let dir = test_dir("identity-migrate");
+        let identity = Identity::generate("alice").unwrap();
+        let ec_key = identity.key.ec_key().unwrap();
+        // As stored by older versions
+        write(dir.join("key"), ec_key.private_key_to_der().unwrap()).unwrap();
+        write(dir.join("username"), "alice").unwrap();
+
+        let mut loaded = Identity::load_from(&dir, || panic!("asked for passphrase"))
+            .unwrap()
+            .unwrap();
+        assert!(!loaded.is_encrypted());
+        loaded.save_to(&dir, "secret").unwrap();
+
+        let mut names: Vec<_> = read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["key", "username"]);
+        assert_private(&dir.join("key"));
+        let stored = read(dir.join("key")).unwrap();
+        let secret = ec_key.private_key().to_vec();
+        assert!(!stored.windows(secret.len()).any(|w| w == &secret[..]));
+        assert!(EcKey::private_key_from_der(&stored).is_err());
+
+        let migrated = Identity::load_from(&dir, passphrase("secret"))
+            .unwrap()
+            .unwrap();
+        assert!(migrated.is_encrypted());
+        assert_eq!(
+            migrated.fingerprint().unwrap(),
+            identity.fingerprint().unwrap()
+        );
+        remove_dir_all(dir).unwrap();
+    }
+}
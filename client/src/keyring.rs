@@ -0,0 +1,188 @@
+use crate::config::server_dir;
+use crate::error::Error;
+use crate::error::Error::{CorruptedKeyring, InvalidUsername, UnsupportedKey};
+use crate::identity::check_curve;
+use openssl::pkey::PKey;
+use openssl::sha::sha256;
+use std::convert::TryInto;
+use std::fs::{read_to_string, rename, write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Signer keys known for a server, one `<fingerprint> <manual|tofu> <username>` line each
+pub struct Keyring {
+    path: PathBuf,
+    entries: Vec<KeyringEntry>,
+}
+
+pub struct KeyringEntry {
+    pub username: String,
+    /// SHA-256 of the signer's public key
+    pub fingerprint: [u8; 32],
+    /// Added with `fver trust add` rather than pinned on first sight
+    pub manual: bool,
+}
+
+impl Keyring {
+    pub fn open(server: &str) -> Result<Self, Error> {
+        Self::load(&server_dir(server)?)
+    }
+
+    pub(crate) fn load(dir: &Path) -> Result<Self, Error> {
+        let path = dir.join("keyring");
+        let mut entries = Vec::new();
+        if path.exists() {
+            for line in read_to_string(&path)?.lines() {
+                entries.push(parse_entry(line).ok_or(CorruptedKeyring)?);
+            }
+        }
+        Ok(Self { path, entries })
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let mut content = String::new();
+        for e in &self.entries {
+            content.push_str(&format!(
+                "{} {} {}\n",
+                hex::encode(e.fingerprint),
+                if e.manual { "manual" } else { "tofu" },
+                e.username
+            ));
+        }
+        // Other fver processes may be reading it at the same time
+        let tmp_path = self
+            .path
+            .with_file_name(format!("keyring.{}.tmp", process::id()));
+        write(&tmp_path, content)?;
+        rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, username: &str) -> Option<&KeyringEntry> {
+        self.entries.iter().find(|e| e.username == username)
+    }
+
+    /// Replaces any key already known for the username. Fails with
+    /// `InvalidUsername` for usernames with control characters, which the
+    /// line based file can't hold.
+    pub fn add(
+        &mut self,
+        username: &str,
+        fingerprint: [u8; 32],
+        manual: bool,
+    ) -> Result<(), Error> {
+        if username.chars().any(char::is_control) {
+            return Err(InvalidUsername);
+        }
+        self.remove(username);
+        self.entries.push(KeyringEntry {
+            username: username.to_string(),
+            fingerprint,
+            manual,
+        });
+        Ok(())
+    }
+
+    /// Trusts the PEM SubjectPublicKeyInfo `pem`, as printed by `fver key
+    /// export --public`, as the key of `username` and returns its fingerprint
+    pub fn import(&mut self, username: &str, pem: &[u8]) -> Result<[u8; 32], Error> {
+        // OpenSSL would decode a private key as well, asking for its
+        // passphrase on the terminal if it is encrypted
+        let text = String::from_utf8_lossy(pem);
+        if !text.contains("-----BEGIN PUBLIC KEY-----") || text.contains("ENCRYPTED") {
+            return Err(UnsupportedKey);
+        }
+        let key = PKey::public_key_from_pem(pem)?;
+        check_curve(&key)?;
+        let fingerprint = sha256(&key.public_key_to_der()?);
+        self.add(username, fingerprint, true)?;
+        Ok(fingerprint)
+    }
+
+    pub fn remove(&mut self, username: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|e| e.username != username);
+        self.entries.len() != len
+    }
+
+    pub fn entries(&self) -> &[KeyringEntry] {
+        &self.entries
+    }
+}
+
+fn parse_entry(line: &str) -> Option<KeyringEntry> {
+    let mut parts = line.splitn(3, ' ');
+    let fingerprint = hex::decode(parts.next()?)
+        .ok()?
+        .as_slice()
+        .try_into()
+        .ok()?;
+    let manual = match parts.next()? {
+        "manual" => true,
+        "tofu" => false,
+        _ => return None,
+    };
+    Some(KeyringEntry {
+        username: parts.next()?.to_string(),
+        fingerprint,
+        manual,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    #[test]
+    fn control_characters_are_not_saved() {
+        let dir = std::env::temp_dir().join(format!("fver-keyring-{}", process::id()));
+        create_dir_all(&dir).unwrap();
+        let mut keyring = Keyring::load(&dir).unwrap();
+        keyring.add("alice smith", [1; 32], false).unwrap();
+        let forged = format!("eve\n{} manual mallory", hex::encode([2; 32]));
+        assert!(matches!(
+            keyring.add(&forged, [3; 32], false),
+            Err(InvalidUsername)
+        ));
+        assert!(keyring.add("eve\r", [3; 32], true).is_err());
+        keyring.save().unwrap();
+
+        let keyring = Keyring::load(&dir).unwrap();
+        remove_dir_all(&dir).unwrap();
+        assert_eq!(keyring.entries().len(), 1);
+        let e = keyring.get("alice smith").unwrap();
+        assert_eq!(e.fingerprint, [1; 32]);
+        assert!(!e.manual);
+        assert!(keyring.get("mallory").is_none());
+    }
+
+    #[test]
+    fn public_key_pem_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("fver-keyring-import-{}", process::id()));
+        create_dir_all(&dir).unwrap();
+        let identity = Identity::generate("alice").unwrap();
+        let mut keyring = Keyring::load(&dir).unwrap();
+        let pem = identity.public_key_pem().unwrap();
+        let fingerprint = keyring.import("alice", &pem).unwrap();
+        assert_eq!(fingerprint, identity.fingerprint().unwrap());
+
+        let pem = identity.private_key_pem("secret").unwrap();
+        assert!(matches!(keyring.import("eve", &pem), Err(UnsupportedKey)));
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let other = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let pem = other.public_key_to_pem().unwrap();
+        assert!(matches!(keyring.import("eve", &pem), Err(UnsupportedKey)));
+        keyring.save().unwrap();
+
+        let keyring = Keyring::load(&dir).unwrap();
+        remove_dir_all(&dir).unwrap();
+        assert_eq!(keyring.entries().len(), 1);
+        let e = keyring.get("alice").unwrap();
+        assert_eq!(e.fingerprint, fingerprint);
+        assert!(e.manual);
+    }
+}
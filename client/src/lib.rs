@@ -0,0 +1,42 @@
+//! Client library for signing and verifying files with an fver server.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), fver::Error> {
+//! let mut client = fver::Client::connect("localhost:37687")?;
+//! let report = client.verify_file("release.tar.gz")?;
+//! for sig in &report.signatures {
+//!     if let Some(signer) = &sig.signer {
+//!         println!("{} valid: {}", signer.username, sig.valid);
+//!     }
+//! }
+//! let policy = fver::Policy {
+//!     required: vec![String::from("alice"), String::from("bob")],
+//!     ..Default::default()
+//! };
+//! if policy.evaluate(&report) != fver::Verdict::Verified {
+//!     std::process::exit(1);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub use crate::client::{
+    Client, Inclusion, Mismatch, SignatureReport, Signer, Trust, VerificationReport,
+};
+pub use crate::config::{config_dir, default_server, DEFAULT_SERVER};
+pub use crate::error::Error;
+pub use crate::identity::Identity;
+pub use crate::keyring::{Keyring, KeyringEntry};
+pub use crate::policy::{Policy, Verdict};
+pub use fver_proto::{Checkpoint, Signature, MAX_FIELD_LEN};
+
+mod checkpoint;
+mod client;
+mod config;
+mod error;
+mod identity;
+mod keyring;
+mod policy;
+mod remotestorage;
+#[cfg(test)]
+mod testserver;
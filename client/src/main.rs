@@ -1,207 +1,919 @@
-use crate::error::Error;
-use crate::error::Error::NoDataDirectory;
-use crate::remotestorage::{RemoteStorage, Signature, User};
-use dirs::data_dir;
+use crate::json::Json;
+use fver::Error::{
+    InvalidSignature, KeyMismatch, RevokedKey, StaleChainHead, UnknownKey, UnknownUser,
+    UnsupportedKey, UnsupportedRequest, WrongPassphrase,
+};
+use fver::{
+    default_server, Client, Error, Identity, Inclusion, Keyring, Mismatch, Policy, SignatureReport,
+    Trust, Verdict, VerificationReport, MAX_FIELD_LEN,
+};
 use hex::encode;
-use openssl::ec::{EcGroup, EcKey};
-use openssl::hash::{Hasher, MessageDigest};
-use openssl::nid::Nid;
-use openssl::pkey::{PKey, Private};
 use openssl::sha::sha256;
-use openssl::sign::{Signer, Verifier};
+use rpassword::prompt_password_stderr;
 use std::convert::TryInto;
-use std::env::args;
-use std::fs::{create_dir_all, File};
-use std::io;
-use std::io::{stdin, stdout, BufRead, Read, Write};
-use std::path::Path;
+use std::env::{args, var};
+use std::fs::read;
+use std::io::{stdin, stdout, BufRead, Write};
 use std::process::exit;
 
-mod error;
-mod remotestorage;
-
-struct Session {
-    storage: RemoteStorage,
-    key: PKey<Private>,
-    username: String,
-}
-
-impl Session {
-    fn login() -> Result<Self, Error> {
-        let mut storage = RemoteStorage::new("localhost:37687")?;
-        let mut config_path = data_dir().ok_or(NoDataDirectory)?;
-        config_path.push("fver");
-        let key;
-        let username;
-        if config_path.exists() {
-            let mut keyfile = File::open(config_path.join("key"))?;
-            let mut der = Vec::new();
-            keyfile.read_to_end(&mut der)?;
-            key = PKey::from_ec_key(EcKey::private_key_from_der(&der)?)?;
-            let mut username_file = File::open(config_path.join("username"))?;
-            let mut username_vec = Vec::new();
-            username_file.read_to_end(&mut username_vec)?;
-            username = String::from_utf8(username_vec)?;
+mod json;
+
+// Exit codes of verify, the worst result over all files is returned
+const EXIT_VERIFIED: i32 = 0;
+const EXIT_NOT_ENOUGH_SIGNATURES: i32 = 1;
+const EXIT_INVALID_SIGNATURE: i32 = 2;
+const EXIT_ERROR: i32 = 3;
+
+#[derive(PartialEq)]
+enum Format {
+    Text,
+    Json,
+}
+
+struct Options {
+    server: String,
+    ca: Option<Vec<u8>>,
+    format: Format,
+    policy: Policy,
+}
+
+fn connect(options: &Options) -> Result<Client, Error> {
+    let ca = options.ca.as_deref();
+    let client = match Client::connect_with_ca(&options.server, ca) {
+        Err(Error::UntrustedServer) if ca.is_some() => {
+            eprintln!("A different CA is already pinned for this server.");
+            return Err(Error::UntrustedServer);
+        }
+        r => r?,
+    };
+    if client.newly_pinned() {
+        let notice = format!(
+            "Pinned server key {}",
+            encode(&sha256(client.server_key())[..8])
+        );
+        // Keeps stdout parseable
+        match options.format {
+            Format::Text => println!("{}", notice),
+            Format::Json => eprintln!("{}", notice),
+        }
+    }
+    Ok(client)
+}
+
+// FVER_PASSPHRASE is for scripts, where there is nobody to ask
+fn passphrase() -> Result<String, Error> {
+    match var("FVER_PASSPHRASE") {
+        Ok(passphrase) => Ok(passphrase),
+        Err(_) => Ok(prompt_password_stderr("Passphrase: ")?),
+    }
+}
+
+fn new_passphrase() -> Result<String, Error> {
+    if let Ok(passphrase) = var("FVER_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    loop {
+        let passphrase = prompt_password_stderr("New passphrase: ")?;
+        if passphrase.is_empty() {
+            eprintln!("Passphrase must not be empty.");
+        } else if passphrase != prompt_password_stderr("Repeat passphrase: ")? {
+            eprintln!("Passphrases do not match.");
         } else {
-            loop {
+            return Ok(passphrase);
+        }
+    }
+}
+
+fn load_identity() -> Result<Option<Identity>, Error> {
+    match Identity::load(passphrase) {
+        Err(WrongPassphrase) => {
+            eprintln!("Wrong passphrase.");
+            exit(1);
+        }
+        r => r,
+    }
+}
+
+fn login(client: &mut Client) -> Result<Identity, Error> {
+    let identity = match load_identity()? {
+        Some(identity) => {
+            if !identity.is_encrypted() {
+                eprintln!("Private key is stored unencrypted, run 'fver key migrate'.");
+            }
+            identity
+        }
+        None => {
+            let username = loop {
                 print!("Enter new username: ");
                 stdout().flush()?;
                 let mut input = String::new();
                 stdin().lock().read_line(&mut input)?;
                 input.retain(|c| c != '\n' && c != '\r');
-                match storage.get_user_by_username(&input)? {
-                    None => {
-                        username = input;
-                        break;
+                if !client.is_registered(&input)? {
+                    break input;
+                }
+                println!("Username already registered.");
+            };
+            let mut identity = Identity::generate(&username)?;
+            identity.save(&new_passphrase()?)?;
+            identity
+        }
+    };
+    match client.register(&identity) {
+        Ok(true) => println!("Registered new key."),
+        Ok(false) => {}
+        Err(KeyMismatch) => {
+            eprintln!("Username already registered with different key.");
+            exit(1);
+        }
+        Err(e) => return Err(e),
+    }
+    println!("Logged in as {}", identity.username());
+    Ok(identity)
+}
+
+fn sign(options: &Options, file: &str) -> Result<(), Error> {
+    let mut client = connect(options)?;
+    let identity = login(&mut client)?;
+    let sig = client.sign_file(&identity, file)?;
+    println!("object hash {}", encode(&sig.obj[..8]));
+    println!("user hash {}", encode(&sig.user[..8]));
+    println!("previous in chain {}", encode(&sig.prev_sig[..8]));
+    println!("Signature was successfully pushed to the server.");
+    Ok(())
+}
+
+fn withdraw(options: &Options, args: &[String]) -> Result<(), Error> {
+    let mut args = args.to_vec();
+    let reason = take_option(&mut args, "--reason", "a text", 1).unwrap_or_default();
+    if args.len() != 1 {
+        eprintln!("Usage: fver withdraw <file> [--reason <text>]");
+        exit(1);
+    }
+    if reason.len() > MAX_FIELD_LEN {
+        eprintln!("The reason can be at most {} bytes long!", MAX_FIELD_LEN);
+        exit(1);
+    }
+    let identity = stored_identity()?;
+    let mut client = connect(options)?;
+    let withdrawals = client.withdraw_file(&identity, &args[0], &reason)?;
+    if withdrawals.is_empty() {
+        eprintln!("No signature of {} to withdraw.", identity.username());
+        exit(1);
+    }
+    for w in withdrawals {
+        println!("Withdrew signature {}.", encode(&w.sig[..8]));
+    }
+    Ok(())
+}
+
+// A file that cannot be verified does not stop the others
+fn verify(options: &Options, files: &[String]) -> i32 {
+    let mut client = match connect(options) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return EXIT_ERROR;
+        }
+    };
+    let mut code = EXIT_VERIFIED;
+    for file in files {
+        let file_code = match client.verify_file(file) {
+            Ok(report) => {
+                for s in &report.signatures {
+                    if let (Trust::Mismatch { .. }, Some(signer)) = (s.trust, &s.signer) {
+                        eprintln!(
+                            "WARNING: the server returned a different key for {} than the pinned one!",
+                            signer.username
+                        );
                     }
-                    Some(_) => {
-                        println!("Username already registered.");
+                }
+                let verdict = options.policy.evaluate(&report);
+                match options.format {
+                    Format::Text => {
+                        print_report(&report);
+                        print_verdict(verdict);
                     }
+                    Format::Json => println!("{}", report_json(file, &report, verdict)),
+                }
+                match verdict {
+                    Verdict::Verified => EXIT_VERIFIED,
+                    Verdict::NotEnoughSignatures => EXIT_NOT_ENOUGH_SIGNATURES,
+                    Verdict::InvalidSignature => EXIT_INVALID_SIGNATURE,
                 }
             }
-            key = PKey::from_ec_key(EcKey::generate(
-                EcGroup::from_curve_name(Nid::SECP384R1)?.as_ref(),
-            )?)?;
-            create_dir_all(&config_path)?;
-
-            let mut key_file = File::create(config_path.join("key"))?;
-            key_file.write_all(&key.private_key_to_der()?)?;
-
-            let mut username_file = File::create(config_path.join("username"))?;
-            username_file.write_all(username.as_bytes())?;
-        }
-        match storage.get_user_by_username(&username)? {
-            None => {
-                println!("Key not registered. Registering.");
-                let u = User {
-                    key: key.public_key_to_der()?,
-                    username: username.as_bytes().to_vec(),
-                };
-                storage.set_user(u)?;
-            }
-            Some(u) => {
-                if u.key != key.public_key_to_der()? {
-                    eprintln!("Username already registered with different key.");
-                    exit(1);
+            Err(e) => {
+                match options.format {
+                    Format::Text => eprintln!("{}: Error: {:?}", file, e),
+                    Format::Json => {
+                        let doc = Json::Object(vec![
+                            ("file", Json::string(file.as_str())),
+                            ("error", Json::string(format!("{:?}", e))),
+                        ]);
+                        println!("{}", doc);
+                    }
                 }
+                EXIT_ERROR
             }
-        }
-        println!("Logged in as {}", username);
-        Ok(Self {
-            key,
-            username,
-            storage: RemoteStorage::new("localhost:37687")?,
-        })
+        };
+        code = code.max(file_code);
     }
+    code
+}
 
-    fn sign<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
-        let mut file = File::open(path)?;
-        let mut hasher = Hasher::new(MessageDigest::sha256())?;
-        io::copy(&mut file, &mut hasher)?;
-        let hash = hasher.finish()?.to_vec();
-
-        let prev_sig = self.storage.get_prev()?;
+fn print_verdict(verdict: Verdict) {
+    match verdict {
+        Verdict::Verified => println!("Verified."),
+        Verdict::NotEnoughSignatures => println!("NOT verified: not enough trusted signatures."),
+        Verdict::InvalidSignature => println!("NOT verified: invalid signature present."),
+    }
+}
 
-        let mut signer = Signer::new_without_digest(&self.key)?;
-        signer.write_all(&hash)?;
-        signer.write_all(&prev_sig)?;
+fn verdict_name(verdict: Verdict) -> &'static str {
+    match verdict {
+        Verdict::Verified => "verified",
+        Verdict::NotEnoughSignatures => "not enough signatures",
+        Verdict::InvalidSignature => "invalid signature",
+    }
+}
 
-        let sig = Signature {
-            obj: hash[..].try_into()?,
-            user: self.username_hash()?,
-            prev_sig,
-            signature: signer.sign_to_vec()?,
-        };
+fn report_json(file: &str, report: &VerificationReport, verdict: Verdict) -> Json {
+    Json::Object(vec![
+        ("file", Json::string(file)),
+        ("verdict", Json::string(verdict_name(verdict))),
+        ("object", Json::hex(&report.object)),
+        (
+            "checkpoint",
+            Json::Object(vec![
+                ("size", Json::Number(report.checkpoint.size)),
+                ("root", Json::hex(&report.checkpoint.root)),
+            ]),
+        ),
+        (
+            "signatures",
+            Json::Array(report.signatures.iter().map(signature_json).collect()),
+        ),
+    ])
+}
 
-        println!("object hash {}", encode(&sig.obj[..8]));
-        println!("user hash {}", encode(&sig.user[..8]));
-        println!("previous in chain {}", encode(&sig.prev_sig[..8]));
+fn signature_json(s: &SignatureReport) -> Json {
+    let error = match (&s.signature, &s.signer) {
+        (None, _) => Some("unknown signature"),
+        (Some(_), None) => Some("unknown user"),
+        (Some(_), Some(_)) if !s.valid => Some("invalid signature"),
+        _ if !s.mismatches.is_empty() => Some("field mismatch"),
+        _ if s.revoked.is_some() => Some("revoked key"),
+        _ if s.withdrawn.is_some() => Some("withdrawn by signer"),
+        _ if matches!(s.trust, Trust::Mismatch { .. }) => Some("key mismatch"),
+        _ => None,
+    };
+    let trust = match s.trust {
+        Trust::Trusted => "trusted",
+        Trust::Pinned => "pinned",
+        Trust::NewlyPinned => "newly pinned",
+        Trust::Mismatch { .. } => "mismatch",
+        Trust::Unknown => "unknown",
+    };
+    let pinned = match s.trust {
+        Trust::Mismatch { pinned } => Some(pinned),
+        _ => None,
+    };
+    let mismatches = s
+        .mismatches
+        .iter()
+        .map(|m| Json::string(mismatch_name(*m)))
+        .collect();
+    let inclusion = match s.inclusion {
+        Inclusion::Included { index, size } => vec![
+            ("status", Json::string("included")),
+            ("index", Json::Number(index)),
+            ("size", Json::Number(size)),
+        ],
+        Inclusion::NotIncluded => vec![("status", Json::string("not included"))],
+        Inclusion::InvalidProof => vec![("status", Json::string("invalid proof"))],
+    };
+    Json::Object(vec![
+        ("hash", Json::hex(&s.hash)),
+        (
+            "signer",
+            Json::or_null(s.signer.as_ref(), |u| Json::string(u.username.as_str())),
+        ),
+        (
+            "fingerprint",
+            Json::or_null(s.signer.as_ref(), |u| Json::hex(&u.fingerprint())),
+        ),
+        (
+            "user",
+            Json::or_null(s.signature.as_ref(), |sig| Json::hex(&sig.user)),
+        ),
+        (
+            "prev_sig",
+            Json::or_null(s.signature.as_ref(), |sig| Json::hex(&sig.prev_sig)),
+        ),
+        ("valid", Json::Bool(s.valid)),
+        ("mismatches", Json::Array(mismatches)),
+        ("revoked_since", Json::or_null(s.revoked, Json::Number)),
+        (
+            "withdrawn",
+            Json::or_null(s.withdrawn.as_deref(), Json::string),
+        ),
+        ("inclusion", Json::Object(inclusion)),
+        ("trust", Json::string(trust)),
+        (
+            "pinned_fingerprint",
+            Json::or_null(pinned, |p| Json::hex(&p)),
+        ),
+        ("error", Json::or_null(error, Json::string)),
+    ])
+}
 
-        self.storage.add_sig(sig)?;
-        println!("Signature was successfully pushed to the server.");
-        Ok(())
+fn mismatch_name(m: Mismatch) -> &'static str {
+    match m {
+        Mismatch::SignatureHash => "signature hash",
+        Mismatch::Object => "object",
+        Mismatch::User => "user",
+        Mismatch::KeyHistory => "key history",
     }
+}
 
-    fn verify<P: AsRef<Path>>(path: P) -> Result<(), Error> {
-        let mut file = File::open(path)?;
-        let mut hasher = Hasher::new(MessageDigest::sha256())?;
-        io::copy(&mut file, &mut hasher)?;
-        let hash = hasher.finish()?[..].try_into()?;
-        println!("{}", encode(&hash));
-        let mut storage = RemoteStorage::new("localhost:37687")?;
-        let sigs = storage.get_obj(hash)?;
+fn print_report(report: &VerificationReport) {
+    println!("{}", encode(report.object));
+    println!(
+        "Checkpoint: log size {}, root {}",
+        report.checkpoint.size,
+        encode(&report.checkpoint.root[..8])
+    );
+    println!(
+        "Found {} signature(s) of object {}.",
+        report.signatures.len(),
+        encode(&report.object[..8])
+    );
+    for s in &report.signatures {
+        let (sig, signer) = match (&s.signature, &s.signer) {
+            (None, _) => {
+                println!("<unknown signature>");
+                continue;
+            }
+            (Some(_), None) => {
+                println!("<unknown user>");
+                continue;
+            }
+            (Some(sig), Some(signer)) => (sig, signer),
+        };
         println!(
-            "Found {} signature(s) of object {}.",
-            sigs.len(),
-            encode(&hash[..8])
+            "{} (key {})",
+            signer.username,
+            encode(&signer.fingerprint()[..8])
         );
-        for sig in sigs {
-            Session::verify_sig(&mut storage, sig)?;
+        println!("  signature hash {}", encode(&s.hash[..8]));
+        println!("  object hash {}", encode(&sig.obj[..8]));
+        println!("  user hash {}", encode(&sig.user[..8]));
+        println!("  previous in chain {}", encode(&sig.prev_sig[..8]));
+        if let (true, Some(since)) = (s.valid, s.revoked) {
+            println!(
+                "  signature made with REVOKED key (revoked from log position {}).",
+                since
+            );
+        } else if s.valid {
+            println!("  signature valid.");
+        } else {
+            println!("  signature INVALID.");
+        }
+        for m in &s.mismatches {
+            match m {
+                Mismatch::SignatureHash => {
+                    println!("  signature hash DOES NOT MATCH requested hash.")
+                }
+                Mismatch::Object => println!("  signature is of ANOTHER object."),
+                Mismatch::User => println!("  username DOES NOT MATCH user hash."),
+                Mismatch::KeyHistory => println!("  key rotations of the signer DO NOT VERIFY."),
+            }
+        }
+        match s.withdrawn.as_deref() {
+            Some("") => println!("  WITHDRAWN by signer."),
+            Some(reason) => println!("  WITHDRAWN by signer: {}", reason),
+            None => {}
+        }
+        if signer.keys.last().is_some_and(|k| *k != signer.key) {
+            println!("  made with a previous key of the signer.");
+        }
+        match s.inclusion {
+            Inclusion::Included { index, size } => {
+                println!("  included in log at position {} of {}.", index, size)
+            }
+            Inclusion::NotIncluded => println!("  NOT included in transparency log."),
+            Inclusion::InvalidProof => println!("  inclusion proof INVALID."),
+        }
+        match s.trust {
+            Trust::Trusted => println!("  signer trusted."),
+            Trust::Pinned | Trust::Unknown => {}
+            Trust::NewlyPinned => println!("  signer key seen for the first time, pinned."),
+            Trust::Mismatch { pinned } => println!(
+                "  signer key DOES NOT MATCH pinned key {}.",
+                encode(&pinned[..8])
+            ),
         }
-        Ok(())
     }
+}
 
-    fn verify_sig(storage: &mut RemoteStorage, hash: [u8; 32]) -> Result<(), Error> {
-        let sig = storage.get_sig(hash)?;
-        match sig {
-            None => {
-                println!("<unknown signature>");
+fn trust(options: &Options, args: &[String]) -> Result<(), Error> {
+    let mut keyring = Keyring::open(&options.server)?;
+    match (args.first().map(String::as_str), args.len()) {
+        (Some("list"), 1) => {
+            for e in keyring.entries() {
+                println!(
+                    "{} {} {}",
+                    encode(e.fingerprint),
+                    if e.manual { "trusted" } else { "pinned " },
+                    e.username
+                );
             }
-            Some(sig) => {
-                let user = storage.get_user(sig.user)?;
-                match user {
-                    None => println!("<unknown user>"),
-                    Some(u) => {
-                        println!(
-                            "{} (key {})",
-                            String::from_utf8_lossy(&u.username),
-                            encode(&u.key[..8])
-                        );
-                        println!("  signature hash {}", encode(&hash[..8]));
-                        println!("  object hash {}", encode(&sig.obj[..8]));
-                        println!("  user hash {}", encode(&sig.user[..8]));
-                        println!("  previous in chain {}", encode(&sig.prev_sig[..8]));
-                        let key = PKey::public_key_from_der(&u.key)?;
-                        let mut verifier = Verifier::new_without_digest(key.as_ref())?;
-                        verifier.write_all(&sig.obj)?;
-                        verifier.write_all(&sig.prev_sig)?;
-                        if verifier.verify(&sig.signature)? {
-                            println!("  signature valid.");
-                        } else {
-                            println!("  signature INVALID.");
+            return Ok(());
+        }
+        (Some("add"), 2) | (Some("add"), 3) => {
+            let username = &args[1];
+            let fingerprint = match args.get(2) {
+                Some(fingerprint) => parse_fingerprint(fingerprint),
+                // Without a fingerprint the key currently on the server is trusted
+                None => match connect(options)?.signer(username)? {
+                    Some(signer) => signer.fingerprint(),
+                    None => {
+                        eprintln!("Unknown user {}!", username);
+                        exit(1);
+                    }
+                },
+            };
+            keyring.add(username, fingerprint, true)?;
+            println!("Trusted {} (key {})", username, encode(&fingerprint[..8]));
+        }
+        (Some("remove"), 2) => {
+            if !keyring.remove(&args[1]) {
+                eprintln!("{} is not in the keyring!", args[1]);
+                exit(1);
+            }
+        }
+        _ => {
+            eprintln!("Usage: fver trust list | add <username> [fingerprint] | remove <username>");
+            exit(1);
+        }
+    }
+    keyring.save()
+}
+
+fn stored_identity() -> Result<Identity, Error> {
+    match load_identity()? {
+        Some(identity) => Ok(identity),
+        None => {
+            eprintln!("Not logged in.");
+            exit(1);
+        }
+    }
+}
+
+fn key(options: &Options, args: &[String]) -> Result<(), Error> {
+    let mut args = args.to_vec();
+    let public = take_flag(&mut args, "--public");
+    let force = take_flag(&mut args, "--force");
+    let from = take_option(&mut args, "--from", "a log position", 1).map(|n| match n.parse() {
+        Ok(n) => n,
+        Err(_) => {
+            eprintln!("Invalid log position '{}'!", n);
+            exit(1);
+        }
+    });
+    match (args.first().map(String::as_str), args.len()) {
+        (Some("migrate"), 1) if !public && !force && from.is_none() => {
+            let mut identity = stored_identity()?;
+            if identity.is_encrypted() {
+                println!("Private key is already encrypted.");
+                return Ok(());
+            }
+            identity.save(&new_passphrase()?)?;
+            println!("Private key encrypted.");
+        }
+        (Some("rotate"), 1) if !public && !force && from.is_none() => {
+            let identity = stored_identity()?;
+            let mut client = connect(options)?;
+            let mut next = Identity::generate(identity.username())?;
+            // Kept aside until the server accepts it, the old key is still the registered one
+            next.save_next(&new_passphrase()?)?;
+            match client.rotate_key(&identity, &next) {
+                Ok(rotation) => {
+                    Identity::promote_next()?;
+                    println!(
+                        "Rotated key {} to {}, effective from log position {}.",
+                        encode(&identity.fingerprint()?[..8]),
+                        encode(&next.fingerprint()?[..8]),
+                        rotation.since
+                    );
+                }
+                Err(e @ KeyMismatch)
+                | Err(e @ UnknownUser)
+                | Err(e @ InvalidSignature)
+                | Err(e @ RevokedKey)
+                | Err(e @ StaleChainHead)
+                | Err(e @ UnsupportedRequest) => {
+                    Identity::discard_next()?;
+                    return Err(e);
+                }
+                Err(e) => {
+                    eprintln!("The server may have accepted the new key, it was kept as key.next in the fver data directory.");
+                    return Err(e);
+                }
+            }
+        }
+        (Some("revoke"), 1) | (Some("revoke"), 2) if !public => {
+            let identity = stored_identity()?;
+            let fingerprint = args.get(1).map(|f| parse_fingerprint(f));
+            let current = identity.fingerprint()?;
+            // Rotating away from a revoked key is refused, nothing could be signed anymore
+            if fingerprint.is_none_or(|f| f == current) && !force {
+                eprintln!(
+                    "Revoking the current key locks you out for good, use --force to do it anyway."
+                );
+                eprintln!(
+                    "To replace a compromised key run `fver key rotate` and then `fver key revoke {}`.",
+                    encode(current)
+                );
+                exit(1);
+            }
+            let mut client = connect(options)?;
+            let key = match fingerprint {
+                None => identity.public_key()?,
+                Some(fingerprint) => {
+                    let keys = client.keys(identity.username())?;
+                    match keys.into_iter().find(|k| sha256(k) == fingerprint) {
+                        Some(key) => key,
+                        None => {
+                            eprintln!("{} never had that key!", identity.username());
+                            exit(1);
                         }
                     }
                 }
+            };
+            let since = from.unwrap_or(client.checkpoint().size);
+            match client.revoke_key(&identity, &key, since) {
+                Err(UnknownKey) => {
+                    eprintln!("The server does not know that key.");
+                    exit(1);
+                }
+                r => r?,
+            };
+            println!(
+                "Revoked key {} from log position {}.",
+                encode(&sha256(&key)[..8]),
+                since
+            );
+        }
+        (Some("revocations"), 2) if !public && !force && from.is_none() => {
+            let mut client = connect(options)?;
+            let revocations = match client.revocations(&args[1]) {
+                Err(UnknownUser) => {
+                    eprintln!("Unknown user {}!", args[1]);
+                    exit(1);
+                }
+                r => r?,
+            };
+            for r in revocations {
+                println!(
+                    "{} revoked from log position {}",
+                    encode(sha256(&r.key)),
+                    r.since
+                );
+            }
+        }
+        (Some("export"), 1) if !force && from.is_none() => {
+            let identity = stored_identity()?;
+            if public {
+                stdout().write_all(&identity.public_key_pem()?)?;
+                eprintln!("Fingerprint: {}", encode(identity.fingerprint()?));
+            } else {
+                stdout().write_all(&identity.private_key_pem(&new_passphrase()?)?)?;
+            }
+        }
+        (Some("import"), 3) if public && !force && from.is_none() => {
+            let pem = read(&args[1])?;
+            let mut keyring = Keyring::open(&options.server)?;
+            let fingerprint = match keyring.import(&args[2], &pem) {
+                Err(UnsupportedKey) => {
+                    eprintln!("Only secp384r1 public keys in PEM/SPKI format are supported.");
+                    exit(1);
+                }
+                r => r?,
+            };
+            keyring.save()?;
+            println!("Trusted {} (key {})", args[2], encode(&fingerprint[..8]));
+            eprintln!("Fingerprint: {}", encode(fingerprint));
+        }
+        (Some("import"), 3) if !public && from.is_none() => {
+            if Identity::exists()? && !force {
+                eprintln!("A key is already stored, use --force to replace it.");
+                exit(1);
             }
+            let pem = read(&args[1])?;
+            let mut identity = match Identity::from_pem(&args[2], &pem, passphrase) {
+                Err(WrongPassphrase) => {
+                    eprintln!("Wrong passphrase.");
+                    exit(1);
+                }
+                Err(UnsupportedKey) => {
+                    eprintln!("Only secp384r1 keys are supported.");
+                    exit(1);
+                }
+                r => r?,
+            };
+            identity.save(&new_passphrase()?)?;
+            println!(
+                "Imported key {} for {}.",
+                encode(&identity.fingerprint()?[..8]),
+                identity.username()
+            );
+        }
+        _ => {
+            eprintln!(
+                "Usage: fver key migrate | rotate | revoke [fingerprint] [--from <position>] [--force] | revocations <username> | export [--public] | import <file> <username> [--force] | import --public <file> <username>"
+            );
+            exit(1);
         }
-        Ok(())
     }
+    Ok(())
+}
+
+fn parse_fingerprint(s: &str) -> [u8; 32] {
+    match hex::decode(s).ok().and_then(|f| f.try_into().ok()) {
+        Some(fingerprint) => fingerprint,
+        None => {
+            eprintln!("Fingerprint must be 64 hex digits!");
+            exit(1);
+        }
+    }
+}
 
-    fn username_hash(&self) -> Result<[u8; 32], Error> {
-        Ok(sha256(self.username.as_bytes()))
+// Removes `name` and its value from the arguments, exits with `code` if the value is missing
+fn take_option(args: &mut Vec<String>, name: &str, value: &str, code: i32) -> Option<String> {
+    let i = args.iter().position(|a| a == name)?;
+    args.remove(i);
+    if i == args.len() {
+        eprintln!("{} requires {}!", name, value);
+        exit(code);
+    }
+    Some(args.remove(i))
+}
+
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|a| a == name) {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+fn take_policy(args: &mut Vec<String>, code: i32) -> Policy {
+    let mut required = Vec::new();
+    while let Some(signer) = take_option(args, "--require", "a username or key fingerprint", code) {
+        required.push(signer);
+    }
+    let min_valid = take_option(args, "--min-valid", "a number", code).map(|n| match n.parse() {
+        Ok(n) if n >= 1 && (required.is_empty() || n <= required.len()) => n,
+        _ => {
+            eprintln!("Invalid signature count '{}'!", n);
+            exit(code);
+        }
+    });
+    Policy {
+        required,
+        min_valid,
+        invalid_fatal: take_flag(args, "--fail-on-invalid"),
+        trusted_only: take_flag(args, "--trusted-only"),
+    }
+}
+
+// Options taking a value, skipped when looking for the command
+const VALUE_OPTIONS: &[&str] = &["--server", "--ca", "--format", "--require", "--min-valid"];
+
+// Exit code for invalid arguments. verify exits with 1 only for not enough
+// signatures, so a CI gate can tell it from a broken invocation.
+fn usage_exit_code(args: &[String]) -> i32 {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if VALUE_OPTIONS.contains(&arg.as_str()) {
+            args.next();
+        } else if !arg.starts_with("--") {
+            return if arg == "verify" { EXIT_ERROR } else { 1 };
+        }
     }
+    1
 }
 
 fn main() {
-    let mut args = args();
-    let command = args.nth(1).unwrap();
-    match command.as_str() {
-        "login" => {
-            Session::login().unwrap();
-        }
-        "sign" => {
-            let file = args.next().unwrap();
-            let mut session = Session::login().unwrap();
-            let r = session.sign(file);
-            r.unwrap();
-        }
-        "verify" => {
-            let file = args.next().unwrap();
-            Session::verify(file).unwrap();
+    let mut args: Vec<String> = args().skip(1).collect();
+    let code = usage_exit_code(&args);
+    let server = take_option(&mut args, "--server", "an address", code);
+    let ca = take_option(&mut args, "--ca", "a file", code);
+    let format = match take_option(&mut args, "--format", "text or json", code).as_deref() {
+        None | Some("text") => Format::Text,
+        Some("json") => Format::Json,
+        Some(format) => {
+            eprintln!("Unknown format '{}'!", format);
+            exit(code);
         }
+    };
+    let server = match server.map_or_else(default_server, Ok) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            exit(code);
+        }
+    };
+    let ca = match ca.map(read).transpose() {
+        Ok(ca) => ca,
+        Err(e) => {
+            eprintln!("Cannot read CA: {}", e);
+            exit(code);
+        }
+    };
+    let policy = take_policy(&mut args, code);
+    let verify_options = format == Format::Json
+        || !policy.required.is_empty()
+        || policy.min_valid.is_some()
+        || policy.invalid_fatal
+        || policy.trusted_only;
+    let options = Options {
+        server,
+        ca,
+        format,
+        policy,
+    };
+    let mut args = args.into_iter();
+    let command = args.next().unwrap_or_default();
+    let files: Vec<String> = args.collect();
+    if command != "verify" && verify_options {
+        eprintln!("Output format and policy options are only supported by verify!");
+        exit(1);
+    }
+    let result = match command.as_str() {
+        "login" => connect(&options).and_then(|mut client| login(&mut client).map(|_| ())),
+        "sign" | "verify" if files.is_empty() => {
+            eprintln!("Missing file!");
+            exit(code);
+        }
+        "sign" => sign(&options, &files[0]),
+        "trust" => trust(&options, &files),
+        "key" => key(&options, &files),
+        "withdraw" => withdraw(&options, &files),
+        "verify" => exit(verify(&options, &files)),
         _ => {
             eprintln!("Unknown command!");
             exit(1);
         }
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {:?}", e);
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fver::{Checkpoint, Signature, Signer};
+    use serde_json::{json, Value};
+
+    fn signature(username: &str, withdrawn: Option<&str>) -> SignatureReport {
+        SignatureReport {
+            hash: [3; 32],
+            signature: Some(Signature {
+                obj: [1; 32],
+                user: [2; 32],
+                prev_sig: [0; 32],
+                signature: Vec::new(),
+            }),
+            signer: Some(Signer {
+                username: username.to_string(),
+                key: b"key".to_vec(),
+                keys: Vec::new(),
+            }),
+            valid: true,
+            inclusion: Inclusion::Included { index: 0, size: 2 },
+            trust: Trust::Pinned,
+            mismatches: Vec::new(),
+            revoked: None,
+            withdrawn: withdrawn.map(str::to_string),
+        }
+    }
+
+    fn report(signatures: Vec<SignatureReport>) -> VerificationReport {
+        VerificationReport {
+            object: [1; 32],
+            checkpoint: Checkpoint {
+                size: 2,
+                root: [5; 32],
+                head: [3; 32],
+                timestamp: 0,
+            },
+            signatures,
+        }
+    }
+
+    #[test]
+    fn json_report_shape() {
+        let mut forged = signature("bob", None);
+        forged.inclusion = Inclusion::InvalidProof;
+        forged.trust = Trust::Mismatch { pinned: [6; 32] };
+        forged.mismatches = vec![Mismatch::Object, Mismatch::KeyHistory];
+        forged.revoked = Some(1);
+        let unknown = SignatureReport {
+            signature: None,
+            signer: None,
+            valid: false,
+            inclusion: Inclusion::NotIncluded,
+            trust: Trust::Unknown,
+            ..signature("", None)
+        };
+        let report = report(vec![signature("alice", Some("")), forged, unknown]);
+        let doc = report_json("file", &report, Verdict::InvalidSignature).to_string();
+        let value: Value = serde_json::from_str(&doc).unwrap();
+
+        let fingerprint = encode(sha256(b"key"));
+        let hex = |b: u8| encode([b; 32]);
+        assert_eq!(
+            value,
+            json!({
+                "file": "file",
+                "verdict": "invalid signature",
+                "object": hex(1),
+                "checkpoint": {"size": 2, "root": hex(5)},
+                "signatures": [
+                    {
+                        "hash": hex(3),
+                        "signer": "alice",
+                        "fingerprint": fingerprint,
+                        "user": hex(2),
+                        "prev_sig": hex(0),
+                        "valid": true,
+                        "mismatches": [],
+                        "revoked_since": null,
+                        "withdrawn": "",
+                        "inclusion": {"status": "included", "index": 0, "size": 2},
+                        "trust": "pinned",
+                        "pinned_fingerprint": null,
+                        "error": "withdrawn by signer",
+                    },
+                    {
+                        "hash": hex(3),
+                        "signer": "bob",
+                        "fingerprint": fingerprint,
+                        "user": hex(2),
+                        "prev_sig": hex(0),
+                        "valid": true,
+                        "mismatches": ["object", "key history"],
+                        "revoked_since": 1,
+                        "withdrawn": null,
+                        "inclusion": {"status": "invalid proof"},
+                        "trust": "mismatch",
+                        "pinned_fingerprint": hex(6),
+                        "error": "field mismatch",
+                    },
+                    {
+                        "hash": hex(3),
+                        "signer": null,
+                        "fingerprint": null,
+                        "user": null,
+                        "prev_sig": null,
+                        "valid": false,
+                        "mismatches": [],
+                        "revoked_since": null,
+                        "withdrawn": null,
+                        "inclusion": {"status": "not included"},
+                        "trust": "unknown",
+                        "pinned_fingerprint": null,
+                        "error": "unknown signature",
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn json_report_escapes_names() {
+        for name in &[
+            "quote \" and backslash \\",
+            "line\nbreak\ttab\rreturn",
+            "control \u{1}\u{1f}\u{7f}",
+            "ünïcødé ✓ \u{2028}",
+            "\",\"verdict\":\"verified",
+        ] {
+            let report = report(vec![signature(name, Some(name))]);
+            let doc = report_json(name, &report, Verdict::Verified).to_string();
+            // One document per line
+            assert!(!doc.contains('\n'));
+            let value: Value = serde_json::from_str(&doc).unwrap();
+            assert_eq!(value["file"], *name);
+            assert_eq!(value["verdict"], "verified");
+            assert_eq!(value["signatures"][0]["signer"], *name);
+            assert_eq!(value["signatures"][0]["withdrawn"], *name);
+        }
     }
 }
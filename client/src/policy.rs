@@ -0,0 +1,288 @@
+use crate::client::{Inclusion, SignatureReport, Trust, VerificationReport};
+use std::collections::{HashMap, HashSet};
+
+/// What a verification has to find to succeed
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    /// Usernames or full hex key fingerprints, empty accepts any signer
+    pub required: Vec<String>,
+    /// Number of distinct users with a valid signature needed. `None` means
+    /// all required signers, or one if there are none.
+    pub min_valid: Option<usize>,
+    /// Fail if any signature does not verify, even if enough others do
+    pub invalid_fatal: bool,
+    /// Count only signers added to the keyring with `fver trust add`
+    pub trusted_only: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Verified,
+    NotEnoughSignatures,
+    InvalidSignature,
+}
+
+impl Policy {
+    /// A signer key differing from the keyring or fields not matching the
+    /// query always fail, the server may be forging signatures
+    pub fn evaluate(&self, report: &VerificationReport) -> Verdict {
+        let mismatch = |s: &SignatureReport| {
+            matches!(s.trust, Trust::Mismatch { .. }) || !s.mismatches.is_empty()
+        };
+        if report.signatures.iter().any(mismatch)
+            || self.invalid_fatal && report.signatures.iter().any(is_invalid)
+        {
+            return Verdict::InvalidSignature;
+        }
+
+        // Signers are counted by username, a user who rotated their key may
+        // have signed with several keys
+        let mut signers: HashMap<&str, HashSet<String>> = HashMap::new();
+        for s in report.signatures.iter().filter(|s| is_trusted(s)) {
+            if self.trusted_only && s.trust != Trust::Trusted {
+                continue;
+            }
+            let signer = s.signer.as_ref().unwrap();
+            signers
+                .entry(signer.username.as_str())
+                .or_default()
+                .insert(hex::encode(signer.fingerprint()));
+        }
+        let signers: Vec<_> = signers.into_iter().collect();
+        let count = if self.required.is_empty() {
+            signers.len()
+        } else {
+            self.matched(&signers)
+        };
+
+        let needed = self.min_valid.unwrap_or_else(|| self.required.len().max(1));
+        if count >= needed {
+            Verdict::Verified
+        } else {
+            Verdict::NotEnoughSignatures
+        }
+    }
+
+    // Number of required entries matched by a signer of their own, as a
+    // maximum matching so that one signer never satisfies two entries
+    fn matched(&self, signers: &[(&str, HashSet<String>)]) -> usize {
+        let mut owners = vec![None; self.required.len()];
+        let mut count = 0;
+        for signer in 0..signers.len() {
+            let mut seen = vec![false; self.required.len()];
+            if self.assign(signer, signers, &mut owners, &mut seen) {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    fn assign(
+        &self,
+        signer: usize,
+        signers: &[(&str, HashSet<String>)],
+        owners: &mut [Option<usize>],
+        seen: &mut [bool],
+    ) -> bool {
+        let (username, fingerprints) = &signers[signer];
+        for (i, r) in self.required.iter().enumerate() {
+            let matches = r == username || fingerprints.contains(&r.to_ascii_lowercase());
+            if seen[i] || !matches {
+                continue;
+            }
+            seen[i] = true;
+            let free = match owners[i] {
+                None => true,
+                Some(other) => self.assign(other, signers, owners, seen),
+            };
+            if free {
+                owners[i] = Some(signer);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+// Valid signature of a known signer with an unrevoked key, included in the
+// log and not withdrawn
+fn is_trusted(s: &SignatureReport) -> bool {
+    s.valid
+        && s.revoked.is_none()
+        && s.withdrawn.is_none()
+        && s.mismatches.is_empty()
+        && s.signer.is_some()
+        && matches!(s.inclusion, Inclusion::Included { .. })
+}
+
+// Unknown signatures and users prove nothing either way and are not counted here
+fn is_invalid(s: &SignatureReport) -> bool {
+    s.signer.is_some()
+        && (!s.valid || s.revoked.is_some() || matches!(s.inclusion, Inclusion::InvalidProof))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Signer;
+    use fver_proto::Checkpoint;
+    use openssl::sha::sha256;
+
+    fn fingerprint(key: &[u8]) -> String {
+        hex::encode(sha256(key))
+    }
+
+    // Valid, included signature by `username` made with `key`
+    fn sig(username: &str, key: &[u8]) -> SignatureReport {
+        SignatureReport {
+            hash: sha256(key),
+            signature: None,
+            signer: Some(Signer {
+                username: username.to_string(),
+                key: key.to_vec(),
+                keys: Vec::new(),
+            }),
+            valid: true,
+            inclusion: Inclusion::Included { index: 0, size: 1 },
+            trust: Trust::Pinned,
+            mismatches: Vec::new(),
+            revoked: None,
+            withdrawn: None,
+        }
+    }
+
+    fn report(signatures: Vec<SignatureReport>) -> VerificationReport {
+        VerificationReport {
+            object: [0; 32],
+            checkpoint: Checkpoint {
+                size: 1,
+                root: [0; 32],
+                head: [0; 32],
+                timestamp: 0,
+            },
+            signatures,
+        }
+    }
+
+    fn policy(required: &[&str], min_valid: Option<usize>) -> Policy {
+        Policy {
+            required: required.iter().map(|r| r.to_string()).collect(),
+            min_valid,
+            ..Policy::default()
+        }
+    }
+
+    #[test]
+    fn one_key_counts_once() {
+        let alice = fingerprint(b"alice");
+        let report = report(vec![sig("alice", b"alice")]);
+        let both = policy(&["alice", &alice], None);
+        assert_eq!(both.evaluate(&report), Verdict::NotEnoughSignatures);
+        let either = policy(&["alice", &alice.to_uppercase()], Some(1));
+        assert_eq!(either.evaluate(&report), Verdict::Verified);
+    }
+
+    #[test]
+    fn rotated_user_counts_once() {
+        let old = fingerprint(b"alice old");
+        let new = fingerprint(b"alice new");
+        let rotated = vec![sig("alice", b"alice old"), sig("alice", b"alice new")];
+        let report = report(rotated);
+        assert_eq!(
+            policy(&[], Some(2)).evaluate(&report),
+            Verdict::NotEnoughSignatures
+        );
+        assert_eq!(
+            policy(&[&old, &new], None).evaluate(&report),
+            Verdict::NotEnoughSignatures
+        );
+        assert_eq!(
+            policy(&[&old, &new], Some(1)).evaluate(&report),
+            Verdict::Verified
+        );
+    }
+
+    #[test]
+    fn min_valid_boundaries() {
+        let sigs = || vec![sig("alice", b"a"), sig("bob", b"b"), sig("carol", b"c")];
+        assert_eq!(
+            policy(&[], Some(3)).evaluate(&report(sigs())),
+            Verdict::Verified
+        );
+        assert_eq!(
+            policy(&[], Some(4)).evaluate(&report(sigs())),
+            Verdict::NotEnoughSignatures
+        );
+        // One signer if nothing is required, every required one otherwise
+        assert_eq!(
+            policy(&[], None).evaluate(&report(Vec::new())),
+            Verdict::NotEnoughSignatures
+        );
+        assert_eq!(
+            policy(&[], Some(0)).evaluate(&report(Vec::new())),
+            Verdict::Verified
+        );
+        assert_eq!(
+            policy(&["alice", "bob"], None).evaluate(&report(sigs())),
+            Verdict::Verified
+        );
+        assert_eq!(
+            policy(&["alice", "dave"], None).evaluate(&report(sigs())),
+            Verdict::NotEnoughSignatures
+        );
+        assert_eq!(
+            policy(&["alice", "dave"], Some(1)).evaluate(&report(sigs())),
+            Verdict::Verified
+        );
+    }
+
+    #[test]
+    fn revoked_and_withdrawn_are_not_counted() {
+        let mut revoked = sig("alice", b"a");
+        revoked.revoked = Some(0);
+        let mut withdrawn = sig("bob", b"b");
+        withdrawn.withdrawn = Some(String::new());
+        let report = report(vec![revoked, withdrawn, sig("carol", b"c")]);
+
+        assert_eq!(policy(&[], Some(1)).evaluate(&report), Verdict::Verified);
+        assert_eq!(
+            policy(&[], Some(2)).evaluate(&report),
+            Verdict::NotEnoughSignatures
+        );
+        assert_eq!(
+            policy(&["alice"], None).evaluate(&report),
+            Verdict::NotEnoughSignatures
+        );
+        assert_eq!(
+            policy(&["bob"], None).evaluate(&report),
+            Verdict::NotEnoughSignatures
+        );
+        // A withdrawal is no invalid signature, a revoked key is
+        let fatal = Policy {
+            invalid_fatal: true,
+            ..policy(&["carol"], None)
+        };
+        assert_eq!(fatal.evaluate(&report), Verdict::InvalidSignature);
+        let mut report = report;
+        report.signatures.retain(|s| s.revoked.is_none());
+        assert_eq!(fatal.evaluate(&report), Verdict::Verified);
+    }
+
+    #[test]
+    fn matched_reassigns_entries() {
+        let shared = fingerprint(b"shared");
+        let alice = fingerprint(b"alice");
+        let signers: Vec<(&str, HashSet<String>)> = vec![
+            (
+                "alice",
+                vec![shared.clone(), alice.clone()].into_iter().collect(),
+            ),
+            ("bob", vec![shared.clone()].into_iter().collect()),
+        ];
+        // alice first takes the shared key's entry and has to give it up to bob
+        assert_eq!(policy(&[&shared, &alice], None).matched(&signers), 2);
+        assert_eq!(policy(&[&shared, &shared], None).matched(&signers), 2);
+        assert_eq!(policy(&[&alice, &alice], None).matched(&signers), 1);
+        assert_eq!(policy(&["bob", &shared], None).matched(&signers), 2);
+    }
+}
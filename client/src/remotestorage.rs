@@ -1,20 +1,91 @@
 use crate::error::Error;
-use crate::error::Error::ServerError;
+use crate::error::Error::{
+    AlreadyWithdrawn, CorruptedMessage, IncompatibleVersion, InvalidSignature, KeyMismatch,
+    ReasonTooLong, RevokedKey, ServerError, UnknownKey, UnknownSignature, UnknownUser,
+};
+use fver_proto::{
+    capability, AddSigStatus, Connection, HelloResponse, HelloStatus, InclusionProof, KeyRotation,
+    Lookup, Payload, Request, Revocation, RevokeKeyStatus, RotateKeyStatus, SetUserStatus,
+    SignResponse, Signature, SignedCheckpoint, TreeHead, User, WithdrawSigStatus, Withdrawal,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 use openssl::sha::sha256;
-use simpletcp::simpletcp::{Message, TcpStream};
-use std::convert::TryInto;
-use std::io::{stdout, Write};
-use std::net::ToSocketAddrs;
+use simpletcp::simpletcp::Message;
+
+const CAPABILITIES: u64 = capability::TRANSPARENCY_LOG
+    | capability::SIGN_REQUEST
+    | capability::KEY_ROTATION
+    | capability::KEY_REVOCATION
+    | capability::SIG_WITHDRAWAL;
 
 pub struct RemoteStorage {
-    conn: TcpStream,
+    conn: Connection,
+    next_id: u64,
+    version: u16,
+    capabilities: u64,
 }
 
 impl RemoteStorage {
-    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
-        let mut conn = TcpStream::connect(addr)?;
-        conn.wait_until_ready()?;
-        Ok(Self { conn })
+    pub fn new(mut conn: Connection) -> Result<Self, Error> {
+        conn.wait_until_ready(5000)?;
+        let mut storage = Self {
+            conn,
+            next_id: 0,
+            version: 0,
+            capabilities: 0,
+        };
+        storage.hello()?;
+        Ok(storage)
+    }
+
+    fn hello(&mut self) -> Result<(), Error> {
+        let resp = self.request(Request::Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES,
+        });
+        // Servers predating the handshake drop the connection on unknown opcodes
+        let resp = match resp.map(|mut m| HelloResponse::decode(&mut m)) {
+            Ok(Ok(resp)) => resp,
+            Ok(Err(fver_proto::Error::Unsupported))
+            | Err(Error::NetworkError(simpletcp::simpletcp::Error::ConnectionClosed)) => {
+                return Err(IncompatibleVersion(None))
+            }
+            Ok(Err(e)) => return Err(e.into()),
+            Err(e) => return Err(e),
+        };
+        if resp.status == HelloStatus::IncompatibleVersion {
+            return Err(IncompatibleVersion(Some((
+                resp.min_version,
+                resp.max_version,
+            ))));
+        }
+        self.version = resp.version;
+        self.capabilities = resp.capabilities;
+        Ok(())
+    }
+
+    /// Protocol version agreed on with the server
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn has_capability(&self, capability: u64) -> bool {
+        self.capabilities & capability == capability
+    }
+
+    fn request(&mut self, r: Request) -> Result<Message, Error> {
+        self.conn.write_blocking(r.encode())?;
+        self.conn.read_timeout(5000)?.ok_or(ServerError)
+    }
+
+    fn lookup<T: Payload>(&mut self, r: Request) -> Result<Option<T>, Error> {
+        let mut resp = self.request(r)?;
+        match Lookup::decode(&mut resp)? {
+            Lookup::Found(v) => Ok(Some(v)),
+            Lookup::NotFound => Ok(None),
+            Lookup::Failed => Err(ServerError),
+        }
     }
 
     pub fn get_user_by_username(&mut self, username: &str) -> Result<Option<User>, Error> {
@@ -22,151 +93,144 @@ impl RemoteStorage {
         self.get_user(hash)
     }
 
-    pub fn get_prev(&mut self) -> Result<[u8; 32], Error> {
-        stdout().lock().flush().unwrap();
-        let mut m = Message::new();
-        m.write_u8(4);
-        self.conn.write_blocking(&m).unwrap();
-
-        let resp = self.conn.read_timeout(5000)?;
-        match resp {
-            None => Err(ServerError),
-            Some(mut resp) => match resp.read_i8()? {
-                -1 => Err(ServerError),
-                0 => Ok([0; 32]),
-                1 => {
-                    let hash = resp.read_buffer()?.try_into()?;
-                    Ok(hash)
-                }
-                _ => Err(ServerError),
-            },
-        }
+    pub fn get_head(&mut self) -> Result<[u8; 32], Error> {
+        let head = self.lookup(Request::GetHead)?;
+        Ok(head.unwrap_or([0; 32]))
+    }
+
+    pub fn get_challenge(&mut self) -> Result<[u8; 32], Error> {
+        self.lookup(Request::GetChallenge)?.ok_or(ServerError)
     }
 
-    pub fn set_user(&mut self, u: User) -> Result<(), Error> {
-        let mut m = Message::new();
-        m.write_u8(0);
-        m.write_buffer(&u.key);
-        m.write_buffer(&u.username);
-        self.conn.write_blocking(&m)?;
-
-        match self.conn.read_timeout(5000)? {
-            None => Err(ServerError),
-            Some(mut resp) => match resp.read_i8()? {
-                0 => Ok(()),
-                -1 => Err(ServerError),
-                _ => Err(ServerError),
-            },
+    pub fn set_user(&mut self, user: User, proof: &[u8]) -> Result<(), Error> {
+        let mut resp = self.request(Request::SetUser {
+            user,
+            proof: proof.to_vec(),
+        })?;
+        match SetUserStatus::decode(&mut resp)? {
+            SetUserStatus::Ok => Ok(()),
+            SetUserStatus::InvalidProof => Err(InvalidSignature),
+            _ => Err(ServerError),
         }
     }
 
     pub fn get_user(&mut self, hash: [u8; 32]) -> Result<Option<User>, Error> {
-        let mut m = Message::new();
-        m.write_u8(1);
-        m.write_buffer(&hash);
-        self.conn.write_blocking(&m)?;
-
-        match self.conn.read_timeout(5000)? {
-            None => Err(ServerError),
-            Some(mut resp) => match resp.read_i8()? {
-                0 => Ok(None),
-                1 => {
-                    let username = resp.read_buffer()?.to_vec();
-                    let key = resp.read_buffer()?.to_vec();
-                    Ok(Some(User { username, key }))
-                }
-                -1 => Err(ServerError),
-                _ => Err(ServerError),
-            },
+        self.lookup(Request::GetUser { hash })
+    }
+
+    /// Pushes `sig` if the chain head is still `expected_prev`. A stale head is
+    /// not an error here, the response carries the current head to retry with.
+    pub fn add_sig(
+        &mut self,
+        expected_prev: [u8; 32],
+        sig: Signature,
+    ) -> Result<SignResponse, Error> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let mut resp = self.request(Request::AddSig {
+            id,
+            expected_prev,
+            sig,
+        })?;
+        let resp = SignResponse::decode(&mut resp)?;
+        if resp.id != id {
+            return Err(CorruptedMessage);
+        }
+        match resp.status {
+            AddSigStatus::Accepted | AddSigStatus::StaleChainHead => Ok(resp),
+            AddSigStatus::UnknownUser => Err(UnknownUser),
+            AddSigStatus::InvalidSignature => Err(InvalidSignature),
+            AddSigStatus::RevokedKey => Err(RevokedKey),
+            AddSigStatus::StorageError => Err(ServerError),
         }
     }
 
-    pub fn add_sig(&mut self, s: Signature) -> Result<(), Error> {
-        let mut m = Message::new();
-        m.write_buffer(&s.obj);
-        m.write_buffer(&s.user);
-        m.write_buffer(&s.prev_sig);
-        m.write_buffer(&s.signature);
-        self.conn.write_blocking(&m)?;
-
-        match self.conn.read_timeout(5000)? {
-            None => Err(ServerError),
-            Some(mut resp) => match resp.read_i8()? {
-                0 => Ok(()),
-                _ => Err(ServerError),
-            },
+    /// Returns false if the log has grown past `rotation.since`
+    pub fn rotate_key(&mut self, rotation: KeyRotation) -> Result<bool, Error> {
+        let mut resp = self.request(Request::RotateKey { rotation })?;
+        match RotateKeyStatus::decode(&mut resp)? {
+            RotateKeyStatus::Ok => Ok(true),
+            RotateKeyStatus::StaleLog => Ok(false),
+            RotateKeyStatus::UnknownUser => Err(UnknownUser),
+            RotateKeyStatus::InvalidSignature => Err(InvalidSignature),
+            RotateKeyStatus::KeyMismatch => Err(KeyMismatch),
+            RotateKeyStatus::RevokedKey => Err(RevokedKey),
+            RotateKeyStatus::Failed => Err(ServerError),
         }
     }
 
-    pub fn get_obj(&mut self, hash: [u8; 32]) -> Result<Vec<[u8; 32]>, Error> {
-        let mut m = Message::new();
-        m.write_i8(2);
-        m.write_buffer(&hash);
-        self.conn.write_blocking(&m)?;
-
-        match self.conn.read_timeout(5000)? {
-            None => Err(ServerError),
-            Some(mut resp) => match resp.read_i8()? {
-                0 => Ok(Vec::new()),
-                1 => {
-                    let mut r = Vec::new();
-                    loop {
-                        let h = resp.read_buffer();
-                        match h {
-                            Ok(h) => {
-                                r.push(h.try_into()?);
-                            }
-                            Err(_) => {
-                                break;
-                            }
-                        }
-                    }
-                    Ok(r)
-                }
-                _ => Err(ServerError),
-            },
+    pub fn get_key_history(&mut self, hash: [u8; 32]) -> Result<Option<Vec<KeyRotation>>, Error> {
+        self.lookup(Request::GetKeyHistory { hash })
+    }
+
+    pub fn revoke_key(&mut self, revocation: Revocation) -> Result<(), Error> {
+        let mut resp = self.request(Request::RevokeKey { revocation })?;
+        match RevokeKeyStatus::decode(&mut resp)? {
+            RevokeKeyStatus::Ok => Ok(()),
+            RevokeKeyStatus::UnknownUser => Err(UnknownUser),
+            RevokeKeyStatus::UnknownKey => Err(UnknownKey),
+            RevokeKeyStatus::InvalidSignature => Err(InvalidSignature),
+            RevokeKeyStatus::Failed => Err(ServerError),
         }
     }
 
-    pub fn get_sig(&mut self, hash: [u8; 32]) -> Result<Option<Signature>, Error> {
-        let mut m = Message::new();
-        m.write_i8(3);
-        m.write_buffer(&hash);
-
-        self.conn.write_blocking(&m)?;
-
-        let resp = self.conn.read_timeout(5000)?;
-        match resp {
-            None => Err(ServerError),
-            Some(mut resp) => match resp.read_i8()? {
-                0 => Ok(None),
-                1 => {
-                    let obj = resp.read_buffer()?.try_into()?;
-                    let user = resp.read_buffer()?.try_into()?;
-                    let prev_sig = resp.read_buffer()?.try_into()?;
-                    let signature = resp.read_buffer()?.try_into()?;
-                    let s = Signature {
-                        obj,
-                        user,
-                        prev_sig,
-                        signature,
-                    };
-                    Ok(Some(s))
-                }
-                _ => Err(ServerError),
-            },
+    pub fn get_revocations(&mut self, hash: [u8; 32]) -> Result<Option<Vec<Revocation>>, Error> {
+        self.lookup(Request::GetRevocations { hash })
+    }
+
+    pub fn withdraw_sig(&mut self, withdrawal: Withdrawal) -> Result<(), Error> {
+        let mut resp = self.request(Request::WithdrawSig { withdrawal })?;
+        match WithdrawSigStatus::decode(&mut resp)? {
+            WithdrawSigStatus::Ok => Ok(()),
+            WithdrawSigStatus::UnknownSignature => Err(UnknownSignature),
+            WithdrawSigStatus::InvalidSignature => Err(InvalidSignature),
+            WithdrawSigStatus::RevokedKey => Err(RevokedKey),
+            WithdrawSigStatus::AlreadyWithdrawn => Err(AlreadyWithdrawn),
+            WithdrawSigStatus::ReasonTooLong => Err(ReasonTooLong),
+            WithdrawSigStatus::Failed => Err(ServerError),
         }
     }
-}
 
-pub struct User {
-    pub(crate) username: Vec<u8>,
-    pub(crate) key: Vec<u8>,
-}
+    pub fn get_withdrawals(&mut self, hash: [u8; 32]) -> Result<Vec<Withdrawal>, Error> {
+        let withdrawals = self.lookup(Request::GetWithdrawals { hash })?;
+        Ok(withdrawals.unwrap_or_default())
+    }
+
+    pub fn get_obj(&mut self, hash: [u8; 32]) -> Result<Vec<[u8; 32]>, Error> {
+        let sigs = self.lookup(Request::GetObj { hash })?;
+        Ok(sigs.unwrap_or_default())
+    }
 
-pub struct Signature {
-    pub(crate) obj: [u8; 32],
-    pub(crate) user: [u8; 32],
-    pub(crate) prev_sig: [u8; 32],
-    pub(crate) signature: Vec<u8>,
+    pub fn get_sig(&mut self, hash: [u8; 32]) -> Result<Option<Signature>, Error> {
+        self.lookup(Request::GetSig { hash })
+    }
+
+    pub fn get_inclusion_proof(
+        &mut self,
+        hash: [u8; 32],
+        size: u64,
+    ) -> Result<Option<InclusionProof>, Error> {
+        self.lookup(Request::GetInclusionProof {
+            hash,
+            size: Some(size),
+        })
+    }
+
+    pub fn get_consistency_proof(
+        &mut self,
+        first: u64,
+        second: u64,
+    ) -> Result<Vec<[u8; 32]>, Error> {
+        self.lookup(Request::GetConsistencyProof { first, second })?
+            .ok_or(ServerError)
+    }
+
+    pub fn get_tree_head(&mut self) -> Result<TreeHead, Error> {
+        self.lookup(Request::GetTreeHead { size: None })?
+            .ok_or(ServerError)
+    }
+
+    pub fn get_checkpoint(&mut self) -> Result<SignedCheckpoint, Error> {
+        self.lookup(Request::GetCheckpoint)?.ok_or(ServerError)
+    }
 }
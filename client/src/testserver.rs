@@ -0,0 +1,403 @@
+// In-process server for the tests, answering read requests over TLS from a
+// state the test sets up directly. Nothing it returns is checked, so tests
+// can hand the client anything a malicious server could.
+
+use std::env::temp_dir;
+use std::fs::{create_dir_all, remove_dir_all, write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+
+use fver_proto::{
+    capability, leaf_hash, node_hash, tls_acceptor, unsupported, Checkpoint, Connection,
+    HelloResponse, InclusionProof, KeyRotation, Lookup, Request, Revocation, Signature,
+    SignedCheckpoint, TreeHead, User, Withdrawal,
+};
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sha::{sha256, Sha256};
+use openssl::ssl::SslAcceptor;
+use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+use openssl::x509::{X509Builder, X509NameBuilder, X509};
+use simpletcp::simpletcp::Message;
+
+use crate::identity::Identity;
+use crate::remotestorage::RemoteStorage;
+
+const CAPABILITIES: u64 = capability::TRANSPARENCY_LOG
+    | capability::SIGN_REQUEST
+    | capability::KEY_ROTATION
+    | capability::KEY_REVOCATION
+    | capability::SIG_WITHDRAWAL;
+
+/// Empty directory unique to the test process and `name`
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = temp_dir().join(format!("fver-{}-{}", name, std::process::id()));
+    let _ = remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+pub struct State {
+    /// Checkpoint signing key
+    pub key: PKey<Private>,
+    pub timestamp: u64,
+    pub users: Vec<User>,
+    /// Signatures in log order
+    pub log: Vec<Signature>,
+    pub rotations: Vec<KeyRotation>,
+    pub revocations: Vec<Revocation>,
+    pub withdrawals: Vec<Withdrawal>,
+    /// Sent instead of the checkpoint of the log
+    pub checkpoint: Option<SignedCheckpoint>,
+    /// Sent instead of any consistency proof
+    pub consistency_proof: Option<Vec<[u8; 32]>>,
+}
+
+impl State {
+    pub fn register(&mut self, identity: &Identity) {
+        self.users.push(User {
+            username: identity.username.as_bytes().to_vec(),
+            key: identity.public_key().unwrap(),
+        });
+    }
+
+    /// Appends a signature of `obj` to the log and returns its hash
+    pub fn sign(&mut self, identity: &Identity, obj: [u8; 32]) -> [u8; 32] {
+        let prev_sig = self.log.last().map_or([0; 32], Signature::hash);
+        let sig = Signature {
+            obj,
+            user: identity.user_hash(),
+            prev_sig,
+            signature: identity.sign(&obj, &prev_sig).unwrap(),
+        };
+        let hash = sig.hash();
+        self.log.push(sig);
+        hash
+    }
+
+    pub fn withdraw(&mut self, identity: &Identity, hash: [u8; 32], reason: &[u8]) {
+        let sig = self.log.iter().find(|s| s.hash() == hash).unwrap();
+        let mut withdrawal = Withdrawal {
+            sig: hash,
+            obj: sig.obj,
+            user: sig.user,
+            reason: reason.to_vec(),
+            signature: Vec::new(),
+        };
+        withdrawal.signature = identity.sign_statement(&withdrawal.signed_data()).unwrap();
+        self.withdrawals.push(withdrawal);
+    }
+
+    /// Rotates the key of `identity` to the one of `next` at the current log size
+    pub fn rotate(&mut self, identity: &Identity, next: &Identity) {
+        let mut rotation = KeyRotation {
+            username: identity.username.as_bytes().to_vec(),
+            old_key: identity.public_key().unwrap(),
+            new_key: next.public_key().unwrap(),
+            since: self.log.len() as u64,
+            old_signature: Vec::new(),
+            new_signature: Vec::new(),
+        };
+        rotation.old_signature = identity.sign_statement(&rotation.signed_data()).unwrap();
+        rotation.new_signature = next.sign_statement(&rotation.signed_data()).unwrap();
+        for u in &mut self.users {
+            if u.username == rotation.username {
+                u.key = rotation.new_key.clone();
+            }
+        }
+        self.rotations.push(rotation);
+    }
+
+    /// Revokes `key` of the user of `identity`, signed by `identity`
+    pub fn revoke(&mut self, identity: &Identity, key: &[u8], since: u64) {
+        let mut revocation = Revocation {
+            username: identity.username.as_bytes().to_vec(),
+            key: key.to_vec(),
+            since,
+            signature: Vec::new(),
+        };
+        revocation.signature = identity.sign_statement(&revocation.signed_data()).unwrap();
+        self.revocations.push(revocation);
+    }
+
+    /// Checkpoint of the log as the server would sign it
+    pub fn signed_checkpoint(&self) -> SignedCheckpoint {
+        let leaves = self.leaves();
+        let checkpoint = Checkpoint {
+            size: leaves.len() as u64,
+            root: root(&leaves),
+            head: self.log.last().map_or([0; 32], Signature::hash),
+            timestamp: self.timestamp,
+        };
+        SignedCheckpoint {
+            key: self.key.public_key_to_der().unwrap(),
+            signature: checkpoint.sign(&self.key).unwrap(),
+            checkpoint,
+        }
+    }
+
+    fn leaves(&self) -> Vec<[u8; 32]> {
+        self.log.iter().map(|s| leaf_hash(&s.hash())).collect()
+    }
+
+    fn respond(&self, request: Request) -> Message {
+        let hashes: Vec<[u8; 32]> = self.log.iter().map(Signature::hash).collect();
+        let leaves = self.leaves();
+        let user = |hash: [u8; 32]| self.users.iter().find(|u| sha256(&u.username) == hash);
+        match request {
+            Request::Hello {
+                min_version,
+                max_version,
+                ..
+            } => HelloResponse::negotiate(min_version, max_version, CAPABILITIES).encode(),
+            Request::GetCheckpoint => {
+                let checkpoint = self.checkpoint.clone();
+                Lookup::Found(checkpoint.unwrap_or_else(|| self.signed_checkpoint())).encode()
+            }
+            Request::GetConsistencyProof { first, second } => {
+                let proof = match &self.consistency_proof {
+                    Some(proof) => proof.clone(),
+                    None if first > 0 && first < second => {
+                        consistency_proof(first as usize, &leaves[..second as usize], true)
+                    }
+                    None => Vec::new(),
+                };
+                Lookup::Found(proof).encode()
+            }
+            Request::GetInclusionProof { hash, size } => {
+                let size = size.map_or(leaves.len(), |size| size as usize);
+                match hashes[..size].iter().position(|h| *h == hash) {
+                    None => Lookup::<InclusionProof>::NotFound.encode(),
+                    Some(index) => Lookup::Found(InclusionProof {
+                        index: index as u64,
+                        size: size as u64,
+                        root: root(&leaves[..size]),
+                        path: inclusion_proof(index, &leaves[..size]),
+                    })
+                    .encode(),
+                }
+            }
+            Request::GetTreeHead { size } => {
+                let size = size.map_or(leaves.len(), |size| size as usize);
+                Lookup::Found(TreeHead {
+                    size: size as u64,
+                    root: root(&leaves[..size]),
+                })
+                .encode()
+            }
+            Request::GetHead => found(hashes.last().copied()).encode(),
+            Request::GetUser { hash } => found(user(hash).cloned()).encode(),
+            Request::GetSig { hash } => {
+                found(self.log.iter().find(|s| s.hash() == hash).cloned()).encode()
+            }
+            Request::GetObj { hash } => {
+                let sigs: Vec<[u8; 32]> = self
+                    .log
+                    .iter()
+                    .filter(|s| s.obj == hash)
+                    .map(Signature::hash)
+                    .collect();
+                found(Some(sigs).filter(|sigs| !sigs.is_empty())).encode()
+            }
+            Request::GetKeyHistory { hash } => {
+                let rotations = self
+                    .rotations
+                    .iter()
+                    .filter(|r| sha256(&r.username) == hash);
+                found(user(hash).map(|_| rotations.cloned().collect::<Vec<_>>())).encode()
+            }
+            Request::GetRevocations { hash } => {
+                let revocations = self
+                    .revocations
+                    .iter()
+                    .filter(|r| sha256(&r.username) == hash);
+                found(user(hash).map(|_| revocations.cloned().collect::<Vec<_>>())).encode()
+            }
+            Request::GetWithdrawals { hash } => {
+                let withdrawals = self.withdrawals.iter().filter(|w| w.obj == hash);
+                let known = self.log.iter().any(|s| s.obj == hash);
+                found(Some(withdrawals.cloned().collect::<Vec<_>>()).filter(|_| known)).encode()
+            }
+            _ => unsupported(),
+        }
+    }
+}
+
+fn found<T>(v: Option<T>) -> Lookup<T> {
+    match v {
+        Some(v) => Lookup::Found(v),
+        None => Lookup::NotFound,
+    }
+}
+
+pub struct TestServer {
+    pub state: Arc<Mutex<State>>,
+    addr: SocketAddr,
+    ca: X509,
+}
+
+impl TestServer {
+    pub fn start(name: &str) -> Self {
+        let key = key();
+        let ca = cert(&key);
+        let dir = test_dir(&format!("{}-tls", name));
+        write(dir.join("cert.pem"), ca.to_pem().unwrap()).unwrap();
+        write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let acceptor = tls_acceptor(dir.join("cert.pem"), dir.join("key.pem")).unwrap();
+
+        let state = Arc::new(Mutex::new(State {
+            key: self::key(),
+            timestamp: 1,
+            users: Vec::new(),
+            log: Vec::new(),
+            rotations: Vec::new(),
+            revocations: Vec::new(),
+            withdrawals: Vec::new(),
+            checkpoint: None,
+            consistency_proof: None,
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shared = state.clone();
+        spawn(move || accept_loop(listener, acceptor, shared));
+        Self { state, addr, ca }
+    }
+
+    pub fn connect(&self) -> RemoteStorage {
+        let conn = Connection::connect_tls(self.addr, "localhost", Some(&self.ca)).unwrap();
+        RemoteStorage::new(conn).unwrap()
+    }
+}
+
+fn accept_loop(listener: TcpListener, acceptor: SslAcceptor, state: Arc<Mutex<State>>) {
+    for socket in listener.incoming() {
+        let conn = match socket.map(|s| Connection::accept_tls(&acceptor, s)) {
+            Ok(Ok(conn)) => conn,
+            _ => continue,
+        };
+        let state = state.clone();
+        spawn(move || serve(conn, &state));
+    }
+}
+
+// Until the client disconnects
+fn serve(mut conn: Connection, state: &Mutex<State>) {
+    if conn.wait_until_ready(5000).is_err() {
+        return;
+    }
+    loop {
+        let mut m = match conn.read_timeout(1000) {
+            Ok(Some(m)) => m,
+            Ok(None) => continue,
+            Err(_) => return,
+        };
+        let resp = match Request::decode(&mut m) {
+            Ok(request) => state.lock().unwrap().respond(request),
+            Err(_) => return,
+        };
+        if conn.write(resp).is_err() {
+            return;
+        }
+    }
+}
+
+// Self-signed certificate for localhost, trusted by the client as its own CA
+fn cert(key: &PKey<Private>) -> X509 {
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+
+    let mut b = X509Builder::new().unwrap();
+    b.set_version(2).unwrap();
+    let serial = BigNum::from_u32(1).unwrap();
+    b.set_serial_number(&serial.to_asn1_integer().unwrap())
+        .unwrap();
+    b.set_subject_name(&name).unwrap();
+    b.set_issuer_name(&name).unwrap();
+    b.set_pubkey(key).unwrap();
+    b.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    b.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    b.append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+        .unwrap();
+    let san = SubjectAlternativeName::new()
+        .dns("localhost")
+        .build(&b.x509v3_context(None, None))
+        .unwrap();
+    b.append_extension(san).unwrap();
+    b.sign(key, MessageDigest::sha256()).unwrap();
+    b.build()
+}
+
+// Merkle tree of RFC 6962, computed from the leaf hashes on every request
+
+fn split(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+fn root(leaves: &[[u8; 32]]) -> [u8; 32] {
+    match leaves.len() {
+        0 => Sha256::new().finish(),
+        1 => leaves[0],
+        n => {
+            let k = split(n);
+            node_hash(&root(&leaves[..k]), &root(&leaves[k..]))
+        }
+    }
+}
+
+fn inclusion_proof(index: usize, leaves: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    let n = leaves.len();
+    if n <= 1 {
+        return Vec::new();
+    }
+    let k = split(n);
+    let (mut proof, sibling) = if index < k {
+        (inclusion_proof(index, &leaves[..k]), root(&leaves[k..]))
+    } else {
+        (inclusion_proof(index - k, &leaves[k..]), root(&leaves[..k]))
+    };
+    proof.push(sibling);
+    proof
+}
+
+fn consistency_proof(m: usize, leaves: &[[u8; 32]], complete: bool) -> Vec<[u8; 32]> {
+    let n = leaves.len();
+    if m == n {
+        return if complete {
+            Vec::new()
+        } else {
+            vec![root(leaves)]
+        };
+    }
+    let k = split(n);
+    let (mut proof, sibling) = if m <= k {
+        (
+            consistency_proof(m, &leaves[..k], complete),
+            root(&leaves[k..]),
+        )
+    } else {
+        (
+            consistency_proof(m - k, &leaves[k..], false),
+            root(&leaves[..k]),
+        )
+    };
+    proof.push(sibling);
+    proof
+}
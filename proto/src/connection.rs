@@ -0,0 +1,326 @@
+use std::convert::TryInto;
+use std::net;
+use std::net::ToSocketAddrs;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
+use std::path::Path;
+use std::time::Instant;
+
+use openssl::error::ErrorStack;
+use openssl::ssl;
+use openssl::ssl::{ErrorCode, Ssl, SslAcceptor, SslConnector, SslFiletype, SslMethod, SslStream};
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::X509;
+use simpletcp::simpletcp::{Error, Message, TcpStream};
+use simpletcp::utils::{poll, poll_timeout, EV_POLLIN, EV_POLLOUT};
+
+use crate::connection::Inner::{Plain, Tls};
+
+// Same limit as simpletcp
+const SIZE_LIMIT: usize = 4 * 1024 * 1024;
+
+/// Either end of a connection, encrypted by simpletcp itself or by TLS.
+/// Over TLS every message is sent as a u32 length followed by its bytes.
+pub struct Connection {
+    inner: Inner,
+}
+
+enum Inner {
+    Plain(Box<TcpStream>),
+    Tls(TlsStream),
+}
+
+struct TlsStream {
+    stream: SslStream<net::TcpStream>,
+    server: bool,
+    ready: bool,
+    // Event the unfinished handshake waits for
+    want: i16,
+    read_buffer: Vec<u8>,
+}
+
+/// Server side TLS context with the certificate chain and key from PEM files
+pub fn tls_acceptor<P: AsRef<Path>>(cert: P, key: P) -> Result<SslAcceptor, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    builder.set_private_key_file(key, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(cert)?;
+    builder.check_private_key()?;
+    Ok(builder.build())
+}
+
+impl Connection {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
+        Ok(TcpStream::connect(addr)?.into())
+    }
+
+    /// The server certificate has to be issued for `domain` by `ca`,
+    /// or by the system trust store if `ca` is `None`
+    pub fn connect_tls<A: ToSocketAddrs>(
+        addr: A,
+        domain: &str,
+        ca: Option<&X509>,
+    ) -> Result<Self, Error> {
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        if let Some(ca) = ca {
+            let mut store = X509StoreBuilder::new()?;
+            store.add_cert(ca.clone())?;
+            builder.set_cert_store(store.build());
+        }
+        let ssl = builder.build().configure()?.into_ssl(domain)?;
+        let s = TlsStream::new(ssl, net::TcpStream::connect(addr)?, false)?;
+        Ok(Self { inner: Tls(s) })
+    }
+
+    /// Starts the TLS handshake with an accepted client, it is completed by `get_ready`
+    pub fn accept_tls(acceptor: &SslAcceptor, socket: net::TcpStream) -> Result<Self, Error> {
+        let s = TlsStream::new(Ssl::new(acceptor.context())?, socket, true)?;
+        Ok(Self { inner: Tls(s) })
+    }
+
+    /// Returns `Err(NotReady)` until `get_ready` succeeds
+    pub fn read(&mut self) -> Result<Option<Message>, Error> {
+        match &mut self.inner {
+            Plain(s) => s.read(),
+            Tls(s) => s.read(),
+        }
+    }
+
+    pub fn read_timeout(&mut self, timeout: i32) -> Result<Option<Message>, Error> {
+        match &mut self.inner {
+            Plain(s) => s.read_timeout(timeout),
+            Tls(s) => {
+                let time = Instant::now();
+                loop {
+                    if let Some(m) = s.read()? {
+                        return Ok(Some(m));
+                    }
+                    let left = timeout - time.elapsed().as_millis() as i32;
+                    if left < 0 || !poll_timeout(s.stream.get_ref(), EV_POLLIN, left) {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn write(&mut self, m: Message) -> Result<(), Error> {
+        match &mut self.inner {
+            Plain(s) => s.write(&m),
+            Tls(s) => s.write(m),
+        }
+    }
+
+    pub fn write_blocking(&mut self, m: Message) -> Result<(), Error> {
+        match &mut self.inner {
+            Plain(s) => s.write_blocking(&m),
+            Tls(s) => s.write(m),
+        }
+    }
+
+    /// Advances the key exchange or TLS handshake, true once it is done
+    pub fn get_ready(&mut self) -> Result<bool, Error> {
+        match &mut self.inner {
+            Plain(s) => s.get_ready(),
+            Tls(s) => Ok(s.handshake()?.is_none()),
+        }
+    }
+
+    /// Event to poll for before calling `read` or `get_ready` again, a TLS
+    /// handshake may have to wait until it can write
+    pub fn event(&self) -> i16 {
+        match &self.inner {
+            Tls(s) if !s.ready => s.want,
+            _ => EV_POLLIN,
+        }
+    }
+
+    /// Whether received data is buffered already, `read` has to be called
+    /// again before polling as poll would not wake up for it
+    pub fn pending(&self) -> bool {
+        match &self.inner {
+            Plain(_) => false,
+            Tls(s) => s.ready && s.stream.ssl().pending() > 0,
+        }
+    }
+
+    /// Fails with `NotReady` if the connection is not ready within `timeout` ms
+    pub fn wait_until_ready(&mut self, timeout: i32) -> Result<(), Error> {
+        let time = Instant::now();
+        loop {
+            let event = match &mut self.inner {
+                Plain(s) => match s.get_ready()? {
+                    true => None,
+                    false => Some(EV_POLLIN),
+                },
+                Tls(s) => s.handshake()?,
+            };
+            let event = match event {
+                None => return Ok(()),
+                Some(event) => event,
+            };
+            let left = timeout - time.elapsed().as_millis() as i32;
+            if left < 0 || !poll_timeout(self, event, left) {
+                return Err(Error::NotReady);
+            }
+        }
+    }
+}
+
+impl From<TcpStream> for Connection {
+    fn from(s: TcpStream) -> Self {
+        Self {
+            inner: Plain(Box::new(s)),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.inner {
+            Plain(s) => s.as_raw_fd(),
+            Tls(s) => s.stream.get_ref().as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for Connection {
+    fn as_raw_socket(&self) -> RawSocket {
+        match &self.inner {
+            Plain(s) => s.as_raw_socket(),
+            Tls(s) => s.stream.get_ref().as_raw_socket(),
+        }
+    }
+}
+
+impl TlsStream {
+    fn new(ssl: Ssl, socket: net::TcpStream, server: bool) -> Result<Self, Error> {
+        socket.set_nonblocking(true)?;
+        let mut s = Self {
+            stream: SslStream::new(ssl, socket)?,
+            server,
+            ready: false,
+            want: EV_POLLIN,
+            read_buffer: Vec::new(),
+        };
+        s.handshake()?;
+        Ok(s)
+    }
+
+    // None once the handshake is done, otherwise the event to wait for
+    fn handshake(&mut self) -> Result<Option<i16>, Error> {
+        if self.ready {
+            return Ok(None);
+        }
+        let result = if self.server {
+            self.stream.accept()
+        } else {
+            self.stream.connect()
+        };
+        match result {
+            Ok(_) => {
+                self.ready = true;
+                Ok(None)
+            }
+            Err(e) => match wait_event(&e) {
+                Some(event) => {
+                    self.want = event;
+                    Ok(Some(event))
+                }
+                None => Err(ssl_error(e)),
+            },
+        }
+    }
+
+    fn read(&mut self) -> Result<Option<Message>, Error> {
+        if !self.ready {
+            return Err(Error::NotReady);
+        }
+        loop {
+            let mut want = 4;
+            if self.read_buffer.len() >= 4 {
+                let len = u32::from_le_bytes(self.read_buffer[..4].try_into().unwrap()) as usize;
+                if len > SIZE_LIMIT {
+                    self.read_buffer.clear();
+                    return Err(Error::SizeLimitExceeded);
+                }
+                want += len;
+                if self.read_buffer.len() == want {
+                    let mut m = Message::new();
+                    for b in self.read_buffer.drain(..).skip(4) {
+                        m.write_u8(b);
+                    }
+                    return Ok(Some(m));
+                }
+            }
+
+            // Only the current frame is read. OpenSSL decrypts whole records
+            // though, frames sent along with it stay buffered there and
+            // `pending` tells that poll won't wake up for them.
+            let start = self.read_buffer.len();
+            self.read_buffer.resize(want, 0);
+            match self.stream.ssl_read(&mut self.read_buffer[start..]) {
+                Ok(0) => return Err(Error::ConnectionClosed),
+                Ok(n) => self.read_buffer.truncate(start + n),
+                Err(e) => {
+                    self.read_buffer.truncate(start);
+                    return match wait_event(&e) {
+                        Some(_) => Ok(None),
+                        None => Err(ssl_error(e)),
+                    };
+                }
+            }
+        }
+    }
+
+    // Message has no public access to its bytes, it is read out byte by byte
+    fn write(&mut self, mut m: Message) -> Result<(), Error> {
+        if !self.ready {
+            return Err(Error::NotReady);
+        }
+        let mut body = Vec::new();
+        while let Ok(b) = m.read_u8() {
+            body.push(b);
+        }
+        let mut frame = (body.len() as u32).to_le_bytes().to_vec();
+        frame.append(&mut body);
+
+        let mut written = 0;
+        while written < frame.len() {
+            match self.stream.ssl_write(&frame[written..]) {
+                Ok(n) => written += n,
+                Err(e) => match wait_event(&e) {
+                    Some(event) => {
+                        poll(self.stream.get_ref(), event);
+                    }
+                    None => return Err(ssl_error(e)),
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+fn wait_event(e: &ssl::Error) -> Option<i16> {
+    match e.code() {
+        ErrorCode::WANT_READ => Some(EV_POLLIN),
+        ErrorCode::WANT_WRITE => Some(EV_POLLOUT),
+        _ => None,
+    }
+}
+
+fn ssl_error(e: ssl::Error) -> Error {
+    if e.code() == ErrorCode::ZERO_RETURN {
+        return Error::ConnectionClosed;
+    }
+    match e.into_io_error() {
+        Ok(e) => Error::TcpError(e),
+        Err(e) => match e.ssl_error() {
+            Some(stack) => Error::EncryptionError(stack.clone()),
+            None => Error::ConnectionClosed,
+        },
+    }
+}
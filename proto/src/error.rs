@@ -0,0 +1,38 @@
+use std::array::TryFromSliceError;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+
+use simpletcp::simpletcp::MessageError;
+
+use crate::error::Error::{CorruptedMessage, UnknownOpcode, UnknownStatus, Unsupported};
+
+pub enum Error {
+    CorruptedMessage,
+    UnknownOpcode(u8),
+    UnknownStatus(i8),
+    /// The peer answered that it does not know the request
+    Unsupported,
+}
+
+impl From<MessageError> for Error {
+    fn from(_: MessageError) -> Self {
+        CorruptedMessage
+    }
+}
+
+impl From<TryFromSliceError> for Error {
+    fn from(_: TryFromSliceError) -> Self {
+        CorruptedMessage
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CorruptedMessage => f.write_str("CorruptedMessage"),
+            UnknownOpcode(op) => f.write_fmt(format_args!("UnknownOpcode: {}", op)),
+            UnknownStatus(s) => f.write_fmt(format_args!("UnknownStatus: {}", s)),
+            Unsupported => f.write_str("Unsupported"),
+        }
+    }
+}
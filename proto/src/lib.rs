@@ -0,0 +1,33 @@
+//! Types and wire format shared by the fver client and server.
+//!
+//! Every request starts with an [`Opcode`] byte, every response with an i8
+//! status. Lookups answer with [`Lookup`], the write operations with
+//! [`SetUserStatus`], [`SignResponse`], [`RotateKeyStatus`], [`RevokeKeyStatus`] and
+//! [`WithdrawSigStatus`].
+//!
+//! A client opens the connection with [`Request::Hello`] to agree on a
+//! protocol version. Requests a server does not know are answered with the
+//! [`UNSUPPORTED`] status.
+//!
+//! Messages travel over a [`Connection`], encrypted either by simpletcp or by TLS.
+//! The transparency log is a Merkle tree hashed with [`leaf_hash`] and [`node_hash`].
+//! Clients check its proofs with [`verify_inclusion`] and [`verify_consistency`].
+
+pub use crate::connection::{tls_acceptor, Connection};
+pub use crate::error::Error;
+pub use crate::merkle::{leaf_hash, node_hash, verify_consistency, verify_inclusion};
+pub use crate::message::{
+    capability, unsupported, AddSigStatus, HelloResponse, HelloStatus, InclusionProof, Lookup,
+    Opcode, Payload, Request, RevokeKeyStatus, RotateKeyStatus, SetUserStatus, SignResponse,
+    TreeHead, WithdrawSigStatus, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, UNSUPPORTED,
+};
+pub use crate::types::{
+    Checkpoint, KeyRotation, Revocation, Signature, SignedCheckpoint, User, Withdrawal,
+    MAX_FIELD_LEN,
+};
+
+mod connection;
+mod error;
+mod merkle;
+mod message;
+mod types;
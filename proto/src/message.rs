@@ -0,0 +1,786 @@
+use std::convert::TryInto;
+
+use simpletcp::simpletcp::Message;
+
+use crate::error::Error;
+use crate::error::Error::{UnknownOpcode, UnknownStatus, Unsupported};
+use crate::types::{
+    Checkpoint, KeyRotation, Revocation, Signature, SignedCheckpoint, User, Withdrawal,
+};
+
+/// Newest protocol version spoken by this crate
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Oldest protocol version this crate still speaks
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Status a server answers requests it does not know with, instead of
+/// dropping the connection
+pub const UNSUPPORTED: i8 = i8::MIN;
+
+/// Capability flags exchanged in `Request::Hello` and `HelloResponse`
+pub mod capability {
+    /// Checkpoints and inclusion/consistency proofs
+    pub const TRANSPARENCY_LOG: u64 = 1;
+    /// `Request::GetHead` and `Request::AddSig`
+    pub const SIGN_REQUEST: u64 = 1 << 1;
+    /// `Request::RequestEnqueue`
+    pub const LEGACY_ENQUEUE: u64 = 1 << 2;
+    /// `Request::RotateKey` and `Request::GetKeyHistory`
+    pub const KEY_ROTATION: u64 = 1 << 3;
+    /// `Request::RevokeKey` and `Request::GetRevocations`
+    pub const KEY_REVOCATION: u64 = 1 << 4;
+    /// `Request::WithdrawSig` and `Request::GetWithdrawals`
+    pub const SIG_WITHDRAWAL: u64 = 1 << 5;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Opcode {
+    SetUser = 0,
+    GetUser = 1,
+    GetObj = 2,
+    GetSig = 3,
+    RequestEnqueue = 4,
+    GetChallenge = 5,
+    GetInclusionProof = 6,
+    GetConsistencyProof = 7,
+    GetTreeHead = 8,
+    GetCheckpoint = 9,
+    GetHead = 10,
+    AddSig = 11,
+    Hello = 12,
+    RotateKey = 13,
+    GetKeyHistory = 14,
+    RevokeKey = 15,
+    GetRevocations = 16,
+    WithdrawSig = 17,
+    GetWithdrawals = 18,
+}
+
+impl Opcode {
+    pub fn from_u8(op: u8) -> Result<Self, Error> {
+        Ok(match op {
+            0 => Opcode::SetUser,
+            1 => Opcode::GetUser,
+            2 => Opcode::GetObj,
+            3 => Opcode::GetSig,
+            4 => Opcode::RequestEnqueue,
+            5 => Opcode::GetChallenge,
+            6 => Opcode::GetInclusionProof,
+            7 => Opcode::GetConsistencyProof,
+            8 => Opcode::GetTreeHead,
+            9 => Opcode::GetCheckpoint,
+            10 => Opcode::GetHead,
+            11 => Opcode::AddSig,
+            12 => Opcode::Hello,
+            13 => Opcode::RotateKey,
+            14 => Opcode::GetKeyHistory,
+            15 => Opcode::RevokeKey,
+            16 => Opcode::GetRevocations,
+            17 => Opcode::WithdrawSig,
+            18 => Opcode::GetWithdrawals,
+            _ => return Err(UnknownOpcode(op)),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    SetUser {
+        user: User,
+        proof: Vec<u8>,
+    },
+    GetUser {
+        hash: [u8; 32],
+    },
+    GetObj {
+        hash: [u8; 32],
+    },
+    GetSig {
+        hash: [u8; 32],
+    },
+    /// Legacy signing: moves the connection to the queue, the server answers with
+    /// the chain head as `Lookup<[u8; 32]>` and then expects a bare `Signature` message.
+    /// Superseded by `AddSig`.
+    RequestEnqueue,
+    GetChallenge,
+    GetInclusionProof {
+        hash: [u8; 32],
+        size: Option<u64>,
+    },
+    GetConsistencyProof {
+        first: u64,
+        second: u64,
+    },
+    GetTreeHead {
+        size: Option<u64>,
+    },
+    GetCheckpoint,
+    /// Answered with `Lookup<[u8; 32]>`, `NotFound` if the chain is empty
+    GetHead,
+    /// Appends `sig` if the chain head is still `expected_prev`, answered with
+    /// a `SignResponse` carrying the same `id`
+    AddSig {
+        id: u64,
+        expected_prev: [u8; 32],
+        sig: Signature,
+    },
+    /// Sent first by the client with the range of protocol versions it speaks,
+    /// answered with a `HelloResponse`
+    Hello {
+        min_version: u16,
+        max_version: u16,
+        capabilities: u64,
+    },
+    /// Replaces the user's current key, answered with `RotateKeyStatus`.
+    /// Rejected as stale if the log has grown past `rotation.since`.
+    RotateKey {
+        rotation: KeyRotation,
+    },
+    /// Answered with `Lookup<Vec<KeyRotation>>`, oldest first and empty if
+    /// the user never rotated their key
+    GetKeyHistory {
+        hash: [u8; 32],
+    },
+    /// Stores a revocation of one of the user's keys, answered with `RevokeKeyStatus`
+    RevokeKey {
+        revocation: Revocation,
+    },
+    /// Answered with `Lookup<Vec<Revocation>>`, empty if none of the user's keys is revoked
+    GetRevocations {
+        hash: [u8; 32],
+    },
+    /// Stores a withdrawal of one of the user's signatures, answered with `WithdrawSigStatus`
+    WithdrawSig {
+        withdrawal: Withdrawal,
+    },
+    /// Answered with `Lookup<Vec<Withdrawal>>` of the signatures of the object,
+    /// empty if none was withdrawn
+    GetWithdrawals {
+        hash: [u8; 32],
+    },
+}
+
+impl Request {
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Request::SetUser { .. } => Opcode::SetUser,
+            Request::GetUser { .. } => Opcode::GetUser,
+            Request::GetObj { .. } => Opcode::GetObj,
+            Request::GetSig { .. } => Opcode::GetSig,
+            Request::RequestEnqueue => Opcode::RequestEnqueue,
+            Request::GetChallenge => Opcode::GetChallenge,
+            Request::GetInclusionProof { .. } => Opcode::GetInclusionProof,
+            Request::GetConsistencyProof { .. } => Opcode::GetConsistencyProof,
+            Request::GetTreeHead { .. } => Opcode::GetTreeHead,
+            Request::GetCheckpoint => Opcode::GetCheckpoint,
+            Request::GetHead => Opcode::GetHead,
+            Request::AddSig { .. } => Opcode::AddSig,
+            Request::Hello { .. } => Opcode::Hello,
+            Request::RotateKey { .. } => Opcode::RotateKey,
+            Request::GetKeyHistory { .. } => Opcode::GetKeyHistory,
+            Request::RevokeKey { .. } => Opcode::RevokeKey,
+            Request::GetRevocations { .. } => Opcode::GetRevocations,
+            Request::WithdrawSig { .. } => Opcode::WithdrawSig,
+            Request::GetWithdrawals { .. } => Opcode::GetWithdrawals,
+        }
+    }
+
+    pub fn encode(&self) -> Message {
+        let mut m = Message::new();
+        m.write_u8(self.opcode() as u8);
+        match self {
+            Request::SetUser { user, proof } => {
+                m.write_buffer(&user.key);
+                m.write_buffer(&user.username);
+                m.write_buffer(proof);
+            }
+            Request::GetUser { hash }
+            | Request::GetObj { hash }
+            | Request::GetSig { hash }
+            | Request::GetKeyHistory { hash }
+            | Request::GetRevocations { hash }
+            | Request::GetWithdrawals { hash } => {
+                m.write_buffer(hash);
+            }
+            Request::GetInclusionProof { hash, size } => {
+                m.write_buffer(hash);
+                if let Some(size) = size {
+                    m.write_u64(*size);
+                }
+            }
+            Request::GetConsistencyProof { first, second } => {
+                m.write_u64(*first);
+                m.write_u64(*second);
+            }
+            Request::GetTreeHead { size } => {
+                if let Some(size) = size {
+                    m.write_u64(*size);
+                }
+            }
+            Request::AddSig {
+                id,
+                expected_prev,
+                sig,
+            } => {
+                m.write_u64(*id);
+                m.write_buffer(expected_prev);
+                sig.write_message(&mut m);
+            }
+            Request::Hello {
+                min_version,
+                max_version,
+                capabilities,
+            } => {
+                m.write_u16(*min_version);
+                m.write_u16(*max_version);
+                m.write_u64(*capabilities);
+            }
+            Request::RotateKey { rotation } => rotation.write_message(&mut m),
+            Request::RevokeKey { revocation } => revocation.write_message(&mut m),
+            Request::WithdrawSig { withdrawal } => withdrawal.write_message(&mut m),
+            Request::RequestEnqueue
+            | Request::GetChallenge
+            | Request::GetCheckpoint
+            | Request::GetHead => {}
+        }
+        m
+    }
+
+    pub fn decode(m: &mut Message) -> Result<Self, Error> {
+        Ok(match Opcode::from_u8(m.read_u8()?)? {
+            Opcode::SetUser => {
+                let key = m.read_buffer()?.to_vec();
+                let username = m.read_buffer()?.to_vec();
+                let proof = m.read_buffer()?.to_vec();
+                Request::SetUser {
+                    user: User { username, key },
+                    proof,
+                }
+            }
+            Opcode::GetUser => Request::GetUser {
+                hash: m.read_buffer()?.try_into()?,
+            },
+            Opcode::GetObj => Request::GetObj {
+                hash: m.read_buffer()?.try_into()?,
+            },
+            Opcode::GetSig => Request::GetSig {
+                hash: m.read_buffer()?.try_into()?,
+            },
+            Opcode::RequestEnqueue => Request::RequestEnqueue,
+            Opcode::GetChallenge => Request::GetChallenge,
+            Opcode::GetInclusionProof => Request::GetInclusionProof {
+                hash: m.read_buffer()?.try_into()?,
+                size: m.read_u64().ok(),
+            },
+            Opcode::GetConsistencyProof => Request::GetConsistencyProof {
+                first: m.read_u64()?,
+                second: m.read_u64()?,
+            },
+            Opcode::GetTreeHead => Request::GetTreeHead {
+                size: m.read_u64().ok(),
+            },
+            Opcode::GetCheckpoint => Request::GetCheckpoint,
+            Opcode::GetHead => Request::GetHead,
+            Opcode::AddSig => Request::AddSig {
+                id: m.read_u64()?,
+                expected_prev: m.read_buffer()?.try_into()?,
+                sig: Signature::read_message(m)?,
+            },
+            Opcode::Hello => Request::Hello {
+                min_version: m.read_u16()?,
+                max_version: m.read_u16()?,
+                capabilities: m.read_u64()?,
+            },
+            Opcode::RotateKey => Request::RotateKey {
+                rotation: KeyRotation::read_message(m)?,
+            },
+            Opcode::GetKeyHistory => Request::GetKeyHistory {
+                hash: m.read_buffer()?.try_into()?,
+            },
+            Opcode::RevokeKey => Request::RevokeKey {
+                revocation: Revocation::read_message(m)?,
+            },
+            Opcode::GetRevocations => Request::GetRevocations {
+                hash: m.read_buffer()?.try_into()?,
+            },
+            Opcode::WithdrawSig => Request::WithdrawSig {
+                withdrawal: Withdrawal::read_message(m)?,
+            },
+            Opcode::GetWithdrawals => Request::GetWithdrawals {
+                hash: m.read_buffer()?.try_into()?,
+            },
+        })
+    }
+}
+
+/// Value carried in a response after the status byte
+pub trait Payload: Sized {
+    fn write_message(&self, m: &mut Message);
+
+    fn read_message(m: &mut Message) -> Result<Self, Error>;
+
+    fn to_message(&self) -> Message {
+        let mut m = Message::new();
+        self.write_message(&mut m);
+        m
+    }
+}
+
+impl Payload for [u8; 32] {
+    fn write_message(&self, m: &mut Message) {
+        m.write_buffer(self);
+    }
+
+    fn read_message(m: &mut Message) -> Result<Self, Error> {
+        Ok(m.read_buffer()?.try_into()?)
+    }
+}
+
+// Hash lists run until the end of the message
+impl Payload for Vec<[u8; 32]> {
+    fn write_message(&self, m: &mut Message) {
+        for h in self {
+            m.write_buffer(h);
+        }
+    }
+
+    fn read_message(m: &mut Message) -> Result<Self, Error> {
+        let mut r = Vec::new();
+        while let Ok(h) = m.read_buffer() {
+            r.push(h.try_into()?);
+        }
+        Ok(r)
+    }
+}
+
+impl Payload for User {
+    fn write_message(&self, m: &mut Message) {
+        m.write_buffer(&self.username);
+        m.write_buffer(&self.key);
+    }
+
+    fn read_message(m: &mut Message) -> Result<Self, Error> {
+        let username = m.read_buffer()?.to_vec();
+        let key = m.read_buffer()?.to_vec();
+        Ok(Self { username, key })
+    }
+}
+
+impl Payload for Signature {
+    fn write_message(&self, m: &mut Message) {
+        m.write_buffer(&self.obj);
+        m.write_buffer(&self.user);
+        m.write_buffer(&self.prev_sig);
+        m.write_buffer(&self.signature);
+    }
+
+    fn read_message(m: &mut Message) -> Result<Self, Error> {
+        Ok(Self {
+            obj: m.read_buffer()?.try_into()?,
+            user: m.read_buffer()?.try_into()?,
+            prev_sig: m.read_buffer()?.try_into()?,
+            signature: m.read_buffer()?.to_vec(),
+        })
+    }
+}
+
+impl Payload for KeyRotation {
+    fn write_message(&self, m: &mut Message) {
+        m.write_buffer(&self.username);
+        m.write_buffer(&self.old_key);
+        m.write_buffer(&self.new_key);
+        m.write_u64(self.since);
+        m.write_buffer(&self.old_signature);
+        m.write_buffer(&self.new_signature);
+    }
+
+    fn read_message(m: &mut Message) -> Result<Self, Error> {
+        Ok(Self {
+            username: m.read_buffer()?.to_vec(),
+            old_key: m.read_buffer()?.to_vec(),
+            new_key: m.read_buffer()?.to_vec(),
+            since: m.read_u64()?,
+            old_signature: m.read_buffer()?.to_vec(),
+            new_signature: m.read_buffer()?.to_vec(),
+        })
+    }
+}
+
+impl Payload for Vec<KeyRotation> {
+    fn write_message(&self, m: &mut Message) {
+        m.write_u32(self.len() as u32);
+        for rotation in self {
+            rotation.write_message(m);
+        }
+    }
+
+    fn read_message(m: &mut Message) -> Result<Self, Error> {
+        let mut r = Vec::new();
+        for _ in 0..m.read_u32()? {
+            r.push(KeyRotation::read_message(m)?);
+        }
+        Ok(r)
+    }
+}
+
+impl Payload for Revocation {
+    fn write_message(&self, m: &mut Message) {
+        m.write_buffer(&self.username);
+        m.write_buffer(&self.key);
+        m.write_u64(self.since);
+        m.write_buffer(&self.signature);
+    }
+
+    fn read_message(m: &mut Message) -> Result<Self, Error> {
+        Ok(Self {
+            username: m.read_buffer()?.to_vec(),
+            key: m.read_buffer()?.to_vec(),
+            since: m.read_u64()?,
+            signature: m.read_buffer()?.to_vec(),
+        })
+    }
+}
+
+impl Payload for Vec<Revocation> {
+    fn write_message(&self, m: &mut Message) {
+        m.write_u32(self.len() as u32);
+        for revocation in self {
+            revocation.write_message(m);
+        }
+    }
+
+    fn read_message(m: &mut Message) -> Result<Self, Error> {
+        let mut r = Vec::new();
+        for _ in 0..m.read_u32()? {
+            r.push(Revocation::read_message(m)?);
+        }
+        Ok(r)
+    }
+}
+
+impl Payload for Withdrawal {
+    fn write_message(&self, m: &mut Message) {
+        m.write_buffer(&self.sig);
+        m.write_buffer(&self.obj);
+        m.write_buffer(&self.user);
+        m.write_buffer(&self.reason);
+        m.write_buffer(&self.signature);
+    }
+
+    fn read_message(m: &mut Message) -> Result<Self, Error> {
+        Ok(Self {
+            sig: m.read_buffer()?.try_into()?,
+            obj: m.read_buffer()?.try_into()?,
+            user: m.read_buffer()?.try_into()?,
+            reason: m.read_buffer()?.to_vec(),
+            signature: m.read_buffer()?.to_vec(),
+        })
+    }
+}
+
+impl Payload for Vec<Withdrawal> {
+    fn write_message(&self, m: &mut Message) {
+        m.write_u32(self.len() as u32);
+        for withdrawal in self {
+            withdrawal.write_message(m);
+        }
+    }
+
+    fn read_message(m: &mut Message) -> Result<Self, Error> {
+        let mut r = Vec::new();
+        for _ in 0..m.read_u32()? {
+            r.push(Withdrawal::read_message(m)?);
+        }
+        Ok(r)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InclusionProof {
+    pub index: u64,
+    pub size: u64,
+    pub root: [u8; 32],
+    pub path: Vec<[u8; 32]>,
+}
+
+impl Payload for InclusionProof {
+    fn write_message(&self, m: &mut Message) {
+        m.write_u64(self.index);
+        m.write_u64(self.size);
+        m.write_buffer(&self.root);
+        self.path.write_message(m);
+    }
+
+    fn read_message(m: &mut Message) -> Result<Self, Error> {
+        Ok(Self {
+            index: m.read_u64()?,
+            size: m.read_u64()?,
+            root: m.read_buffer()?.try_into()?,
+            path: Vec::read_message(m)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TreeHead {
+    pub size: u64,
+    pub root: [u8; 32],
+}
+
+impl Payload for TreeHead {
+    fn write_message(&self, m: &mut Message) {
+        m.write_u64(self.size);
+        m.write_buffer(&self.root);
+    }
+
+    fn read_message(m: &mut Message) -> Result<Self, Error> {
+        Ok(Self {
+            size: m.read_u64()?,
+            root: m.read_buffer()?.try_into()?,
+        })
+    }
+}
+
+impl Payload for SignedCheckpoint {
+    fn write_message(&self, m: &mut Message) {
+        m.write_buffer(&self.key);
+        m.write_u64(self.checkpoint.size);
+        m.write_buffer(&self.checkpoint.root);
+        m.write_buffer(&self.checkpoint.head);
+        m.write_u64(self.checkpoint.timestamp);
+        m.write_buffer(&self.signature);
+    }
+
+    fn read_message(m: &mut Message) -> Result<Self, Error> {
+        let key = m.read_buffer()?.to_vec();
+        let checkpoint = Checkpoint {
+            size: m.read_u64()?,
+            root: m.read_buffer()?.try_into()?,
+            head: m.read_buffer()?.try_into()?,
+            timestamp: m.read_u64()?,
+        };
+        let signature = m.read_buffer()?.to_vec();
+        Ok(Self {
+            key,
+            checkpoint,
+            signature,
+        })
+    }
+}
+
+/// Response to a read request: 1 followed by the value, 0 if there is
+/// nothing to return, -1 if the server failed
+#[derive(Clone, Debug, PartialEq)]
+pub enum Lookup<T> {
+    Found(T),
+    NotFound,
+    Failed,
+}
+
+impl<T: Payload> Lookup<T> {
+    pub fn encode(&self) -> Message {
+        let mut m = Message::new();
+        match self {
+            Lookup::Found(v) => {
+                m.write_i8(1);
+                v.write_message(&mut m);
+            }
+            Lookup::NotFound => m.write_i8(0),
+            Lookup::Failed => m.write_i8(-1),
+        }
+        m
+    }
+
+    pub fn decode(m: &mut Message) -> Result<Self, Error> {
+        match m.read_i8()? {
+            1 => Ok(Lookup::Found(T::read_message(m)?)),
+            0 => Ok(Lookup::NotFound),
+            -1 => Ok(Lookup::Failed),
+            UNSUPPORTED => Err(Unsupported),
+            s => Err(UnknownStatus(s)),
+        }
+    }
+}
+
+macro_rules! status {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident = $value:literal,)* }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        pub enum $name {
+            $($variant = $value,)*
+        }
+
+        impl $name {
+            pub fn from_i8(s: i8) -> Result<Self, Error> {
+                match s {
+                    $($value => Ok($name::$variant),)*
+                    UNSUPPORTED => Err(Unsupported),
+                    _ => Err(UnknownStatus(s)),
+                }
+            }
+
+            pub fn encode(self) -> Message {
+                let mut m = Message::new();
+                m.write_i8(self as i8);
+                m
+            }
+
+            pub fn decode(m: &mut Message) -> Result<Self, Error> {
+                Self::from_i8(m.read_i8()?)
+            }
+        }
+    };
+}
+
+status! {
+    /// Response to `Request::SetUser`
+    SetUserStatus {
+        Ok = 0,
+        Failed = -1,
+        NoChallenge = -2,
+        InvalidProof = -3,
+    }
+}
+
+status! {
+    /// Outcome of `Request::AddSig` or of the signature sent after `Request::RequestEnqueue`
+    AddSigStatus {
+        Accepted = 0,
+        StorageError = -1,
+        StaleChainHead = -2,
+        UnknownUser = -3,
+        InvalidSignature = -4,
+        RevokedKey = -5,
+    }
+}
+
+status! {
+    /// Outcome of `Request::RotateKey`, `KeyMismatch` if `old_key` is not
+    /// the user's current key
+    RotateKeyStatus {
+        Ok = 0,
+        Failed = -1,
+        StaleLog = -2,
+        UnknownUser = -3,
+        InvalidSignature = -4,
+        KeyMismatch = -5,
+        RevokedKey = -6,
+    }
+}
+
+status! {
+    /// Outcome of `Request::RevokeKey`, `UnknownKey` if the key never belonged to the user
+    RevokeKeyStatus {
+        Ok = 0,
+        Failed = -1,
+        UnknownUser = -2,
+        UnknownKey = -3,
+        InvalidSignature = -4,
+    }
+}
+
+status! {
+    /// Outcome of `Request::WithdrawSig`. Withdrawals have to be signed with the
+    /// signer's current key, `RevokedKey` if it is revoked. `ReasonTooLong` if
+    /// the reason is longer than `MAX_FIELD_LEN`.
+    WithdrawSigStatus {
+        Ok = 0,
+        Failed = -1,
+        UnknownSignature = -2,
+        InvalidSignature = -3,
+        RevokedKey = -4,
+        AlreadyWithdrawn = -5,
+        ReasonTooLong = -6,
+    }
+}
+
+/// Response to `Request::AddSig`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignResponse {
+    pub id: u64,
+    pub status: AddSigStatus,
+    /// Chain head after the request, the new signature if it was accepted
+    pub head: [u8; 32],
+}
+
+impl SignResponse {
+    pub fn encode(&self) -> Message {
+        let mut m = self.status.encode();
+        m.write_u64(self.id);
+        m.write_buffer(&self.head);
+        m
+    }
+
+    pub fn decode(m: &mut Message) -> Result<Self, Error> {
+        Ok(Self {
+            status: AddSigStatus::decode(m)?,
+            id: m.read_u64()?,
+            head: m.read_buffer()?.try_into()?,
+        })
+    }
+}
+
+status! {
+    /// Outcome of `Request::Hello`
+    HelloStatus {
+        Ok = 0,
+        IncompatibleVersion = -1,
+    }
+}
+
+/// Response to `Request::Hello`
+#[derive(Clone, Debug, PartialEq)]
+pub struct HelloResponse {
+    pub status: HelloStatus,
+    /// Highest version both sides speak, 0 if there is none
+    pub version: u16,
+    pub min_version: u16,
+    pub max_version: u16,
+    pub capabilities: u64,
+}
+
+impl HelloResponse {
+    /// Answers a client speaking `min_version..=max_version` on behalf of
+    /// a server speaking the versions of this crate
+    pub fn negotiate(min_version: u16, max_version: u16, capabilities: u64) -> Self {
+        let version = max_version.min(PROTOCOL_VERSION);
+        let compatible = version >= min_version.max(MIN_PROTOCOL_VERSION);
+        Self {
+            status: if compatible {
+                HelloStatus::Ok
+            } else {
+                HelloStatus::IncompatibleVersion
+            },
+            version: if compatible { version } else { 0 },
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    pub fn encode(&self) -> Message {
+        let mut m = self.status.encode();
+        m.write_u16(self.version);
+        m.write_u16(self.min_version);
+        m.write_u16(self.max_version);
+        m.write_u64(self.capabilities);
+        m
+    }
+
+    pub fn decode(m: &mut Message) -> Result<Self, Error> {
+        Ok(Self {
+            status: HelloStatus::decode(m)?,
+            version: m.read_u16()?,
+            min_version: m.read_u16()?,
+            max_version: m.read_u16()?,
+            capabilities: m.read_u64()?,
+        })
+    }
+}
+
+/// Response to a request with an unknown opcode
+pub fn unsupported() -> Message {
+    let mut m = Message::new();
+    m.write_i8(UNSUPPORTED);
+    m
+}
@@ -0,0 +1,354 @@
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sha::Sha256;
+use openssl::sign::{Signer, Verifier};
+
+/// Longest key, signature or reason a stored record may hold, longer
+/// fields are refused when read
+pub const MAX_FIELD_LEN: usize = 4096;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub username: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+impl User {
+    /// Checks that `proof` is a signature of `challenge || username` made with the user's key
+    pub fn verify_proof(&self, challenge: &[u8], proof: &[u8]) -> Result<bool, ErrorStack> {
+        let key = PKey::public_key_from_der(&self.key)?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), key.as_ref())?;
+        verifier.update(challenge)?;
+        verifier.update(&self.username)?;
+        verifier.verify(proof)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&(self.key.len() as u32).to_le_bytes())?;
+        writer.write_all(&self.key)?;
+        writer.write_all(&self.username)?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut key = Vec::new();
+        let mut key_len_bytes = [0; 4];
+        reader.read_exact(&mut key_len_bytes)?;
+        let key_len = u32::from_le_bytes(key_len_bytes);
+        if key_len as usize > MAX_FIELD_LEN {
+            return Err(io::Error::new(ErrorKind::InvalidData, "key too long"));
+        }
+        key.resize(key_len as usize, 0);
+        reader.read_exact(&mut key)?;
+        let mut username = Vec::new();
+        reader.read_to_end(&mut username)?;
+        Ok(Self { username, key })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    pub obj: [u8; 32],
+    pub user: [u8; 32],
+    pub prev_sig: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+impl Signature {
+    pub fn hash(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.obj);
+        hasher.update(&self.user);
+        hasher.update(&self.prev_sig);
+        hasher.update(&self.signature);
+        hasher.finish()
+    }
+
+    pub fn verify(&self, key: &[u8]) -> Result<bool, ErrorStack> {
+        let key = PKey::public_key_from_der(key)?;
+        let mut verifier = Verifier::new_without_digest(key.as_ref())?;
+        verifier.update(&self.obj)?;
+        verifier.update(&self.prev_sig)?;
+        verifier.verify(&self.signature)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.obj)?;
+        writer.write_all(&self.user)?;
+        writer.write_all(&self.prev_sig)?;
+        writer.write_all(&self.signature)?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut obj = [0; 32];
+        reader.read_exact(&mut obj)?;
+        let mut user = [0; 32];
+        reader.read_exact(&mut user)?;
+        let mut prev_sig = [0; 32];
+        reader.read_exact(&mut prev_sig)?;
+        let mut signature = Vec::new();
+        reader.read_to_end(&mut signature)?;
+        Ok(Self {
+            obj,
+            user,
+            prev_sig,
+            signature,
+        })
+    }
+}
+
+/// Replacement of a user's key. Both keys sign the record, so it can be
+/// forged neither by the server nor by someone holding only one of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyRotation {
+    pub username: Vec<u8>,
+    pub old_key: Vec<u8>,
+    pub new_key: Vec<u8>,
+    /// Log size at the rotation, signatures from this position on are made with `new_key`
+    pub since: u64,
+    pub old_signature: Vec<u8>,
+    pub new_signature: Vec<u8>,
+}
+
+impl KeyRotation {
+    /// What both keys sign
+    pub fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for field in &[&self.username, &self.old_key, &self.new_key] {
+            data.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            data.extend_from_slice(field);
+        }
+        data.extend_from_slice(&self.since.to_le_bytes());
+        data
+    }
+
+    pub fn verify(&self) -> Result<bool, ErrorStack> {
+        let data = self.signed_data();
+        for (key, signature) in &[
+            (&self.old_key, &self.old_signature),
+            (&self.new_key, &self.new_signature),
+        ] {
+            let key = PKey::public_key_from_der(key)?;
+            let mut verifier = Verifier::new(MessageDigest::sha256(), key.as_ref())?;
+            verifier.update(&data)?;
+            if !verifier.verify(signature)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for field in &[&self.username, &self.old_key, &self.new_key] {
+            write_field(writer, field)?;
+        }
+        writer.write_all(&self.since.to_le_bytes())?;
+        write_field(writer, &self.old_signature)?;
+        write_field(writer, &self.new_signature)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let username = read_field(reader)?;
+        let old_key = read_field(reader)?;
+        let new_key = read_field(reader)?;
+        let mut since = [0; 8];
+        reader.read_exact(&mut since)?;
+        Ok(Self {
+            username,
+            old_key,
+            new_key,
+            since: u64::from_le_bytes(since),
+            old_signature: read_field(reader)?,
+            new_signature: read_field(reader)?,
+        })
+    }
+}
+
+// Keeps a revocation from being mistaken for any other signed statement
+const REVOCATION_TAG: &[u8] = b"fver key revocation";
+
+/// Statement that `key` of a user must not be trusted for signatures from log
+/// position `since` on. Signed by the revoked key itself or by a key that
+/// replaced it, so a key can be revoked after it was rotated away.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Revocation {
+    pub username: Vec<u8>,
+    pub key: Vec<u8>,
+    pub since: u64,
+    pub signature: Vec<u8>,
+}
+
+impl Revocation {
+    pub fn signed_data(&self) -> Vec<u8> {
+        let mut data = REVOCATION_TAG.to_vec();
+        for field in &[&self.username, &self.key] {
+            data.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            data.extend_from_slice(field);
+        }
+        data.extend_from_slice(&self.since.to_le_bytes());
+        data
+    }
+
+    /// Whether the revocation is signed by the revoked key or `successor`
+    pub fn verify(&self, successor: &[u8]) -> Result<bool, ErrorStack> {
+        let data = self.signed_data();
+        for key in &[&self.key, successor] {
+            let key = PKey::public_key_from_der(key)?;
+            let mut verifier = Verifier::new(MessageDigest::sha256(), key.as_ref())?;
+            verifier.update(&data)?;
+            if verifier.verify(&self.signature)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Whether the revocation covers `key` at log position `index`
+    pub fn covers(&self, key: &[u8], index: u64) -> bool {
+        self.key == key && self.since <= index
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_field(writer, &self.username)?;
+        write_field(writer, &self.key)?;
+        writer.write_all(&self.since.to_le_bytes())?;
+        write_field(writer, &self.signature)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let username = read_field(reader)?;
+        let key = read_field(reader)?;
+        let mut since = [0; 8];
+        reader.read_exact(&mut since)?;
+        Ok(Self {
+            username,
+            key,
+            since: u64::from_le_bytes(since),
+            signature: read_field(reader)?,
+        })
+    }
+}
+
+const WITHDRAWAL_TAG: &[u8] = b"fver signature withdrawal";
+
+/// Statement by a signer that signature `sig` of object `obj` no longer
+/// stands, with an optional `reason`. The signature itself stays in the log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Withdrawal {
+    pub sig: [u8; 32],
+    pub obj: [u8; 32],
+    pub user: [u8; 32],
+    pub reason: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl Withdrawal {
+    pub fn signed_data(&self) -> Vec<u8> {
+        let mut data = WITHDRAWAL_TAG.to_vec();
+        data.extend_from_slice(&self.sig);
+        data.extend_from_slice(&self.obj);
+        data.extend_from_slice(&self.user);
+        data.extend_from_slice(&(self.reason.len() as u32).to_le_bytes());
+        data.extend_from_slice(&self.reason);
+        data
+    }
+
+    pub fn verify(&self, key: &[u8]) -> Result<bool, ErrorStack> {
+        let key = PKey::public_key_from_der(key)?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), key.as_ref())?;
+        verifier.update(&self.signed_data())?;
+        verifier.verify(&self.signature)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.sig)?;
+        writer.write_all(&self.obj)?;
+        writer.write_all(&self.user)?;
+        write_field(writer, &self.reason)?;
+        write_field(writer, &self.signature)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut hashes = [[0; 32]; 3];
+        for hash in &mut hashes {
+            reader.read_exact(hash)?;
+        }
+        let [sig, obj, user] = hashes;
+        Ok(Self {
+            sig,
+            obj,
+            user,
+            reason: read_field(reader)?,
+            signature: read_field(reader)?,
+        })
+    }
+}
+
+fn write_field<W: Write>(writer: &mut W, field: &[u8]) -> io::Result<()> {
+    writer.write_all(&(field.len() as u32).to_le_bytes())?;
+    writer.write_all(field)
+}
+
+fn read_field<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    if len as usize > MAX_FIELD_LEN {
+        return Err(io::Error::new(ErrorKind::InvalidData, "field too long"));
+    }
+    let mut field = vec![0; len as usize];
+    reader.read_exact(&mut field)?;
+    Ok(field)
+}
+
+/// Signed statement of the log size and root together with the chain head
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    pub size: u64,
+    pub root: [u8; 32],
+    pub head: [u8; 32],
+    pub timestamp: u64,
+}
+
+impl Checkpoint {
+    pub fn sign(&self, key: &PKey<Private>) -> Result<Vec<u8>, ErrorStack> {
+        let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+        signer.update(&self.signed_data())?;
+        signer.sign_to_vec()
+    }
+
+    pub fn verify(&self, key: &[u8], signature: &[u8]) -> Result<bool, ErrorStack> {
+        let key = PKey::public_key_from_der(key)?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), key.as_ref())?;
+        verifier.update(&self.signed_data())?;
+        verifier.verify(signature)
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&self.size.to_le_bytes());
+        data.extend_from_slice(&self.root);
+        data.extend_from_slice(&self.head);
+        data.extend_from_slice(&self.timestamp.to_le_bytes());
+        data
+    }
+}
+
+/// Checkpoint as sent by the server, with the public key it was signed by
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignedCheckpoint {
+    pub key: Vec<u8>,
+    pub checkpoint: Checkpoint,
+    pub signature: Vec<u8>,
+}
+
+impl SignedCheckpoint {
+    pub fn verify(&self) -> Result<bool, ErrorStack> {
+        self.checkpoint.verify(&self.key, &self.signature)
+    }
+}
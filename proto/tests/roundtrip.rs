@@ -0,0 +1,461 @@
+use fver_proto::{
+    capability, leaf_hash, node_hash, unsupported, AddSigStatus, Checkpoint, Error, HelloResponse,
+    HelloStatus, InclusionProof, KeyRotation, Lookup, Opcode, Payload, Request, Revocation,
+    RevokeKeyStatus, RotateKeyStatus, SetUserStatus, SignResponse, Signature, SignedCheckpoint,
+    TreeHead, User, WithdrawSigStatus, Withdrawal, MAX_FIELD_LEN, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use simpletcp::simpletcp::Message;
+
+fn hash(n: u8) -> [u8; 32] {
+    [n; 32]
+}
+
+fn user() -> User {
+    User {
+        username: b"alice".to_vec(),
+        key: vec![1, 2, 3, 4],
+    }
+}
+
+fn signature() -> Signature {
+    Signature {
+        obj: hash(1),
+        user: hash(2),
+        prev_sig: hash(3),
+        signature: vec![9; 70],
+    }
+}
+
+fn rotation() -> KeyRotation {
+    KeyRotation {
+        username: b"alice".to_vec(),
+        old_key: vec![1, 2, 3, 4],
+        new_key: vec![5, 6, 7],
+        since: 12,
+        old_signature: vec![8; 70],
+        new_signature: vec![9; 71],
+    }
+}
+
+fn revocation() -> Revocation {
+    Revocation {
+        username: b"alice".to_vec(),
+        key: vec![1, 2, 3, 4],
+        since: 7,
+        signature: vec![8; 70],
+    }
+}
+
+fn withdrawal() -> Withdrawal {
+    Withdrawal {
+        sig: hash(1),
+        obj: hash(2),
+        user: hash(3),
+        reason: b"contains malware".to_vec(),
+        signature: vec![9; 70],
+    }
+}
+
+fn key() -> PKey<Private> {
+    PKey::from_ec_key(
+        EcKey::generate(EcGroup::from_curve_name(Nid::SECP384R1).unwrap().as_ref()).unwrap(),
+    )
+    .unwrap()
+}
+
+fn sign(key: &PKey<Private>, data: &[u8]) -> Vec<u8> {
+    let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+    signer.update(data).unwrap();
+    signer.sign_to_vec().unwrap()
+}
+
+fn roundtrip_request(r: Request) {
+    let decoded = Request::decode(&mut r.encode()).unwrap();
+    assert_eq!(decoded, r);
+}
+
+fn roundtrip_lookup<T: Payload + PartialEq + std::fmt::Debug + Clone>(v: T) {
+    for l in [Lookup::Found(v), Lookup::NotFound, Lookup::Failed] {
+        let decoded = Lookup::<T>::decode(&mut l.encode()).unwrap();
+        assert_eq!(decoded, l);
+    }
+}
+
+#[test]
+fn requests() {
+    roundtrip_request(Request::SetUser {
+        user: user(),
+        proof: vec![5; 64],
+    });
+    roundtrip_request(Request::GetUser { hash: hash(1) });
+    roundtrip_request(Request::GetObj { hash: hash(2) });
+    roundtrip_request(Request::GetSig { hash: hash(3) });
+    roundtrip_request(Request::RequestEnqueue);
+    roundtrip_request(Request::GetChallenge);
+    roundtrip_request(Request::GetInclusionProof {
+        hash: hash(4),
+        size: Some(7),
+    });
+    roundtrip_request(Request::GetInclusionProof {
+        hash: hash(4),
+        size: None,
+    });
+    roundtrip_request(Request::GetConsistencyProof {
+        first: 3,
+        second: 10,
+    });
+    roundtrip_request(Request::GetTreeHead { size: Some(5) });
+    roundtrip_request(Request::GetTreeHead { size: None });
+    roundtrip_request(Request::GetCheckpoint);
+    roundtrip_request(Request::GetHead);
+    roundtrip_request(Request::AddSig {
+        id: 42,
+        expected_prev: hash(3),
+        sig: signature(),
+    });
+    roundtrip_request(Request::Hello {
+        min_version: 1,
+        max_version: 3,
+        capabilities: capability::SIGN_REQUEST,
+    });
+    roundtrip_request(Request::RotateKey {
+        rotation: rotation(),
+    });
+    roundtrip_request(Request::GetKeyHistory { hash: hash(5) });
+    roundtrip_request(Request::RevokeKey {
+        revocation: revocation(),
+    });
+    roundtrip_request(Request::GetRevocations { hash: hash(6) });
+    roundtrip_request(Request::WithdrawSig {
+        withdrawal: withdrawal(),
+    });
+    roundtrip_request(Request::GetWithdrawals { hash: hash(7) });
+}
+
+#[test]
+fn opcodes_are_single_unsigned_byte() {
+    for op in 0..=18 {
+        let opcode = Opcode::from_u8(op).unwrap();
+        assert_eq!(opcode as u8, op);
+    }
+    let mut m = Request::GetObj { hash: hash(1) }.encode();
+    assert_eq!(m.read_u8().unwrap(), 2);
+    assert!(matches!(Opcode::from_u8(19), Err(Error::UnknownOpcode(19))));
+}
+
+#[test]
+fn truncated_request() {
+    let mut m = Message::new();
+    m.write_u8(Opcode::GetUser as u8);
+    m.write_buffer(&[0; 16]);
+    assert!(Request::decode(&mut m).is_err());
+}
+
+#[test]
+fn lookups() {
+    roundtrip_lookup(hash(7));
+    roundtrip_lookup(user());
+    roundtrip_lookup(signature());
+    roundtrip_lookup(vec![hash(1), hash(2), hash(3)]);
+    roundtrip_lookup(Vec::<[u8; 32]>::new());
+    roundtrip_lookup(InclusionProof {
+        index: 2,
+        size: 5,
+        root: hash(8),
+        path: vec![hash(4), hash(5), hash(6)],
+    });
+    roundtrip_lookup(TreeHead {
+        size: 12,
+        root: hash(9),
+    });
+    roundtrip_lookup(SignedCheckpoint {
+        key: vec![1; 120],
+        checkpoint: Checkpoint {
+            size: 12,
+            root: hash(9),
+            head: hash(10),
+            timestamp: 1_600_000_000,
+        },
+        signature: vec![2; 100],
+    });
+    roundtrip_lookup(vec![rotation(), rotation()]);
+    roundtrip_lookup(Vec::<KeyRotation>::new());
+    roundtrip_lookup(vec![revocation()]);
+    roundtrip_lookup(Vec::<Revocation>::new());
+    roundtrip_lookup(vec![withdrawal()]);
+    roundtrip_lookup(Vec::<Withdrawal>::new());
+}
+
+#[test]
+fn statuses() {
+    for s in &[
+        SetUserStatus::Ok,
+        SetUserStatus::Failed,
+        SetUserStatus::NoChallenge,
+        SetUserStatus::InvalidProof,
+    ] {
+        assert_eq!(SetUserStatus::decode(&mut s.encode()).unwrap(), *s);
+    }
+    for s in &[
+        AddSigStatus::Accepted,
+        AddSigStatus::StorageError,
+        AddSigStatus::StaleChainHead,
+        AddSigStatus::UnknownUser,
+        AddSigStatus::InvalidSignature,
+        AddSigStatus::RevokedKey,
+    ] {
+        assert_eq!(AddSigStatus::decode(&mut s.encode()).unwrap(), *s);
+    }
+    for s in &[
+        RotateKeyStatus::Ok,
+        RotateKeyStatus::Failed,
+        RotateKeyStatus::StaleLog,
+        RotateKeyStatus::UnknownUser,
+        RotateKeyStatus::InvalidSignature,
+        RotateKeyStatus::KeyMismatch,
+        RotateKeyStatus::RevokedKey,
+    ] {
+        assert_eq!(RotateKeyStatus::decode(&mut s.encode()).unwrap(), *s);
+    }
+    for s in &[
+        RevokeKeyStatus::Ok,
+        RevokeKeyStatus::Failed,
+        RevokeKeyStatus::UnknownUser,
+        RevokeKeyStatus::UnknownKey,
+        RevokeKeyStatus::InvalidSignature,
+    ] {
+        assert_eq!(RevokeKeyStatus::decode(&mut s.encode()).unwrap(), *s);
+    }
+    for s in &[
+        WithdrawSigStatus::Ok,
+        WithdrawSigStatus::Failed,
+        WithdrawSigStatus::UnknownSignature,
+        WithdrawSigStatus::InvalidSignature,
+        WithdrawSigStatus::RevokedKey,
+        WithdrawSigStatus::AlreadyWithdrawn,
+        WithdrawSigStatus::ReasonTooLong,
+    ] {
+        assert_eq!(WithdrawSigStatus::decode(&mut s.encode()).unwrap(), *s);
+    }
+    assert!(matches!(
+        AddSigStatus::from_i8(-6),
+        Err(Error::UnknownStatus(-6))
+    ));
+}
+
+#[test]
+fn sign_responses() {
+    for status in &[AddSigStatus::Accepted, AddSigStatus::StaleChainHead] {
+        let r = SignResponse {
+            id: 7,
+            status: *status,
+            head: hash(5),
+        };
+        assert_eq!(SignResponse::decode(&mut r.encode()).unwrap(), r);
+    }
+}
+
+#[test]
+fn hello() {
+    let r = HelloResponse::negotiate(MIN_PROTOCOL_VERSION, u16::MAX, capability::SIGN_REQUEST);
+    assert_eq!(r.status, HelloStatus::Ok);
+    assert_eq!(r.version, PROTOCOL_VERSION);
+    assert_eq!(r.min_version, MIN_PROTOCOL_VERSION);
+    assert_eq!(r.max_version, PROTOCOL_VERSION);
+    assert_eq!(HelloResponse::decode(&mut r.encode()).unwrap(), r);
+
+    let r = HelloResponse::negotiate(PROTOCOL_VERSION + 1, u16::MAX, 0);
+    assert_eq!(r.status, HelloStatus::IncompatibleVersion);
+    assert_eq!(r.version, 0);
+    assert_eq!(HelloResponse::decode(&mut r.encode()).unwrap(), r);
+
+    let r = HelloResponse::negotiate(0, MIN_PROTOCOL_VERSION - 1, 0);
+    assert_eq!(r.status, HelloStatus::IncompatibleVersion);
+}
+
+#[test]
+fn unsupported_requests() {
+    assert!(matches!(
+        HelloResponse::decode(&mut unsupported()),
+        Err(Error::Unsupported)
+    ));
+    assert!(matches!(
+        Lookup::<[u8; 32]>::decode(&mut unsupported()),
+        Err(Error::Unsupported)
+    ));
+}
+
+#[test]
+fn submitted_signature() {
+    let sig = signature();
+    let decoded = Signature::read_message(&mut sig.to_message()).unwrap();
+    assert_eq!(decoded, sig);
+    assert_eq!(decoded.hash(), sig.hash());
+}
+
+#[test]
+fn storage_encoding() {
+    let mut data = Vec::new();
+    user().write_to(&mut data).unwrap();
+    assert_eq!(User::read_from(&mut &data[..]).unwrap(), user());
+
+    let mut data = Vec::new();
+    signature().write_to(&mut data).unwrap();
+    assert_eq!(Signature::read_from(&mut &data[..]).unwrap(), signature());
+
+    let mut data = Vec::new();
+    rotation().write_to(&mut data).unwrap();
+    rotation().write_to(&mut data).unwrap();
+    let mut reader = &data[..];
+    assert_eq!(KeyRotation::read_from(&mut reader).unwrap(), rotation());
+    assert_eq!(KeyRotation::read_from(&mut reader).unwrap(), rotation());
+    assert!(reader.is_empty());
+
+    let mut data = Vec::new();
+    revocation().write_to(&mut data).unwrap();
+    assert_eq!(Revocation::read_from(&mut &data[..]).unwrap(), revocation());
+
+    let mut data = Vec::new();
+    withdrawal().write_to(&mut data).unwrap();
+    assert_eq!(Withdrawal::read_from(&mut &data[..]).unwrap(), withdrawal());
+}
+
+#[test]
+fn signatures_verify() {
+    let key = key();
+    let public = key.public_key_to_der().unwrap();
+
+    let mut sig = signature();
+    let mut signer = Signer::new_without_digest(&key).unwrap();
+    signer.update(&sig.obj).unwrap();
+    signer.update(&sig.prev_sig).unwrap();
+    sig.signature = signer.sign_to_vec().unwrap();
+    assert!(sig.verify(&public).unwrap());
+    sig.prev_sig = hash(4);
+    assert!(!sig.verify(&public).unwrap());
+
+    let checkpoint = Checkpoint {
+        size: 3,
+        root: hash(1),
+        head: hash(2),
+        timestamp: 10,
+    };
+    let mut signed = SignedCheckpoint {
+        key: public,
+        signature: checkpoint.sign(&key).unwrap(),
+        checkpoint,
+    };
+    let mut decoded = SignedCheckpoint::read_message(&mut signed.to_message()).unwrap();
+    assert!(decoded.verify().unwrap());
+    decoded.checkpoint.size = 4;
+    assert!(!decoded.verify().unwrap());
+    signed.checkpoint.timestamp = 11;
+    assert!(!signed.verify().unwrap());
+}
+
+#[test]
+fn rotations_verify() {
+    let (old, new) = (key(), key());
+    let mut rotation = KeyRotation {
+        username: b"alice".to_vec(),
+        old_key: old.public_key_to_der().unwrap(),
+        new_key: new.public_key_to_der().unwrap(),
+        since: 5,
+        old_signature: Vec::new(),
+        new_signature: Vec::new(),
+    };
+    rotation.old_signature = sign(&old, &rotation.signed_data());
+    rotation.new_signature = sign(&new, &rotation.signed_data());
+    assert!(rotation.verify().unwrap());
+
+    // Both keys have to sign
+    let mut one_key = rotation.clone();
+    one_key.new_signature = sign(&old, &rotation.signed_data());
+    assert!(!one_key.verify().unwrap());
+
+    let mut moved = rotation.clone();
+    moved.since = 6;
+    assert!(!moved.verify().unwrap());
+}
+
+#[test]
+fn revocations_verify() {
+    let (revoked, current, other) = (key(), key(), key());
+    let current_key = current.public_key_to_der().unwrap();
+    let mut revocation = Revocation {
+        username: b"alice".to_vec(),
+        key: revoked.public_key_to_der().unwrap(),
+        since: 3,
+        signature: Vec::new(),
+    };
+    for (signer, valid) in &[(&revoked, true), (&current, true), (&other, false)] {
+        revocation.signature = sign(signer, &revocation.signed_data());
+        assert_eq!(revocation.verify(&current_key).unwrap(), *valid);
+    }
+
+    revocation.since = 4;
+    assert!(!revocation.verify(&current_key).unwrap());
+    assert!(revocation.covers(&revocation.key, 4));
+    assert!(!revocation.covers(&revocation.key, 3));
+    assert!(!revocation.covers(&current_key, 4));
+}
+
+#[test]
+fn withdrawals_verify() {
+    let (signer, other) = (key(), key());
+    let signer_key = signer.public_key_to_der().unwrap();
+    let mut withdrawal = withdrawal();
+    withdrawal.signature = sign(&signer, &withdrawal.signed_data());
+    assert!(withdrawal.verify(&signer_key).unwrap());
+    assert!(!withdrawal
+        .verify(&other.public_key_to_der().unwrap())
+        .unwrap());
+
+    // The reason is signed too
+    withdrawal.reason.clear();
+    assert!(!withdrawal.verify(&signer_key).unwrap());
+}
+
+// The wire format has no such limit, so the server has to refuse longer
+// reasons before they are stored
+#[test]
+fn oversized_reason() {
+    let mut w = withdrawal();
+    w.reason = vec![b'x'; MAX_FIELD_LEN];
+    let mut data = Vec::new();
+    w.write_to(&mut data).unwrap();
+    assert_eq!(Withdrawal::read_from(&mut &data[..]).unwrap(), w);
+
+    w.reason.push(b'x');
+    roundtrip_request(Request::WithdrawSig {
+        withdrawal: w.clone(),
+    });
+    let mut data = Vec::new();
+    w.write_to(&mut data).unwrap();
+    assert!(Withdrawal::read_from(&mut &data[..]).is_err());
+}
+
+#[test]
+fn merkle_hashes() {
+    // RFC 6962 hash of the empty leaf
+    assert_eq!(
+        leaf_hash(&[]),
+        [
+            0x6e, 0x34, 0x0b, 0x9c, 0xff, 0xb3, 0x7a, 0x98, 0x9c, 0xa5, 0x44, 0xe6, 0xbb, 0x78,
+            0x0a, 0x2c, 0x78, 0x90, 0x1d, 0x3f, 0xb3, 0x37, 0x38, 0x76, 0x85, 0x11, 0xa3, 0x06,
+            0x17, 0xaf, 0xa0, 0x1d,
+        ]
+    );
+    let (a, b) = (leaf_hash(b"a"), leaf_hash(b"b"));
+    let mut leaf = Vec::new();
+    leaf.extend_from_slice(&a);
+    leaf.extend_from_slice(&b);
+    assert_ne!(node_hash(&a, &b), leaf_hash(&leaf));
+    assert_ne!(node_hash(&a, &b), node_hash(&b, &a));
+}
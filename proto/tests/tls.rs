@@ -0,0 +1,246 @@
+use std::env::temp_dir;
+use std::fs::{create_dir_all, remove_dir_all, write};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::spawn;
+
+use fver_proto::{tls_acceptor, Connection, Lookup, Request, User};
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{SslAcceptor, SslConnector, SslMethod};
+use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509Builder, X509NameBuilder, X509};
+use simpletcp::simpletcp::{Error, Message};
+
+fn key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+// Self-signed CA if `issuer` is None, otherwise a server certificate for localhost
+fn cert(name: &str, key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>) -> X509 {
+    let mut subject = X509NameBuilder::new().unwrap();
+    subject.append_entry_by_text("CN", name).unwrap();
+    let subject = subject.build();
+
+    let mut b = X509Builder::new().unwrap();
+    b.set_version(2).unwrap();
+    let serial = BigNum::from_u32(rand_serial()).unwrap();
+    b.set_serial_number(&serial.to_asn1_integer().unwrap())
+        .unwrap();
+    b.set_subject_name(&subject).unwrap();
+    b.set_pubkey(key).unwrap();
+    b.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    b.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    match issuer {
+        None => {
+            b.set_issuer_name(&subject).unwrap();
+            b.append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                .unwrap();
+            b.sign(key, MessageDigest::sha256()).unwrap();
+        }
+        Some((ca, ca_key)) => {
+            b.set_issuer_name(ca.subject_name()).unwrap();
+            let san = SubjectAlternativeName::new()
+                .dns("localhost")
+                .ip("127.0.0.1")
+                .build(&b.x509v3_context(Some(ca), None))
+                .unwrap();
+            b.append_extension(san).unwrap();
+            b.sign(ca_key, MessageDigest::sha256()).unwrap();
+        }
+    }
+    b.build()
+}
+
+fn rand_serial() -> u32 {
+    let mut r = [0; 4];
+    openssl::rand::rand_bytes(&mut r).unwrap();
+    u32::from_le_bytes(r) >> 1
+}
+
+struct Pki {
+    ca: X509,
+    acceptor: SslAcceptor,
+}
+
+fn pki(name: &str) -> Pki {
+    let ca_key = key();
+    let ca = cert("fver test CA", &ca_key, None);
+    let server_key = key();
+    let server = cert("localhost", &server_key, Some((&ca, &ca_key)));
+
+    let dir = temp_dir().join(format!("fver-tls-{}-{}", std::process::id(), name));
+    create_dir_all(&dir).unwrap();
+    let cert = dir.join("cert.pem");
+    let key = dir.join("key.pem");
+    write(&cert, server.to_pem().unwrap()).unwrap();
+    write(&key, server_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    let acceptor = tls_acceptor(&cert, &key).unwrap();
+    remove_dir_all(&dir).unwrap();
+    Pki { ca, acceptor }
+}
+
+// Echo server answering every request with the user it was sent, for one connection
+fn serve(pki: Pki) -> (String, X509) {
+    let acceptor = pki.acceptor;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    spawn(move || {
+        let (socket, _) = listener.accept().unwrap();
+        let mut conn = match Connection::accept_tls(&acceptor, socket) {
+            Ok(conn) => conn,
+            Err(_) => return,
+        };
+        if conn.wait_until_ready(5000).is_err() {
+            return;
+        }
+        while let Ok(Some(mut m)) = conn.read_timeout(5000) {
+            let user = match Request::decode(&mut m).unwrap() {
+                Request::SetUser { user, .. } => user,
+                _ => panic!("unexpected request"),
+            };
+            conn.write(Lookup::Found(user).encode()).unwrap();
+        }
+    });
+    (addr, pki.ca)
+}
+
+fn request(conn: &mut Connection, user: User) {
+    conn.write_blocking(
+        Request::SetUser {
+            user: user.clone(),
+            proof: vec![1; 64],
+        }
+        .encode(),
+    )
+    .unwrap();
+    let mut resp = conn.read_timeout(5000).unwrap().unwrap();
+    assert_eq!(Lookup::decode(&mut resp).unwrap(), Lookup::Found(user));
+}
+
+// The handshake may fail right away or while waiting for the server
+fn handshake(addr: &str, domain: &str, ca: Option<&X509>) -> Result<(), Error> {
+    Connection::connect_tls(addr, domain, ca)?.wait_until_ready(5000)
+}
+
+#[test]
+fn roundtrip_with_pinned_ca() {
+    let (addr, ca) = serve(pki("roundtrip"));
+    let mut conn = Connection::connect_tls(&addr, "localhost", Some(&ca)).unwrap();
+    conn.wait_until_ready(5000).unwrap();
+    for n in 0..3 {
+        request(
+            &mut conn,
+            User {
+                username: vec![b'a' + n; 8],
+                key: vec![n; 120],
+            },
+        );
+    }
+    // Larger than a single TLS record
+    request(
+        &mut conn,
+        User {
+            username: b"large".to_vec(),
+            key: vec![7; 100_000],
+        },
+    );
+}
+
+#[test]
+fn ip_address() {
+    let (addr, ca) = serve(pki("ip"));
+    handshake(&addr, "127.0.0.1", Some(&ca)).unwrap();
+}
+
+#[test]
+fn rejects_other_ca() {
+    let other = cert("other CA", &key(), None);
+    let (addr, _) = serve(pki("other-ca"));
+    assert!(handshake(&addr, "localhost", Some(&other)).is_err());
+}
+
+#[test]
+fn rejects_wrong_host() {
+    let (addr, ca) = serve(pki("wrong-host"));
+    assert!(handshake(&addr, "example.com", Some(&ca)).is_err());
+}
+
+#[test]
+fn rejects_untrusted_without_ca() {
+    let (addr, _) = serve(pki("system-roots"));
+    assert!(handshake(&addr, "localhost", None).is_err());
+}
+
+// Length prefixed frame as Connection sends it over TLS
+fn frame(mut m: Message) -> Vec<u8> {
+    let mut body = Vec::new();
+    while let Ok(b) = m.read_u8() {
+        body.push(b);
+    }
+    let mut frame = (body.len() as u32).to_le_bytes().to_vec();
+    frame.append(&mut body);
+    frame
+}
+
+#[test]
+fn frames_in_one_record() {
+    let pki = pki("one-record");
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let ca = pki.ca;
+    let users: Vec<_> = (0..2)
+        .map(|n| User {
+            username: vec![b'a' + n; 8],
+            key: vec![n; 120],
+        })
+        .collect();
+    let sent = users.clone();
+    spawn(move || {
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(ca).unwrap();
+        let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+        builder.set_cert_store(store.build());
+        let socket = TcpStream::connect(addr).unwrap();
+        let mut stream = builder.build().connect("localhost", socket).unwrap();
+        let mut data = Vec::new();
+        for user in sent {
+            data.extend(frame(
+                Request::SetUser {
+                    user,
+                    proof: vec![1; 64],
+                }
+                .encode(),
+            ));
+        }
+        stream.write_all(&data).unwrap();
+        // Until the server hangs up
+        let _ = stream.read(&mut [0; 1]);
+    });
+
+    let (socket, _) = listener.accept().unwrap();
+    let mut conn = Connection::accept_tls(&pki.acceptor, socket).unwrap();
+    conn.wait_until_ready(5000).unwrap();
+    let mut received = Vec::new();
+    let mut m = conn.read_timeout(5000).unwrap().unwrap();
+    received.push(Request::decode(&mut m).unwrap());
+    // The second frame arrived with the first, poll would not report it
+    assert!(conn.pending());
+    let mut m = conn.read().unwrap().unwrap();
+    received.push(Request::decode(&mut m).unwrap());
+    assert!(!conn.pending());
+    for (request, user) in received.into_iter().zip(users) {
+        match request {
+            Request::SetUser { user: u, .. } => assert_eq!(u, user),
+            _ => panic!("unexpected request"),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use openssl::ec::{EcGroup, EcKey};
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+
+use crate::error::Error;
+use crate::localstorage::write_private;
+
+pub fn load_key<P: AsRef<Path>>(path: P) -> Result<PKey<Private>, Error> {
+    let path = path.as_ref();
+    if path.exists() {
+        let mut file = File::open(path)?;
+        let mut der = Vec::new();
+        file.read_to_end(&mut der)?;
+        return Ok(PKey::from_ec_key(EcKey::private_key_from_der(&der)?)?);
+    }
+    let key = PKey::from_ec_key(EcKey::generate(
+        EcGroup::from_curve_name(Nid::SECP384R1)?.as_ref(),
+    )?)?;
+    write_private(path, &key.private_key_to_der()?)?;
+    Ok(key)
+}
@@ -0,0 +1,185 @@
+use std::fs::File;
+use std::io::Read;
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+
+use crate::error::Error;
+use crate::error::Error::InvalidConfig;
+use crate::log::Level;
+use crate::storage::Backend;
+
+pub struct Config {
+    pub(crate) bind: Vec<String>,
+    pub(crate) storage: PathBuf,
+    pub(crate) backend: Backend,
+    pub(crate) threads: usize,
+    pub(crate) enqueue_timeout: i32,
+    pub(crate) log_level: Level,
+    pub(crate) tls_cert: Option<PathBuf>,
+    pub(crate) tls_key: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: vec![String::from("0.0.0.0:37687")],
+            storage: PathBuf::from("storage"),
+            backend: Backend::Filesystem,
+            threads: 8,
+            enqueue_timeout: 1000,
+            log_level: Level::Info,
+            tls_cert: None,
+            tls_key: None,
+        }
+    }
+}
+
+pub const USAGE: &str = "Usage: fver-server [options]
+       fver-server fsck [--repair] [options]
+
+Commands:
+    fsck                     Check the storage for damage and exit, --repair fixes what it can
+
+Options:
+    --config <file>          Read options from file (key = value per line)
+    --bind <address>         Address to listen on, can be repeated (default 0.0.0.0:37687)
+    --storage <path>         Storage root directory (default storage)
+    --backend <backend>      Storage backend, fs or sqlite (default fs)
+    --threads <n>            Number of worker threads (default 8)
+    --enqueue-timeout <ms>   How long legacy clients may take to send a signature (default 1000)
+    --log-level <level>      error, warn, info or debug (default info)
+    --tls-cert <file>        PEM certificate chain, enables TLS together with --tls-key
+    --tls-key <file>         PEM private key of the certificate
+    --help                   Print this message";
+
+impl Config {
+    /// Builds the configuration from command line arguments.
+    /// Options given on the command line override the config file.
+    pub fn from_args<I: Iterator<Item = String>>(args: I) -> Result<Self, Error> {
+        let mut options = Vec::new();
+        let mut config_file = None;
+        let mut args = args;
+        while let Some(arg) = args.next() {
+            let key = match arg.strip_prefix("--") {
+                Some(key) => key.replace('-', "_"),
+                None => return Err(InvalidConfig(format!("Unexpected argument '{}'", arg))),
+            };
+            let value = args
+                .next()
+                .ok_or_else(|| InvalidConfig(format!("Missing value for '{}'", arg)))?;
+            if key == "config" {
+                config_file = Some(value);
+            } else {
+                options.push((key, value));
+            }
+        }
+
+        let mut config = Config::default();
+        if let Some(path) = config_file {
+            let mut content = String::new();
+            File::open(&path)
+                .and_then(|mut f| f.read_to_string(&mut content))
+                .map_err(|e| InvalidConfig(format!("Cannot read '{}': {}", path, e)))?;
+            let mut bind = Vec::new();
+            for (n, line) in content.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (key, value) = line.split_once('=').ok_or_else(|| {
+                    InvalidConfig(format!("{}:{}: expected 'key = value'", path, n + 1))
+                })?;
+                let (key, value) = (key.trim(), value.trim());
+                if key == "bind" {
+                    bind.push(value.to_string());
+                } else {
+                    config.set(key, value)?;
+                }
+            }
+            if !bind.is_empty() {
+                config.bind = bind;
+            }
+        }
+
+        let mut bind = Vec::new();
+        for (key, value) in options {
+            if key == "bind" {
+                bind.push(value);
+            } else {
+                config.set(&key, &value)?;
+            }
+        }
+        if !bind.is_empty() {
+            config.bind = bind;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        match key {
+            "storage" => {
+                self.storage = PathBuf::from(value);
+            }
+            "backend" => {
+                self.backend = Backend::parse(value)
+                    .ok_or_else(|| InvalidConfig(format!("Invalid storage backend '{}'", value)))?;
+            }
+            "threads" => {
+                self.threads = value
+                    .parse()
+                    .map_err(|_| InvalidConfig(format!("Invalid thread count '{}'", value)))?;
+            }
+            "enqueue_timeout" => {
+                self.enqueue_timeout = value
+                    .parse()
+                    .map_err(|_| InvalidConfig(format!("Invalid enqueue timeout '{}'", value)))?;
+            }
+            "log_level" => {
+                self.log_level = Level::parse(value)
+                    .ok_or_else(|| InvalidConfig(format!("Invalid log level '{}'", value)))?;
+            }
+            "tls_cert" => {
+                self.tls_cert = Some(PathBuf::from(value));
+            }
+            "tls_key" => {
+                self.tls_key = Some(PathBuf::from(value));
+            }
+            _ => {
+                return Err(InvalidConfig(format!("Unknown option '{}'", key)));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.threads == 0 {
+            return Err(InvalidConfig(String::from(
+                "Thread count must be at least 1",
+            )));
+        }
+        if self.enqueue_timeout <= 0 {
+            return Err(InvalidConfig(String::from(
+                "Enqueue timeout must be positive",
+            )));
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(InvalidConfig(String::from(
+                "TLS needs both a certificate and a key",
+            )));
+        }
+        for addr in &self.bind {
+            if addr.to_socket_addrs().is_err() {
+                return Err(InvalidConfig(format!("Invalid bind address '{}'", addr)));
+            }
+        }
+        if self.storage.exists() && !self.storage.is_dir() {
+            return Err(InvalidConfig(format!(
+                "Storage path '{}' is not a directory",
+                self.storage.display()
+            )));
+        }
+        Ok(())
+    }
+}
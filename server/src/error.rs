@@ -1,21 +1,30 @@
 use std::array::TryFromSliceError;
-use std::fmt::Debug;
-use std::io;
+use std::fmt::{Debug, Formatter};
+use std::{fmt, io};
 
 use simpletcp::simpletcp::MessageError;
 
-use crate::error::Error::{CorruptedMessage, IOError, NetworkError, OpenSSLError};
+use crate::error::Error::{
+    CorruptedMessage, CorruptedStorage, DatabaseError, HashCollision, IOError, InvalidConfig,
+    InvalidSignature, NetworkError, OpenSSLError, RevokedKey, StaleChainHead, UnknownUser,
+};
 use openssl::error::ErrorStack;
 use std::convert::Infallible;
 
-#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     NetworkError(simpletcp::simpletcp::Error),
     OpenSSLError(openssl::error::ErrorStack),
     IOError(io::Error),
+    DatabaseError(rusqlite::Error),
     HashCollision,
     CorruptedStorage,
     CorruptedMessage,
+    UnknownUser,
+    StaleChainHead,
+    InvalidSignature,
+    RevokedKey,
+    InvalidConfig(String),
 }
 
 impl From<simpletcp::simpletcp::Error> for Error {
@@ -30,6 +39,18 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        DatabaseError(e)
+    }
+}
+
+impl From<fver_proto::Error> for Error {
+    fn from(_: fver_proto::Error) -> Self {
+        CorruptedMessage
+    }
+}
+
 impl From<MessageError> for Error {
     fn from(_: MessageError) -> Self {
         CorruptedMessage
@@ -53,3 +74,22 @@ impl From<openssl::error::ErrorStack> for Error {
         OpenSSLError(e)
     }
 }
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError(e) => f.write_fmt(format_args!("NetworkError: {:?}", e)),
+            OpenSSLError(e) => f.write_fmt(format_args!("OpenSSLError: {:?}", e)),
+            IOError(e) => f.write_fmt(format_args!("IOError: {:?}", e)),
+            DatabaseError(e) => f.write_fmt(format_args!("DatabaseError: {:?}", e)),
+            HashCollision => f.write_str("HashCollision"),
+            CorruptedStorage => f.write_str("CorruptedStorage"),
+            CorruptedMessage => f.write_str("CorruptedMessage"),
+            UnknownUser => f.write_str("UnknownUser"),
+            StaleChainHead => f.write_str("StaleChainHead"),
+            InvalidSignature => f.write_str("InvalidSignature"),
+            RevokedKey => f.write_str("RevokedKey"),
+            InvalidConfig(e) => f.write_fmt(format_args!("InvalidConfig: {}", e)),
+        }
+    }
+}
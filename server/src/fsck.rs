@@ -0,0 +1,658 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use hex::encode;
+use openssl::sha::sha256;
+
+use crate::error::Error;
+use crate::error::Error::InvalidConfig;
+use crate::localstorage::{append_entry, write_atomic, LocalStorage};
+use crate::storage::{Signature, Storage, User};
+
+struct Fsck<'a> {
+    storage: &'a LocalStorage,
+    repair: bool,
+    problems: usize,
+    repaired: usize,
+}
+
+/// Checks the storage at `path`, returns the number of problems left unrepaired
+pub fn run<P: AsRef<Path>>(path: P, repair: bool) -> Result<usize, Error> {
+    if !path.as_ref().is_dir() {
+        return Err(InvalidConfig(format!(
+            "Storage '{}' does not exist",
+            path.as_ref().display()
+        )));
+    }
+    // A check only reads, the tree is left exactly as it was
+    let storage = LocalStorage::open_unchecked(path, repair)?;
+    let mut fsck = Fsck {
+        storage: &storage,
+        repair,
+        problems: 0,
+        repaired: 0,
+    };
+
+    if storage.root().join("journal").exists() {
+        fsck.problem("journal: unfinished signature write");
+        if repair {
+            storage.recover()?;
+            fsck.fixed("replayed journal");
+        }
+    }
+
+    fsck.check_users()?;
+    fsck.check_rotations()?;
+    fsck.check_revocations()?;
+    let sigs = fsck.check_sigs()?;
+    fsck.check_objects(&sigs)?;
+    fsck.check_withdrawals(&sigs)?;
+    let chain = fsck.check_chain(&sigs)?;
+    if let Some(chain) = chain {
+        fsck.check_log(&chain)?;
+    }
+
+    println!(
+        "{} problem(s) found, {} repaired.",
+        fsck.problems, fsck.repaired
+    );
+    Ok(fsck.problems - fsck.repaired)
+}
+
+impl Fsck<'_> {
+    fn problem(&mut self, msg: &str) {
+        self.problems += 1;
+        println!("{}", msg);
+    }
+
+    fn fixed(&mut self, msg: &str) {
+        self.repaired += 1;
+        println!("  repaired: {}", msg);
+    }
+
+    fn dir(&self, name: &str) -> PathBuf {
+        self.storage.root().join(name)
+    }
+
+    // Damaged files are moved aside instead of deleted so nothing is lost
+    fn move_to_lost_found(&mut self, dir: &str, name: &str) -> Result<(), Error> {
+        let lost = self.dir("lost+found");
+        create_dir_all(&lost)?;
+        rename(
+            self.dir(dir).join(name),
+            lost.join(format!("{}-{}", dir, name)),
+        )?;
+        self.fixed("moved to lost+found");
+        Ok(())
+    }
+
+    fn entries(&mut self, dir: &str) -> Result<Vec<String>, Error> {
+        let mut names = Vec::new();
+        // Only created on repair, a check treats it as empty
+        if !self.dir(dir).is_dir() {
+            return Ok(names);
+        }
+        for entry in read_dir(self.dir(dir))? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".tmp") {
+                self.problem(&format!("{}/{}: leftover temporary file", dir, name));
+                if self.repair {
+                    remove_file(self.dir(dir).join(&name))?;
+                    self.fixed("removed");
+                }
+                continue;
+            }
+            names.push(name);
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    // Registered key and every key rotated to, empty if the user is unknown
+    // or unreadable, which check_users reports
+    fn user_keys(&self, name: &str) -> Result<Vec<Vec<u8>>, Error> {
+        let p = self.dir("user").join(name);
+        if !p.exists() {
+            return Ok(Vec::new());
+        }
+        let user = match File::open(p).and_then(|mut f| User::read_from(&mut f)) {
+            Ok(user) => user,
+            Err(_) => return Ok(Vec::new()),
+        };
+        let mut keys = vec![user.key];
+        if let Ok(rotations) = self.storage.rotations(name) {
+            keys.extend(rotations.into_iter().map(|r| r.new_key));
+        }
+        Ok(keys)
+    }
+
+    fn check_users(&mut self) -> Result<(), Error> {
+        for name in self.entries("user")? {
+            let user =
+                File::open(self.dir("user").join(&name)).and_then(|mut f| User::read_from(&mut f));
+            let user = match user {
+                Ok(user) => user,
+                Err(_) => {
+                    self.problem(&format!("user/{}: unreadable", name));
+                    if self.repair {
+                        self.move_to_lost_found("user", &name)?;
+                    }
+                    continue;
+                }
+            };
+            let expected = encode(sha256(&user.username));
+            if name != expected {
+                self.problem(&format!(
+                    "user/{}: filename does not match sha256 of username '{}'",
+                    name,
+                    String::from_utf8_lossy(&user.username)
+                ));
+                if self.repair {
+                    if self.dir("user").join(&expected).exists() {
+                        self.move_to_lost_found("user", &name)?;
+                    } else {
+                        rename(
+                            self.dir("user").join(&name),
+                            self.dir("user").join(&expected),
+                        )?;
+                        self.fixed(&format!("renamed to {}", expected));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Reported only, a rotation can't be repaired without the user's keys
+    fn check_rotations(&mut self) -> Result<(), Error> {
+        for name in self.entries("rotations")? {
+            let p = self.dir("user").join(&name);
+            if !p.exists() {
+                self.problem(&format!("rotations/{}: unknown user", name));
+                continue;
+            }
+            let rotations = match self.storage.rotations(&name) {
+                Ok(rotations) => rotations,
+                Err(_) => {
+                    self.problem(&format!("rotations/{}: unreadable", name));
+                    continue;
+                }
+            };
+            let mut key = match File::open(p).and_then(|mut f| User::read_from(&mut f)) {
+                Ok(user) => user.key,
+                // Reported by check_users
+                Err(_) => continue,
+            };
+            for (i, rotation) in rotations.into_iter().enumerate() {
+                if encode(sha256(&rotation.username)) != name {
+                    self.problem(&format!(
+                        "rotations/{}: rotation {} of another user",
+                        name, i
+                    ));
+                }
+                if rotation.old_key != key {
+                    self.problem(&format!(
+                        "rotations/{}: rotation {} does not follow the previous key",
+                        name, i
+                    ));
+                }
+                if !rotation.verify().unwrap_or(false) {
+                    self.problem(&format!(
+                        "rotations/{}: rotation {} does not verify",
+                        name, i
+                    ));
+                }
+                key = rotation.new_key;
+            }
+        }
+        Ok(())
+    }
+
+    // Reported only, like rotations
+    fn check_revocations(&mut self) -> Result<(), Error> {
+        for name in self.entries("revocations")? {
+            let p = self.dir("user").join(&name);
+            if !p.exists() {
+                self.problem(&format!("revocations/{}: unknown user", name));
+                continue;
+            }
+            let revocations = match self.storage.revocations(&name) {
+                Ok(revocations) => revocations,
+                Err(_) => {
+                    self.problem(&format!("revocations/{}: unreadable", name));
+                    continue;
+                }
+            };
+            let keys = self.user_keys(&name)?;
+            // Signed by the revoked key or one that replaced it
+            for (i, revocation) in revocations.into_iter().enumerate() {
+                if encode(sha256(&revocation.username)) != name {
+                    self.problem(&format!(
+                        "revocations/{}: revocation {} of another user",
+                        name, i
+                    ));
+                }
+                let pos = match keys.iter().position(|key| key == &revocation.key) {
+                    Some(pos) => pos,
+                    None => {
+                        self.problem(&format!(
+                            "revocations/{}: revocation {} of an unknown key",
+                            name, i
+                        ));
+                        continue;
+                    }
+                };
+                let signers = &keys[pos..];
+                if !signers
+                    .iter()
+                    .any(|key| revocation.verify(key).unwrap_or(false))
+                {
+                    self.problem(&format!(
+                        "revocations/{}: revocation {} does not verify",
+                        name, i
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_sigs(&mut self) -> Result<HashMap<[u8; 32], Signature>, Error> {
+        let mut sigs = HashMap::new();
+        for name in self.entries("sig")? {
+            let sig = File::open(self.dir("sig").join(&name))
+                .and_then(|mut f| Signature::read_from(&mut f))
+                .map(|sig| (sig.hash(), sig));
+            match sig {
+                Ok((hash, sig)) if encode(hash) == name => {
+                    self.check_signer(&name, &sig)?;
+                    sigs.insert(hash, sig);
+                }
+                Ok(_) => {
+                    self.problem(&format!("sig/{}: hash does not match filename", name));
+                    if self.repair {
+                        self.move_to_lost_found("sig", &name)?;
+                    }
+                }
+                Err(_) => {
+                    self.problem(&format!("sig/{}: unreadable", name));
+                    if self.repair {
+                        self.move_to_lost_found("sig", &name)?;
+                    }
+                }
+            }
+        }
+        Ok(sigs)
+    }
+
+    // Reported only, the signature is still part of the chain. Any key the
+    // user ever had is accepted, which one was current is up to the client.
+    fn check_signer(&mut self, name: &str, sig: &Signature) -> Result<(), Error> {
+        let p = self.dir("user").join(encode(sig.user));
+        if !p.exists() {
+            self.problem(&format!("sig/{}: signed by unknown user", name));
+            return Ok(());
+        }
+        let keys = self.user_keys(&encode(sig.user))?;
+        if !keys.iter().any(|key| sig.verify(key).unwrap_or(false)) {
+            self.problem(&format!("sig/{}: signature does not verify", name));
+        }
+        Ok(())
+    }
+
+    fn check_objects(&mut self, sigs: &HashMap<[u8; 32], Signature>) -> Result<(), Error> {
+        let mut indexed = HashSet::new();
+        for name in self.entries("obj")? {
+            let mut data = Vec::new();
+            File::open(self.dir("obj").join(&name))?.read_to_end(&mut data)?;
+            let before = self.problems;
+            if data.len() % 32 != 0 {
+                self.problem(&format!("obj/{}: truncated entry", name));
+            }
+            let mut entries: Vec<[u8; 32]> = Vec::new();
+            for chunk in data.chunks_exact(32) {
+                let hash: [u8; 32] = chunk.try_into()?;
+                match sigs.get(&hash) {
+                    None => {
+                        self.problem(&format!(
+                            "obj/{}: entry {} points at missing signature",
+                            name,
+                            encode(hash)
+                        ));
+                    }
+                    Some(sig) if encode(sig.obj) != name => {
+                        self.problem(&format!(
+                            "obj/{}: entry {} belongs to object {}",
+                            name,
+                            encode(hash),
+                            encode(sig.obj)
+                        ));
+                    }
+                    Some(_) if entries.contains(&hash) => {
+                        self.problem(&format!("obj/{}: duplicate entry {}", name, encode(hash)));
+                    }
+                    Some(_) => {
+                        entries.push(hash);
+                    }
+                }
+            }
+            indexed.extend(entries.iter().copied());
+            if self.problems > before && self.repair {
+                let p = self.dir("obj").join(&name);
+                if entries.is_empty() {
+                    remove_file(p)?;
+                } else {
+                    write_atomic(&p, &entries.concat())?;
+                }
+                // One rewrite fixes every problem found in this file
+                self.repaired += self.problems - before - 1;
+                self.fixed("rewrote index");
+            }
+        }
+
+        let mut unindexed: Vec<_> = sigs.keys().filter(|h| !indexed.contains(*h)).collect();
+        unindexed.sort();
+        for hash in unindexed {
+            let obj = sigs[hash].obj;
+            self.problem(&format!(
+                "sig/{}: not indexed in obj/{}",
+                encode(hash),
+                encode(obj)
+            ));
+            if self.repair {
+                append_entry(&self.dir("obj").join(encode(obj)), hash)?;
+                self.fixed("added to index");
+            }
+        }
+        Ok(())
+    }
+
+    // Reported only. Withdrawals are signed with the key current at the time,
+    // which may have been rotated away since.
+    fn check_withdrawals(&mut self, sigs: &HashMap<[u8; 32], Signature>) -> Result<(), Error> {
+        for name in self.entries("withdrawals")? {
+            if !self.dir("obj").join(&name).exists() {
+                self.problem(&format!("withdrawals/{}: unknown object", name));
+                continue;
+            }
+            let withdrawals = match self.storage.withdrawals(&name) {
+                Ok(withdrawals) => withdrawals,
+                Err(_) => {
+                    self.problem(&format!("withdrawals/{}: unreadable", name));
+                    continue;
+                }
+            };
+            for (i, withdrawal) in withdrawals.into_iter().enumerate() {
+                let matches = sigs.get(&withdrawal.sig).is_some_and(|sig| {
+                    encode(sig.obj) == name
+                        && sig.obj == withdrawal.obj
+                        && sig.user == withdrawal.user
+                });
+                if !matches {
+                    self.problem(&format!(
+                        "withdrawals/{}: withdrawal {} of an unknown signature",
+                        name, i
+                    ));
+                    continue;
+                }
+                let keys = self.user_keys(&encode(withdrawal.user))?;
+                if !keys
+                    .iter()
+                    .any(|key| withdrawal.verify(key).unwrap_or(false))
+                {
+                    self.problem(&format!(
+                        "withdrawals/{}: withdrawal {} does not verify",
+                        name, i
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the chain from genesis to head, or None if it is broken
+    fn check_chain(
+        &mut self,
+        sigs: &HashMap<[u8; 32], Signature>,
+    ) -> Result<Option<Vec<[u8; 32]>>, Error> {
+        let head = match self.storage.get_prev() {
+            Ok(Some(head)) => head,
+            Ok(None) if sigs.is_empty() => return Ok(Some(Vec::new())),
+            _ => {
+                self.problem("prev_sig: missing or unreadable chain head");
+                // The head is the only signature no other signature points at
+                let referenced: HashSet<_> = sigs.values().map(|s| s.prev_sig).collect();
+                let tips: Vec<_> = sigs.keys().filter(|h| !referenced.contains(*h)).collect();
+                if self.repair && tips.len() == 1 {
+                    self.storage.set_prev(*tips[0])?;
+                    self.fixed(&format!("set chain head to {}", encode(tips[0])));
+                    *tips[0]
+                } else {
+                    return Ok(None);
+                }
+            }
+        };
+
+        let mut chain = Vec::new();
+        let mut visited = HashSet::new();
+        let mut hash = head;
+        loop {
+            if !visited.insert(hash) {
+                self.problem(&format!("chain: loop at {}", encode(hash)));
+                return Ok(None);
+            }
+            match sigs.get(&hash) {
+                None => {
+                    self.problem(&format!("chain: broken, {} is missing", encode(hash)));
+                    return Ok(None);
+                }
+                Some(sig) => {
+                    chain.push(hash);
+                    if sig.prev_sig == [0; 32] {
+                        break;
+                    }
+                    hash = sig.prev_sig;
+                }
+            }
+        }
+        chain.reverse();
+
+        let mut orphans: Vec<_> = sigs.keys().filter(|h| !visited.contains(*h)).collect();
+        orphans.sort();
+        for hash in orphans {
+            self.problem(&format!("sig/{}: not part of the chain", encode(hash)));
+        }
+        Ok(Some(chain))
+    }
+
+    fn check_log(&mut self, chain: &[[u8; 32]]) -> Result<(), Error> {
+        let p = self.dir("log");
+        let mut data = Vec::new();
+        if p.exists() {
+            File::open(&p)?.read_to_end(&mut data)?;
+        }
+        if data != chain.concat() {
+            self.problem("log: does not match the signature chain");
+            if self.repair {
+                write_atomic(&p, &chain.concat())?;
+                self.fixed("rebuilt log from chain");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{key, sign, test_dir, user};
+    use std::collections::BTreeMap;
+    use std::fs::{copy, read, remove_dir_all, write, OpenOptions};
+    use std::io::Write;
+
+    // Storage of one user with three signatures, two of them on the same object
+    fn storage(name: &str) -> (PathBuf, Vec<Signature>) {
+        let dir = test_dir(name);
+        let mut storage = LocalStorage::new(&dir).unwrap();
+        let alice = key();
+        storage.set_user(user("alice", &alice)).unwrap();
+        let mut sigs: Vec<Signature> = Vec::new();
+        for obj in &[[1; 32], [1; 32], [2; 32]] {
+            let prev = sigs.last().map_or([0; 32], |s| s.hash());
+            let sig = sign(&alice, "alice", *obj, prev);
+            storage.add_sig(prev, sig.clone()).unwrap();
+            sigs.push(sig);
+        }
+        (dir, sigs)
+    }
+
+    // Every file and directory below `dir` with its contents
+    fn snapshot(dir: &Path) -> BTreeMap<PathBuf, Option<Vec<u8>>> {
+        let mut files = BTreeMap::new();
+        for entry in read_dir(dir).unwrap() {
+            let p = entry.unwrap().path();
+            if p.is_dir() {
+                files.extend(snapshot(&p));
+                files.insert(p, None);
+            } else {
+                files.insert(p.clone(), Some(read(p).unwrap()));
+            }
+        }
+        files
+    }
+
+    // Runs a check and asserts it left the tree untouched
+    fn check(dir: &Path) -> usize {
+        let before = snapshot(dir);
+        let problems = run(dir, false).unwrap();
+        assert_eq!(snapshot(dir), before);
+        problems
+    }
+
+    #[test]
+    fn clean_storage() {
+        let (dir, _) = storage("fsck-clean");
+        assert_eq!(check(&dir), 0);
+        assert_eq!(run(&dir, true).unwrap(), 0);
+
+        // Storage created before withdrawals existed
+        remove_dir_all(dir.join("withdrawals")).unwrap();
+        remove_dir_all(dir.join("files")).unwrap();
+        assert_eq!(check(&dir), 0);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn torn_record() {
+        let (dir, sigs) = storage("fsck-torn");
+        let index = dir.join("obj").join(encode([1; 32]));
+        let mut file = OpenOptions::new().append(true).open(&index).unwrap();
+        file.write_all(&[0; 5]).unwrap();
+        let user = dir.join("user").join(encode(sha256(b"alice")));
+        let data = read(&user).unwrap();
+        write(&user, &data[..3]).unwrap();
+
+        // The index and the user, and every signature by the now unknown user
+        assert_eq!(check(&dir), 5);
+        assert_eq!(run(&dir, true).unwrap(), 3);
+        assert_eq!(
+            read(&index).unwrap(),
+            [sigs[0].hash(), sigs[1].hash()].concat()
+        );
+        assert!(!user.exists());
+        let lost = dir
+            .join("lost+found")
+            .join(format!("user-{}", encode(sha256(b"alice"))));
+        assert_eq!(read(lost).unwrap(), &data[..3]);
+        assert_eq!(check(&dir), 3);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dangling_sig() {
+        let (dir, sigs) = storage("fsck-dangling");
+        let index = dir.join("obj").join(encode([2; 32]));
+        let mut file = OpenOptions::new().append(true).open(&index).unwrap();
+        file.write_all(&[9; 32]).unwrap();
+        let stray = dir.join("sig").join(encode([8; 32]));
+        copy(dir.join("sig").join(encode(sigs[0].hash())), &stray).unwrap();
+
+        assert_eq!(check(&dir), 2);
+        assert_eq!(run(&dir, true).unwrap(), 0);
+        assert_eq!(read(&index).unwrap(), sigs[2].hash());
+        assert!(!stray.exists());
+        assert!(dir
+            .join("lost+found")
+            .join(format!("sig-{}", encode([8; 32])))
+            .exists());
+        assert_eq!(check(&dir), 0);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn bad_chain_link() {
+        let (dir, sigs) = storage("fsck-chain");
+        let head = dir.join("prev_sig");
+        write(&head, [7; 32]).unwrap();
+
+        // Which signature the head should be is not guessed at
+        assert_eq!(check(&dir), 1);
+        assert_eq!(run(&dir, true).unwrap(), 1);
+        assert_eq!(read(&head).unwrap(), [7; 32]);
+
+        // Unless it is gone, then it is the only signature nothing points at
+        remove_file(&head).unwrap();
+        assert_eq!(check(&dir), 1);
+        assert_eq!(run(&dir, true).unwrap(), 0);
+        assert_eq!(read(&head).unwrap(), sigs[2].hash());
+        assert_eq!(check(&dir), 0);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stray_tmp() {
+        let (dir, _) = storage("fsck-tmp");
+        let tmp = dir.join("sig").join("partial.tmp");
+        write(&tmp, [1, 2, 3]).unwrap();
+
+        assert_eq!(check(&dir), 1);
+        assert_eq!(run(&dir, true).unwrap(), 0);
+        assert!(!tmp.exists());
+        assert_eq!(check(&dir), 0);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn leftover_journal() {
+        let (dir, sigs) = storage("fsck-journal");
+        let sig = sign(&key(), "alice", [3; 32], sigs[2].hash());
+        let mut record = sig.hash().to_vec();
+        sig.write_to(&mut record).unwrap();
+        let journal = dir.join("journal");
+
+        // A torn record was never acknowledged and is dropped
+        write(&journal, &record[..40]).unwrap();
+        assert_eq!(check(&dir), 1);
+        assert_eq!(run(&dir, true).unwrap(), 0);
+        assert!(!journal.exists());
+        assert!(!dir.join("sig").join(encode(sig.hash())).exists());
+        assert_eq!(check(&dir), 0);
+
+        // A complete one is replayed, the key is only reported afterwards
+        write(&journal, &record).unwrap();
+        assert_eq!(check(&dir), 1);
+        assert_eq!(run(&dir, true).unwrap(), 1);
+        assert!(!journal.exists());
+        assert_eq!(read(dir.join("prev_sig")).unwrap(), sig.hash());
+        let log = read(dir.join("log")).unwrap();
+        assert_eq!(
+            log,
+            [sigs[0].hash(), sigs[1].hash(), sigs[2].hash(), sig.hash()].concat()
+        );
+        assert_eq!(check(&dir), 1);
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,37 +1,172 @@
-use std::fs::{create_dir_all, File, OpenOptions};
-use std::io::{ErrorKind, Read, Write};
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 
 use hex::encode;
 use openssl::sha::sha256;
 
 use crate::error::Error;
-use crate::error::Error::{CorruptedMessage, CorruptedStorage, HashCollision, IOError};
-use openssl::hash::{Hasher, MessageDigest};
-use std::convert::TryInto;
+use crate::error::Error::{
+    CorruptedMessage, CorruptedStorage, HashCollision, IOError, StaleChainHead,
+};
+use crate::merkle::Log;
+use crate::storage::{KeyRotation, Object, Revocation, Signature, Storage, User, Withdrawal};
 
 pub struct LocalStorage {
     root: PathBuf,
+    log: Log,
 }
 
 impl LocalStorage {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut storage = Self::open_unchecked(path, true)?;
+        storage.recover()?;
+        storage.load_log()?;
+        Ok(storage)
+    }
+
+    /// Opens the storage without replaying the journal or loading the log,
+    /// used by fsck which has to cope with damaged storage. Missing
+    /// directories are only created if `create` is set.
+    pub fn open_unchecked<P: AsRef<Path>>(path: P, create: bool) -> Result<Self, Error> {
         let root = PathBuf::from(path.as_ref());
-        create_dir_all(root.join("user"))?;
-        create_dir_all(root.join("sig"))?;
-        create_dir_all(root.join("obj"))?;
-        create_dir_all(root.join("files"))?;
-        Ok(Self { root })
+        if create {
+            create_dir_all(root.join("user"))?;
+            create_dir_all(root.join("rotations"))?;
+            create_dir_all(root.join("revocations"))?;
+            create_dir_all(root.join("sig"))?;
+            create_dir_all(root.join("obj"))?;
+            create_dir_all(root.join("withdrawals"))?;
+            create_dir_all(root.join("files"))?;
+        }
+        Ok(Self {
+            root,
+            log: Log::new(),
+        })
     }
 
-    pub fn set_prev(&self, hash: [u8; 32]) -> Result<(), Error> {
-        let p = self.root.join("prev_sig");
-        let mut file = File::create(p)?;
-        file.write_all(&hash)?;
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Finishes a signature that was journaled but not completely written
+    pub fn recover(&self) -> Result<(), Error> {
+        for dir in &[
+            "",
+            "user",
+            "rotations",
+            "revocations",
+            "sig",
+            "obj",
+            "withdrawals",
+        ] {
+            for entry in read_dir(self.root.join(dir))? {
+                let p = entry?.path();
+                if p.extension() == Some(OsStr::new("tmp")) {
+                    remove_file(p)?;
+                }
+            }
+        }
+
+        let p = self.root.join("journal");
+        if !p.exists() {
+            return Ok(());
+        }
+        let mut record = Vec::new();
+        File::open(&p)?.read_to_end(&mut record)?;
+        // A torn record was never acknowledged to the client, drop it
+        if record.len() > 32 && sha256(&record[32..]) == record[..32] {
+            let sig_hash = record[..32].try_into()?;
+            let sig = Signature::read_from(&mut &record[32..])?;
+            self.apply_sig(sig_hash, &sig)?;
+        }
+        remove_file(p)?;
+        sync_dir(&self.root);
+        Ok(())
+    }
+
+    fn load_log(&mut self) -> Result<(), Error> {
+        let p = self.root.join("log");
+        let mut sigs = Vec::new();
+        if p.exists() {
+            let mut file = File::open(p)?;
+            let mut buf = [0; 32];
+            loop {
+                match file.read_exact(&mut buf) {
+                    Ok(_) => {
+                        sigs.push(buf);
+                    }
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                        break;
+                    }
+                    Err(e) => {
+                        return Err(IOError(e));
+                    }
+                }
+            }
+        } else if let Some(mut hash) = self.get_prev()? {
+            // Storage predates the log, rebuild it from the signature chain
+            loop {
+                let sig = self.get_sig(&hash)?.ok_or(CorruptedStorage)?;
+                sigs.push(hash);
+                if sig.prev_sig == [0; 32] {
+                    break;
+                }
+                hash = sig.prev_sig;
+            }
+            sigs.reverse();
+            write_atomic(&p, &sigs.concat())?;
+        }
+        for hash in sigs {
+            self.log.push(hash);
+        }
         Ok(())
     }
 
-    pub fn get_prev(&self) -> Result<Option<[u8; 32]>, Error> {
+    // The user file keeps the registered key, rotations/<user hash> holds
+    // every rotation since and is the only file written when rotating
+    pub fn rotations(&self, name: &str) -> Result<Vec<KeyRotation>, Error> {
+        read_records(&self.root.join("rotations").join(name), |r| {
+            KeyRotation::read_from(r)
+        })
+    }
+
+    pub fn revocations(&self, name: &str) -> Result<Vec<Revocation>, Error> {
+        read_records(&self.root.join("revocations").join(name), |r| {
+            Revocation::read_from(r)
+        })
+    }
+
+    // Indexed by object like obj/, withdrawals/<object hash> holds the
+    // withdrawals of its signatures
+    pub fn withdrawals(&self, name: &str) -> Result<Vec<Withdrawal>, Error> {
+        read_records(&self.root.join("withdrawals").join(name), |r| {
+            Withdrawal::read_from(r)
+        })
+    }
+
+    pub fn set_prev(&self, hash: [u8; 32]) -> Result<(), Error> {
+        write_atomic(&self.root.join("prev_sig"), &hash)
+    }
+
+    // Every step is idempotent, so it is safe to run again after a crash
+    fn apply_sig(&self, sig_hash: [u8; 32], sig: &Signature) -> Result<(), Error> {
+        let mut data = Vec::new();
+        sig.write_to(&mut data)?;
+        write_atomic(&self.root.join("sig").join(encode(sig_hash)), &data)?;
+        append_entry(&self.root.join("obj").join(encode(sig.obj)), &sig_hash)?;
+        append_entry(&self.root.join("log"), &sig_hash)?;
+        self.set_prev(sig_hash)
+    }
+}
+
+impl Storage for LocalStorage {
+    fn get_prev(&self) -> Result<Option<[u8; 32]>, Error> {
         let p = self.root.join("prev_sig");
         if !p.exists() {
             return Ok(None);
@@ -43,32 +178,72 @@ impl LocalStorage {
         Ok(Some(r))
     }
 
-    pub fn set_user(&mut self, u: User) -> Result<(), Error> {
+    fn set_user(&mut self, u: User) -> Result<(), Error> {
         let hash = sha256(&u.username);
         let filename = encode(hash);
         let p = self.root.join("user").join(filename);
         if p.exists() {
             return Err(HashCollision);
         }
-        let mut file = File::create(p)?;
-        u.write_to(&mut file)?;
-        Ok(())
+        let mut data = Vec::new();
+        u.write_to(&mut data)?;
+        write_atomic(&p, &data)
     }
 
-    pub fn get_user(&mut self, hash: &[u8]) -> Result<Option<User>, Error> {
+    fn get_user(&mut self, hash: &[u8]) -> Result<Option<User>, Error> {
         if hash.len() != 32 {
             return Err(CorruptedMessage);
         }
         let filename = encode(hash);
-        let p = self.root.join("user").join(filename);
+        let p = self.root.join("user").join(&filename);
         if !p.exists() {
             return Ok(None);
         }
         let mut file = File::open(p)?;
-        Ok(Some(User::read_from(&mut file)?))
+        let mut user = User::read_from(&mut file)?;
+        if let Some(rotation) = self.rotations(&filename)?.pop() {
+            user.key = rotation.new_key;
+        }
+        Ok(Some(user))
+    }
+
+    fn rotate_key(&mut self, rotation: KeyRotation) -> Result<(), Error> {
+        let name = encode(sha256(&rotation.username));
+        let mut record = Vec::new();
+        rotation.write_to(&mut record)?;
+        append_record(&self.root.join("rotations").join(name), &record)
+    }
+
+    fn get_key_history(&mut self, hash: &[u8]) -> Result<Option<Vec<KeyRotation>>, Error> {
+        if hash.len() != 32 {
+            return Err(CorruptedMessage);
+        }
+        let name = encode(hash);
+        if !self.root.join("user").join(&name).exists() {
+            return Ok(None);
+        }
+        Ok(Some(self.rotations(&name)?))
+    }
+
+    fn add_revocation(&mut self, revocation: Revocation) -> Result<(), Error> {
+        let name = encode(sha256(&revocation.username));
+        let mut record = Vec::new();
+        revocation.write_to(&mut record)?;
+        append_record(&self.root.join("revocations").join(name), &record)
+    }
+
+    fn get_revocations(&mut self, hash: &[u8]) -> Result<Option<Vec<Revocation>>, Error> {
+        if hash.len() != 32 {
+            return Err(CorruptedMessage);
+        }
+        let name = encode(hash);
+        if !self.root.join("user").join(&name).exists() {
+            return Ok(None);
+        }
+        Ok(Some(self.revocations(&name)?))
     }
 
-    pub fn get_obj(&mut self, hash: &[u8]) -> Result<Option<Object>, Error> {
+    fn get_obj(&mut self, hash: &[u8]) -> Result<Option<Object>, Error> {
         if hash.len() != 32 {
             return Err(CorruptedMessage);
         }
@@ -97,29 +272,57 @@ impl LocalStorage {
         Ok(Some(Object { sigs }))
     }
 
-    pub fn add_sig(&mut self, sig: Signature) -> Result<(), Error> {
-        let sig_hash = sig.hash()?;
-        let mut filename = encode(sig_hash);
-        let mut p = self.root.join("sig").join(filename);
-        if p.exists() {
+    fn add_withdrawal(&mut self, withdrawal: Withdrawal) -> Result<(), Error> {
+        let name = encode(withdrawal.obj);
+        let mut record = Vec::new();
+        withdrawal.write_to(&mut record)?;
+        append_record(&self.root.join("withdrawals").join(name), &record)
+    }
+
+    fn get_withdrawals(&mut self, hash: &[u8]) -> Result<Option<Vec<Withdrawal>>, Error> {
+        if hash.len() != 32 {
+            return Err(CorruptedMessage);
+        }
+        let name = encode(hash);
+        if !self.root.join("obj").join(&name).exists() {
+            return Ok(None);
+        }
+        Ok(Some(self.withdrawals(&name)?))
+    }
+
+    fn add_sig(&mut self, expected_prev: [u8; 32], sig: Signature) -> Result<(), Error> {
+        let journal = self.root.join("journal");
+        // Left behind by a signature that could not be written, only
+        // replaying it at the next start keeps the log in order
+        if journal.exists() {
+            return Err(CorruptedStorage);
+        }
+        if self.get_prev()?.unwrap_or([0; 32]) != expected_prev {
+            return Err(StaleChainHead);
+        }
+        let sig_hash = sig.hash();
+        if self.root.join("sig").join(encode(sig_hash)).exists() {
             return Err(HashCollision);
         }
-        let mut file = File::create(p)?;
-        sig.write_to(&mut file)?;
-
-        filename = encode(sig.obj);
-        p = self.root.join("obj").join(filename);
-        file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(p)?;
-        file.write_all(&sig_hash)?;
-        self.set_prev(sig_hash)?;
+
+        // The journal record makes the following steps all-or-nothing,
+        // recover() replays it if we crash before it is removed
+        let mut record = sig_hash.to_vec();
+        sig.write_to(&mut record)?;
+        write_atomic(&journal, &record)?;
+        if let Err(e) = self.apply_sig(sig_hash, &sig) {
+            // Some steps may be done already, finish or keep the journal
+            warn!("Failed to write signature, replaying journal: {:?}", e);
+            self.recover()?;
+        } else {
+            remove_file(journal)?;
+        }
+
+        self.log.push(sig_hash);
         Ok(())
     }
 
-    pub fn get_sig(&self, hash: &[u8]) -> Result<Option<Signature>, Error> {
+    fn get_sig(&self, hash: &[u8]) -> Result<Option<Signature>, Error> {
         if hash.len() != 32 {
             return Err(CorruptedMessage);
         }
@@ -131,78 +334,220 @@ impl LocalStorage {
         let mut file = File::open(p)?;
         Ok(Some(Signature::read_from(&mut file)?))
     }
+
+    fn log(&self) -> &Log {
+        &self.log
+    }
 }
 
-pub struct User {
-    pub(crate) username: Vec<u8>,
-    pub(crate) key: Vec<u8>,
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Error> {
+    write_atomic_with(path, data, &OpenOptions::new())
 }
 
-impl User {
-    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
-        writer.write_all(&(self.key.len() as u32).to_le_bytes())?;
-        writer.write_all(&self.key)?;
-        writer.write_all(&self.username)?;
-        Ok(())
+// Like write_atomic, but the file is only accessible by its owner
+pub(crate) fn write_private(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    options.mode(0o600);
+    write_atomic_with(path, data, &options)
+}
+
+fn write_atomic_with(path: &Path, data: &[u8], options: &OpenOptions) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    // A leftover temporary file would keep its permissions
+    if tmp.exists() {
+        remove_file(&tmp)?;
     }
+    let mut file = options
+        .clone()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    rename(tmp, path)?;
+    if let Some(dir) = path.parent() {
+        sync_dir(dir);
+    }
+    Ok(())
+}
 
-    fn read_from<R: Read>(reader: &mut R) -> Result<Self, Error> {
-        let mut key = Vec::new();
-        let mut key_len_bytes = [0; 4];
-        reader.read_exact(&mut key_len_bytes)?;
-        let key_len = u32::from_le_bytes(key_len_bytes);
-        if key_len > 4096 {
-            return Err(CorruptedStorage);
+// Records of variable length are appended by rewriting the whole file,
+// so that a crash can't leave a partial record behind
+fn append_record(path: &Path, record: &[u8]) -> Result<(), Error> {
+    let mut data = Vec::new();
+    if path.exists() {
+        File::open(path)?.read_to_end(&mut data)?;
+    }
+    data.extend_from_slice(record);
+    write_atomic(path, &data)
+}
+
+fn read_records<T, F: Fn(&mut &[u8]) -> io::Result<T>>(
+    path: &Path,
+    read: F,
+) -> Result<Vec<T>, Error> {
+    let mut records = Vec::new();
+    if path.exists() {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        let mut reader = &data[..];
+        while !reader.is_empty() {
+            records.push(read(&mut reader)?);
         }
-        key.resize(key_len as usize, 0);
-        reader.read_exact(&mut key)?;
-        let mut username = Vec::new();
-        reader.read_to_end(&mut username)?;
-        Ok(Self { username, key })
     }
+    Ok(records)
 }
 
-pub struct Object {
-    pub(crate) sigs: Vec<[u8; 32]>,
+// Appends a 32 byte entry to an index file unless it is already its last entry,
+// a partially written entry left by a crash is cut off first
+pub(crate) fn append_entry(path: &Path, entry: &[u8; 32]) -> Result<(), Error> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(false)
+        .open(path)?;
+    let mut len = file.metadata()?.len();
+    if len % 32 != 0 {
+        len -= len % 32;
+        file.set_len(len)?;
+    }
+    if len >= 32 {
+        let mut last = [0; 32];
+        file.seek(SeekFrom::Start(len - 32))?;
+        file.read_exact(&mut last)?;
+        if &last == entry {
+            return Ok(());
+        }
+    }
+    file.seek(SeekFrom::Start(len))?;
+    file.write_all(entry)?;
+    file.sync_all()?;
+    Ok(())
 }
 
-pub struct Signature {
-    pub(crate) obj: [u8; 32],
-    pub(crate) user: [u8; 32],
-    pub(crate) prev_sig: [u8; 32],
-    pub(crate) signature: Vec<u8>,
+// Directories can't be opened on every platform, syncing them is best effort
+fn sync_dir(path: &Path) {
+    if let Ok(dir) = File::open(path) {
+        let _ = dir.sync_all();
+    }
 }
 
-impl Signature {
-    fn hash(&self) -> Result<[u8; 32], Error> {
-        let mut hasher = Hasher::new(MessageDigest::sha256())?;
-        self.write_to(&mut hasher)?;
-        let hash = hasher.finish()?;
-        Ok((&hash[..32]).try_into()?)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{key, sign, test_dir, user};
+    use std::fs::{read, remove_dir_all, write};
+
+    // Storage of one user with two signatures, and a third one to add
+    fn storage(name: &str) -> (PathBuf, Vec<Signature>, Signature) {
+        let dir = test_dir(name);
+        let mut storage = LocalStorage::new(&dir).unwrap();
+        let alice = key();
+        storage.set_user(user("alice", &alice)).unwrap();
+        let first = sign(&alice, "alice", [1; 32], [0; 32]);
+        let second = sign(&alice, "alice", [1; 32], first.hash());
+        storage.add_sig([0; 32], first.clone()).unwrap();
+        storage.add_sig(first.hash(), second.clone()).unwrap();
+        let third = sign(&alice, "alice", [1; 32], second.hash());
+        (dir, vec![first, second], third)
     }
 
-    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
-        writer.write_all(&self.obj)?;
-        writer.write_all(&self.user)?;
-        writer.write_all(&self.prev_sig)?;
-        writer.write_all(&self.signature)?;
-        Ok(())
+    fn journal(sig: &Signature) -> Vec<u8> {
+        let mut record = sig.hash().to_vec();
+        sig.write_to(&mut record).unwrap();
+        record
     }
 
-    fn read_from<R: Read>(reader: &mut R) -> Result<Self, Error> {
-        let mut obj = [0; 32];
-        reader.read_exact(&mut obj)?;
-        let mut user = [0; 32];
-        reader.read_exact(&mut user)?;
-        let mut prev_sig = [0; 32];
-        reader.read_exact(&mut prev_sig)?;
-        let mut signature = Vec::new();
-        reader.read_to_end(&mut signature)?;
-        Ok(Self {
-            obj,
-            user,
-            prev_sig,
-            signature,
-        })
+    #[test]
+    fn open_replays_journal_once() {
+        let (dir, sigs, sig) = storage("journal-replay");
+        // Crashed after the signature, the index entry and part of the log
+        // entry were written, but before the head was moved
+        write(dir.join("journal"), journal(&sig)).unwrap();
+        LocalStorage::open_unchecked(&dir, false)
+            .unwrap()
+            .apply_sig(sig.hash(), &sig)
+            .unwrap();
+        let log = [sigs[0].hash(), sigs[1].hash(), sig.hash()].concat();
+        write(dir.join("log"), &log[..80]).unwrap();
+        write(dir.join("prev_sig"), sigs[1].hash()).unwrap();
+
+        for _ in 0..2 {
+            let storage = LocalStorage::new(&dir).unwrap();
+            assert_eq!(storage.get_prev().unwrap(), Some(sig.hash()));
+            assert_eq!(storage.log().size(), 3);
+            assert!(!dir.join("journal").exists());
+        }
+        assert_eq!(read(dir.join("log")).unwrap(), log);
+        assert_eq!(read(dir.join("obj").join(encode([1; 32]))).unwrap(), log);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn open_drops_torn_journal_and_temporary_files() {
+        let (dir, sigs, sig) = storage("journal-torn");
+        write(dir.join("journal"), &journal(&sig)[..50]).unwrap();
+        let tmp = dir.join("sig").join(format!("{}.tmp", encode(sig.hash())));
+        write(&tmp, [1, 2, 3]).unwrap();
+        write(dir.join("log.tmp"), [4, 5, 6]).unwrap();
+
+        let storage = LocalStorage::new(&dir).unwrap();
+        assert_eq!(storage.get_prev().unwrap(), Some(sigs[1].hash()));
+        assert_eq!(storage.log().size(), 2);
+        assert!(!dir.join("journal").exists());
+        assert!(!tmp.exists());
+        assert!(!dir.join("log.tmp").exists());
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn add_sig_waits_for_recovery() {
+        let (dir, sigs, sig) = storage("journal-refuse");
+        let mut storage = LocalStorage::new(&dir).unwrap();
+        write(dir.join("journal"), journal(&sig)).unwrap();
+
+        let result = storage.add_sig(sigs[1].hash(), sig.clone());
+        assert!(matches!(result, Err(CorruptedStorage)));
+        assert_eq!(storage.get_prev().unwrap(), Some(sigs[1].hash()));
+
+        // The journaled signature is the head once replayed
+        storage.recover().unwrap();
+        assert_eq!(storage.get_prev().unwrap(), Some(sig.hash()));
+        let next = sign(&key(), "alice", [2; 32], sig.hash());
+        let result = storage.add_sig(sigs[1].hash(), next.clone());
+        assert!(matches!(result, Err(StaleChainHead)));
+        storage.add_sig(sig.hash(), next).unwrap();
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn append_entry_cuts_torn_entry() {
+        let dir = test_dir("append-entry");
+        let p = dir.join("index");
+        append_entry(&p, &[1; 32]).unwrap();
+        let mut data = read(&p).unwrap();
+        data.extend_from_slice(&[2; 7]);
+        write(&p, &data).unwrap();
+
+        append_entry(&p, &[2; 32]).unwrap();
+        append_entry(&p, &[2; 32]).unwrap();
+        assert_eq!(read(&p).unwrap(), [[1; 32], [2; 32]].concat());
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn append_record_replaces_temporary_file() {
+        let dir = test_dir("append-record");
+        let p = dir.join("records");
+        write(dir.join("records.tmp"), [9; 100]).unwrap();
+
+        append_record(&p, b"first").unwrap();
+        append_record(&p, b"second").unwrap();
+        assert_eq!(read(&p).unwrap(), b"firstsecond");
+        assert!(!dir.join("records.tmp").exists());
+        remove_dir_all(dir).unwrap();
     }
 }
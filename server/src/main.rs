@@ -1,20 +1,165 @@
+use std::env::args;
+use std::net::TcpListener;
+use std::process::exit;
+use std::sync::Arc;
+use std::thread::spawn;
+
+use fver_proto::{tls_acceptor, Connection};
+use openssl::ssl::SslAcceptor;
 use simpletcp::simpletcp::TcpServer;
 
+use crate::config::{Config, USAGE};
+use crate::error::Error::InvalidConfig;
+use crate::storage::Backend;
 use crate::threadpool::Server;
 
+#[macro_use]
+mod log;
+mod checkpoint;
+mod config;
 mod error;
+mod fsck;
 mod localstorage;
+mod merkle;
+mod sqlitestorage;
+mod storage;
+#[cfg(test)]
+mod testutil;
 mod threadpool;
 
+enum Listener {
+    Plain(TcpServer),
+    Tls(TcpListener, Arc<SslAcceptor>),
+}
+
+impl Listener {
+    fn accept(&self) -> Option<Connection> {
+        match self {
+            Listener::Plain(server) => server.accept_blocking().ok().map(Connection::from),
+            Listener::Tls(listener, acceptor) => {
+                let (socket, addr) = listener.accept().ok()?;
+                match Connection::accept_tls(acceptor, socket) {
+                    Ok(client) => Some(client),
+                    Err(e) => {
+                        debug!("TLS handshake with {} failed: {:?}", addr, e);
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
+
 fn main() {
-    let mut pool = Server::new(8, "storage");
-    let server = TcpServer::new("0.0.0.0:37687").unwrap();
-    loop {
-        match server.accept_blocking() {
-            Ok(client) => {
-                pool.accept(client);
+    let mut args: Vec<String> = args().skip(1).collect();
+    if args.iter().any(|a| a == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let fsck = args.first().map(String::as_str) == Some("fsck");
+    if fsck {
+        args.remove(0);
+    }
+    let repair = match args.iter().position(|a| a == "--repair") {
+        Some(i) if fsck => {
+            args.remove(i);
+            true
+        }
+        _ => false,
+    };
+    let config = match Config::from_args(args.into_iter()) {
+        Ok(config) => config,
+        Err(InvalidConfig(e)) => {
+            eprintln!("{}\nRun with --help for usage.", e);
+            exit(2);
+        }
+        Err(e) => {
+            eprintln!("{:?}", e);
+            exit(2);
+        }
+    };
+    log::set_level(config.log_level);
+
+    if fsck {
+        if config.backend != Backend::Filesystem {
+            error!("fsck only supports the fs backend");
+            exit(2);
+        }
+        match fsck::run(&config.storage, repair) {
+            Ok(0) => {}
+            Ok(_) => exit(1),
+            Err(e) => {
+                error!("fsck failed: {:?}", e);
+                exit(2);
             }
-            _ => {}
         }
+        return;
+    }
+
+    let pool = match Server::new(
+        config.threads,
+        config.backend,
+        &config.storage,
+        config.enqueue_timeout,
+    ) {
+        Ok(pool) => Arc::new(pool),
+        Err(e) => {
+            error!(
+                "Failed to open storage '{}': {:?}",
+                config.storage.display(),
+                e
+            );
+            exit(1);
+        }
+    };
+
+    let acceptor = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => match tls_acceptor(cert, key) {
+            Ok(acceptor) => Some(Arc::new(acceptor)),
+            Err(e) => {
+                error!("Failed to load TLS certificate: {}", e);
+                exit(1);
+            }
+        },
+        _ => None,
+    };
+
+    let mut listeners = Vec::new();
+    for addr in &config.bind {
+        let listener = match &acceptor {
+            Some(acceptor) => TcpListener::bind(addr.as_str())
+                .map(|l| Listener::Tls(l, acceptor.clone()))
+                .map_err(|e| format!("{:?}", e)),
+            None => TcpServer::new(addr.as_str())
+                .map(Listener::Plain)
+                .map_err(|e| format!("{:?}", e)),
+        };
+        match listener {
+            Ok(listener) => {
+                info!(
+                    "Listening on {}{}",
+                    addr,
+                    if acceptor.is_some() { " (TLS)" } else { "" }
+                );
+                listeners.push(listener);
+            }
+            Err(e) => {
+                error!("Failed to bind {}: {}", addr, e);
+                exit(1);
+            }
+        }
+    }
+
+    let mut handles = Vec::new();
+    for listener in listeners {
+        let pool = pool.clone();
+        handles.push(spawn(move || loop {
+            if let Some(client) = listener.accept() {
+                pool.accept(client);
+            }
+        }));
+    }
+    for handle in handles {
+        let _ = handle.join();
     }
 }
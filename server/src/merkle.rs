@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use fver_proto::{leaf_hash, node_hash};
+use openssl::sha::Sha256;
+
+use crate::error::Error;
+use crate::error::Error::CorruptedMessage;
+
+// Largest power of two smaller than n
+fn split(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+/// Append-only log of signature hashes, kept in memory by every storage backend
+pub struct Log {
+    // levels[h][i] is the root of the complete subtree over leaves
+    // i * 2^h .. (i + 1) * 2^h, levels[0] are the leaves themselves.
+    // Every other subtree of a proof splits into O(log n) of these.
+    levels: Vec<Vec<[u8; 32]>>,
+    positions: HashMap<[u8; 32], usize>,
+}
+
+impl Log {
+    pub fn new() -> Self {
+        Self {
+            levels: vec![Vec::new()],
+            positions: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, sig_hash: [u8; 32]) {
+        self.positions.insert(sig_hash, self.size());
+        let mut hash = leaf_hash(&sig_hash);
+        let mut level = 0;
+        loop {
+            if level == self.levels.len() {
+                self.levels.push(Vec::new());
+            }
+            let hashes = &mut self.levels[level];
+            hashes.push(hash);
+            let n = hashes.len();
+            // A left child waits for its sibling
+            if n % 2 == 1 {
+                break;
+            }
+            hash = node_hash(&hashes[n - 2], &hashes[n - 1]);
+            level += 1;
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    pub fn position(&self, sig_hash: &[u8]) -> Option<usize> {
+        let sig_hash: [u8; 32] = sig_hash.try_into().ok()?;
+        self.positions.get(&sig_hash).copied()
+    }
+
+    pub fn root(&self, size: usize) -> Result<[u8; 32], Error> {
+        if size > self.size() {
+            return Err(CorruptedMessage);
+        }
+        Ok(self.subtree(0, size))
+    }
+
+    /// Audit path of leaf `index` in the tree of the first `size` leaves
+    pub fn inclusion_proof(&self, index: usize, size: usize) -> Result<Vec<[u8; 32]>, Error> {
+        if index >= size || size > self.size() {
+            return Err(CorruptedMessage);
+        }
+        let mut proof = Vec::new();
+        self.path(index, 0, size, &mut proof);
+        Ok(proof)
+    }
+
+    /// Proof that the tree of the first `first` leaves is a prefix of the tree of the first `second`
+    pub fn consistency_proof(&self, first: usize, second: usize) -> Result<Vec<[u8; 32]>, Error> {
+        if first > second || second > self.size() {
+            return Err(CorruptedMessage);
+        }
+        let mut proof = Vec::new();
+        if first > 0 && first < second {
+            self.subproof(first, 0, second, true, &mut proof);
+        }
+        Ok(proof)
+    }
+
+    // Root of the tree over leaves start..end. Subtrees of RFC 6962 trees
+    // start at a multiple of their size when that is a power of two.
+    fn subtree(&self, start: usize, end: usize) -> [u8; 32] {
+        let n = end - start;
+        if n == 0 {
+            return Sha256::new().finish();
+        }
+        if n.is_power_of_two() {
+            let level = n.trailing_zeros() as usize;
+            return self.levels[level][start >> level];
+        }
+        let k = split(n);
+        node_hash(
+            &self.subtree(start, start + k),
+            &self.subtree(start + k, end),
+        )
+    }
+
+    fn path(&self, index: usize, start: usize, end: usize, proof: &mut Vec<[u8; 32]>) {
+        let n = end - start;
+        if n <= 1 {
+            return;
+        }
+        let k = split(n);
+        if index < k {
+            self.path(index, start, start + k, proof);
+            proof.push(self.subtree(start + k, end));
+        } else {
+            self.path(index - k, start + k, end, proof);
+            proof.push(self.subtree(start, start + k));
+        }
+    }
+
+    fn subproof(
+        &self,
+        m: usize,
+        start: usize,
+        end: usize,
+        complete: bool,
+        proof: &mut Vec<[u8; 32]>,
+    ) {
+        let n = end - start;
+        if m == n {
+            if !complete {
+                proof.push(self.subtree(start, end));
+            }
+            return;
+        }
+        let k = split(n);
+        if m <= k {
+            self.subproof(m, start, start + k, complete, proof);
+            proof.push(self.subtree(start + k, end));
+        } else {
+            self.subproof(m - k, start + k, end, false, proof);
+            proof.push(self.subtree(start, start + k));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fver_proto::{leaf_hash, node_hash, verify_consistency, verify_inclusion};
+
+    use super::{split, Log};
+
+    fn log(size: usize) -> Log {
+        let mut log = Log::new();
+        for i in 0..size {
+            log.push([i as u8; 32]);
+        }
+        log
+    }
+
+    // Root computed straight from the leaves, without the cached subtrees
+    fn naive_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+        match leaves.len() {
+            0 => openssl::sha::Sha256::new().finish(),
+            1 => leaf_hash(&leaves[0]),
+            n => {
+                let k = split(n);
+                node_hash(&naive_root(&leaves[..k]), &naive_root(&leaves[k..]))
+            }
+        }
+    }
+
+    #[test]
+    fn roots_match_the_leaves() {
+        let log = log(20);
+        let leaves: Vec<[u8; 32]> = (0..20).map(|i| [i as u8; 32]).collect();
+        for size in 0..=20 {
+            assert_eq!(log.root(size).unwrap(), naive_root(&leaves[..size]));
+        }
+        assert!(log.root(21).is_err());
+    }
+
+    #[test]
+    fn inclusion_proofs_verify() {
+        for size in 1..=20 {
+            let log = log(size);
+            let root = log.root(size).unwrap();
+            for index in 0..size {
+                let leaf = leaf_hash(&[index as u8; 32]);
+                let proof = log.inclusion_proof(index, size).unwrap();
+                assert!(verify_inclusion(
+                    &leaf,
+                    index as u64,
+                    size as u64,
+                    &proof,
+                    &root
+                ));
+            }
+            assert!(log.inclusion_proof(size, size).is_err());
+        }
+    }
+
+    #[test]
+    fn tampered_inclusion_proofs_fail() {
+        for size in 2..=20 {
+            let log = log(size);
+            let root = log.root(size).unwrap();
+            for index in 0..size {
+                let leaf = leaf_hash(&[index as u8; 32]);
+                let proof = log.inclusion_proof(index, size).unwrap();
+                let (index, size) = (index as u64, size as u64);
+                for i in 0..proof.len() {
+                    let mut flipped = proof.clone();
+                    flipped[i][0] ^= 1;
+                    assert!(!verify_inclusion(&leaf, index, size, &flipped, &root));
+                }
+                let other = (index + 1) % size;
+                assert!(!verify_inclusion(&leaf, other, size, &proof, &root));
+                let truncated = &proof[..proof.len() - 1];
+                assert!(!verify_inclusion(&leaf, index, size, truncated, &root));
+                let mut extended = proof.clone();
+                extended.push(root);
+                assert!(!verify_inclusion(&leaf, index, size, &extended, &root));
+            }
+        }
+    }
+
+    #[test]
+    fn consistency_proofs_verify() {
+        let log = log(20);
+        for second in 1..=20 {
+            let second_root = log.root(second).unwrap();
+            for first in 1..=second {
+                let first_root = log.root(first).unwrap();
+                let proof = log.consistency_proof(first, second).unwrap();
+                assert!(verify_consistency(
+                    first as u64,
+                    second as u64,
+                    &proof,
+                    &first_root,
+                    &second_root
+                ));
+                for i in 0..proof.len() {
+                    let mut flipped = proof.clone();
+                    flipped[i][0] ^= 1;
+                    assert!(!verify_consistency(
+                        first as u64,
+                        second as u64,
+                        &flipped,
+                        &first_root,
+                        &second_root
+                    ));
+                }
+            }
+        }
+        assert!(log.consistency_proof(2, 1).is_err());
+        assert!(log.consistency_proof(1, 21).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+use std::convert::TryInto;
+use std::fs::create_dir_all;
+use std::path::Path;
+
+use openssl::sha::sha256;
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, TransactionBehavior};
+
+use crate::error::Error;
+use crate::error::Error::{CorruptedMessage, HashCollision, StaleChainHead};
+use crate::merkle::Log;
+use crate::storage::{KeyRotation, Object, Revocation, Signature, Storage, User, Withdrawal};
+
+// Everything lives in a single database file, the head of the chain
+// is the signature with the highest sequence number.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        hash BLOB PRIMARY KEY,
+        username BLOB NOT NULL,
+        key BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS key_rotations (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        user BLOB NOT NULL,
+        username BLOB NOT NULL,
+        old_key BLOB NOT NULL,
+        new_key BLOB NOT NULL,
+        since INTEGER NOT NULL,
+        old_signature BLOB NOT NULL,
+        new_signature BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS key_rotations_user ON key_rotations (user);
+    CREATE TABLE IF NOT EXISTS revocations (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        user BLOB NOT NULL,
+        username BLOB NOT NULL,
+        key BLOB NOT NULL,
+        since INTEGER NOT NULL,
+        signature BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS revocations_user ON revocations (user);
+    CREATE TABLE IF NOT EXISTS sigs (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        hash BLOB NOT NULL UNIQUE,
+        obj BLOB NOT NULL,
+        user BLOB NOT NULL,
+        prev_sig BLOB NOT NULL,
+        signature BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS sigs_obj ON sigs (obj);
+    CREATE TABLE IF NOT EXISTS withdrawals (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        sig BLOB NOT NULL UNIQUE,
+        obj BLOB NOT NULL,
+        user BLOB NOT NULL,
+        reason BLOB NOT NULL,
+        signature BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS withdrawals_obj ON withdrawals (obj);
+";
+
+pub struct SqliteStorage {
+    conn: Connection,
+    log: Log,
+}
+
+impl SqliteStorage {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        create_dir_all(&path)?;
+        let conn = Connection::open(path.as_ref().join("fver.db"))?;
+        conn.execute_batch(SCHEMA)?;
+
+        let mut log = Log::new();
+        {
+            let mut stmt = conn.prepare("SELECT hash FROM sigs ORDER BY seq")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let hash: Vec<u8> = row.get(0)?;
+                log.push(hash[..].try_into()?);
+            }
+        }
+        Ok(Self { conn, log })
+    }
+}
+
+fn head(conn: &Connection) -> Result<Option<[u8; 32]>, Error> {
+    let hash: Option<Vec<u8>> = conn
+        .query_row(
+            "SELECT hash FROM sigs ORDER BY seq DESC LIMIT 1",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    match hash {
+        None => Ok(None),
+        Some(hash) => Ok(Some(hash[..].try_into()?)),
+    }
+}
+
+fn is_constraint_violation(e: &rusqlite::Error) -> bool {
+    matches!(e, rusqlite::Error::SqliteFailure(e, _) if e.code == ErrorCode::ConstraintViolation)
+}
+
+impl Storage for SqliteStorage {
+    fn get_prev(&self) -> Result<Option<[u8; 32]>, Error> {
+        head(&self.conn)
+    }
+
+    fn set_user(&mut self, u: User) -> Result<(), Error> {
+        let hash = sha256(&u.username);
+        match self.conn.execute(
+            "INSERT INTO users (hash, username, key) VALUES (?1, ?2, ?3)",
+            params![&hash[..], u.username, u.key],
+        ) {
+            Ok(_) => Ok(()),
+            Err(e) if is_constraint_violation(&e) => Err(HashCollision),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_user(&mut self, hash: &[u8]) -> Result<Option<User>, Error> {
+        if hash.len() != 32 {
+            return Err(CorruptedMessage);
+        }
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT username, key FROM users WHERE hash = ?1",
+                [hash],
+                |row| {
+                    Ok(User {
+                        username: row.get(0)?,
+                        key: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    fn rotate_key(&mut self, rotation: KeyRotation) -> Result<(), Error> {
+        let hash = sha256(&rotation.username);
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "UPDATE users SET key = ?1 WHERE hash = ?2",
+            params![rotation.new_key, &hash[..]],
+        )?;
+        tx.execute(
+            "INSERT INTO key_rotations (user, username, old_key, new_key, since, old_signature, new_signature)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                &hash[..],
+                rotation.username,
+                rotation.old_key,
+                rotation.new_key,
+                rotation.since as i64,
+                rotation.old_signature,
+                rotation.new_signature
+            ],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_key_history(&mut self, hash: &[u8]) -> Result<Option<Vec<KeyRotation>>, Error> {
+        if self.get_user(hash)?.is_none() {
+            return Ok(None);
+        }
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT username, old_key, new_key, since, old_signature, new_signature
+             FROM key_rotations WHERE user = ?1 ORDER BY seq",
+        )?;
+        let mut rows = stmt.query([hash])?;
+        let mut rotations = Vec::new();
+        while let Some(row) = rows.next()? {
+            rotations.push(KeyRotation {
+                username: row.get(0)?,
+                old_key: row.get(1)?,
+                new_key: row.get(2)?,
+                since: row.get::<_, i64>(3)? as u64,
+                old_signature: row.get(4)?,
+                new_signature: row.get(5)?,
+            });
+        }
+        Ok(Some(rotations))
+    }
+
+    fn add_revocation(&mut self, revocation: Revocation) -> Result<(), Error> {
+        let hash = sha256(&revocation.username);
+        self.conn.execute(
+            "INSERT INTO revocations (user, username, key, since, signature) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                &hash[..],
+                revocation.username,
+                revocation.key,
+                revocation.since as i64,
+                revocation.signature
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_revocations(&mut self, hash: &[u8]) -> Result<Option<Vec<Revocation>>, Error> {
+        if self.get_user(hash)?.is_none() {
+            return Ok(None);
+        }
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT username, key, since, signature FROM revocations WHERE user = ?1 ORDER BY seq",
+        )?;
+        let mut rows = stmt.query([hash])?;
+        let mut revocations = Vec::new();
+        while let Some(row) = rows.next()? {
+            revocations.push(Revocation {
+                username: row.get(0)?,
+                key: row.get(1)?,
+                since: row.get::<_, i64>(2)? as u64,
+                signature: row.get(3)?,
+            });
+        }
+        Ok(Some(revocations))
+    }
+
+    fn get_obj(&mut self, hash: &[u8]) -> Result<Option<Object>, Error> {
+        if hash.len() != 32 {
+            return Err(CorruptedMessage);
+        }
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT hash FROM sigs WHERE obj = ?1 ORDER BY seq")?;
+        let mut rows = stmt.query([hash])?;
+        let mut sigs = Vec::new();
+        while let Some(row) = rows.next()? {
+            let sig: Vec<u8> = row.get(0)?;
+            sigs.push(sig[..].try_into()?);
+        }
+        if sigs.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Object { sigs }))
+    }
+
+    fn add_withdrawal(&mut self, withdrawal: Withdrawal) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT INTO withdrawals (sig, obj, user, reason, signature) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                &withdrawal.sig[..],
+                &withdrawal.obj[..],
+                &withdrawal.user[..],
+                withdrawal.reason,
+                withdrawal.signature
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_withdrawals(&mut self, hash: &[u8]) -> Result<Option<Vec<Withdrawal>>, Error> {
+        if self.get_obj(hash)?.is_none() {
+            return Ok(None);
+        }
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT sig, obj, user, reason, signature FROM withdrawals WHERE obj = ?1 ORDER BY seq",
+        )?;
+        let mut rows = stmt.query([hash])?;
+        let mut withdrawals = Vec::new();
+        while let Some(row) = rows.next()? {
+            let (sig, obj, user): (Vec<u8>, Vec<u8>, Vec<u8>) =
+                (row.get(0)?, row.get(1)?, row.get(2)?);
+            withdrawals.push(Withdrawal {
+                sig: sig[..].try_into()?,
+                obj: obj[..].try_into()?,
+                user: user[..].try_into()?,
+                reason: row.get(3)?,
+                signature: row.get(4)?,
+            });
+        }
+        Ok(Some(withdrawals))
+    }
+
+    fn add_sig(&mut self, expected_prev: [u8; 32], sig: Signature) -> Result<(), Error> {
+        let sig_hash = sig.hash();
+        // IMMEDIATE takes the write lock up front, so the head can't move
+        // between the check and the insert
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if head(&tx)?.unwrap_or([0; 32]) != expected_prev {
+            return Err(StaleChainHead);
+        }
+        match tx.execute(
+            "INSERT INTO sigs (hash, obj, user, prev_sig, signature) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                &sig_hash[..],
+                &sig.obj[..],
+                &sig.user[..],
+                &sig.prev_sig[..],
+                sig.signature
+            ],
+        ) {
+            Ok(_) => {}
+            Err(e) if is_constraint_violation(&e) => return Err(HashCollision),
+            Err(e) => return Err(e.into()),
+        }
+        tx.commit()?;
+
+        self.log.push(sig_hash);
+        Ok(())
+    }
+
+    fn get_sig(&self, hash: &[u8]) -> Result<Option<Signature>, Error> {
+        if hash.len() != 32 {
+            return Err(CorruptedMessage);
+        }
+        let row = self
+            .conn
+            .query_row(
+                "SELECT obj, user, prev_sig, signature FROM sigs WHERE hash = ?1",
+                [hash],
+                |row| {
+                    Ok((
+                        row.get::<_, Vec<u8>>(0)?,
+                        row.get::<_, Vec<u8>>(1)?,
+                        row.get::<_, Vec<u8>>(2)?,
+                        row.get::<_, Vec<u8>>(3)?,
+                    ))
+                },
+            )
+            .optional()?;
+        match row {
+            None => Ok(None),
+            Some((obj, user, prev_sig, signature)) => Ok(Some(Signature {
+                obj: obj[..].try_into()?,
+                user: user[..].try_into()?,
+                prev_sig: prev_sig[..].try_into()?,
+                signature,
+            })),
+        }
+    }
+
+    fn log(&self) -> &Log {
+        &self.log
+    }
+}
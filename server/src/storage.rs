@@ -0,0 +1,184 @@
+use std::path::Path;
+
+pub use fver_proto::{KeyRotation, Revocation, Signature, User, Withdrawal};
+
+use crate::error::Error;
+use crate::localstorage::LocalStorage;
+use crate::merkle::Log;
+use crate::sqlitestorage::SqliteStorage;
+
+pub trait Storage: Send {
+    fn get_prev(&self) -> Result<Option<[u8; 32]>, Error>;
+
+    fn set_user(&mut self, u: User) -> Result<(), Error>;
+
+    /// The user with their current key
+    fn get_user(&mut self, hash: &[u8]) -> Result<Option<User>, Error>;
+
+    /// Makes `rotation.new_key` the current key of the user, the rotation
+    /// has to be checked by the caller
+    fn rotate_key(&mut self, rotation: KeyRotation) -> Result<(), Error>;
+
+    /// Rotations of the user's key, oldest first, `None` if the user is unknown
+    fn get_key_history(&mut self, hash: &[u8]) -> Result<Option<Vec<KeyRotation>>, Error>;
+
+    /// Stores a revocation checked by the caller
+    fn add_revocation(&mut self, revocation: Revocation) -> Result<(), Error>;
+
+    /// Revocations of the user's keys in the order they were added, `None` if the user is unknown
+    fn get_revocations(&mut self, hash: &[u8]) -> Result<Option<Vec<Revocation>>, Error>;
+
+    fn get_obj(&mut self, hash: &[u8]) -> Result<Option<Object>, Error>;
+
+    /// Stores a withdrawal checked by the caller
+    fn add_withdrawal(&mut self, withdrawal: Withdrawal) -> Result<(), Error>;
+
+    /// Withdrawals of the object's signatures, `None` if the object is unknown
+    fn get_withdrawals(&mut self, hash: &[u8]) -> Result<Option<Vec<Withdrawal>>, Error>;
+
+    /// Appends `sig` to the chain, only if the chain head is still `expected_prev`
+    fn add_sig(&mut self, expected_prev: [u8; 32], sig: Signature) -> Result<(), Error>;
+
+    fn get_sig(&self, hash: &[u8]) -> Result<Option<Signature>, Error>;
+
+    /// Merkle log over all signatures in the order they were added
+    fn log(&self) -> &Log;
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Backend {
+    Filesystem,
+    Sqlite,
+}
+
+impl Backend {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "fs" => Some(Backend::Filesystem),
+            "sqlite" => Some(Backend::Sqlite),
+            _ => None,
+        }
+    }
+}
+
+pub fn open<P: AsRef<Path>>(backend: Backend, path: P) -> Result<Box<dyn Storage>, Error> {
+    Ok(match backend {
+        Backend::Filesystem => Box::new(LocalStorage::new(path)?),
+        Backend::Sqlite => Box::new(SqliteStorage::new(path)?),
+    })
+}
+
+pub struct Object {
+    pub(crate) sigs: Vec<[u8; 32]>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error::{HashCollision, StaleChainHead};
+    use crate::testutil::{key, sign, statement, test_dir, user, withdrawal};
+    use openssl::sha::sha256;
+    use std::fs::remove_dir_all;
+
+    // What every backend has to do the same way, checked again after reopening
+    fn contract(backend: Backend, name: &str) {
+        let dir = test_dir(name);
+        let mut storage = open(backend, &dir).unwrap();
+        let alice = key();
+        let hash = sha256(b"alice");
+
+        assert_eq!(storage.get_user(&hash).unwrap(), None);
+        assert_eq!(storage.get_key_history(&hash).unwrap(), None);
+        assert_eq!(storage.get_revocations(&hash).unwrap(), None);
+        storage.set_user(user("alice", &alice)).unwrap();
+        let result = storage.set_user(user("alice", &key()));
+        assert!(matches!(result, Err(HashCollision)));
+        assert_eq!(
+            storage.get_user(&hash).unwrap(),
+            Some(user("alice", &alice))
+        );
+        assert_eq!(storage.get_key_history(&hash).unwrap(), Some(Vec::new()));
+        assert_eq!(storage.get_revocations(&hash).unwrap(), Some(Vec::new()));
+
+        assert_eq!(storage.get_prev().unwrap(), None);
+        let first = sign(&alice, "alice", [1; 32], [0; 32]);
+        let second = sign(&alice, "alice", [1; 32], first.hash());
+        storage.add_sig([0; 32], first.clone()).unwrap();
+        let result = storage.add_sig([0; 32], second.clone());
+        assert!(matches!(result, Err(StaleChainHead)));
+        storage.add_sig(first.hash(), second.clone()).unwrap();
+        let result = storage.add_sig(second.hash(), second.clone());
+        assert!(matches!(result, Err(HashCollision)));
+        assert_eq!(storage.get_prev().unwrap(), Some(second.hash()));
+        assert!(storage.get_obj(&[2; 32]).unwrap().is_none());
+        assert_eq!(storage.get_withdrawals(&[2; 32]).unwrap(), None);
+
+        let next = key();
+        let mut rotation = KeyRotation {
+            username: b"alice".to_vec(),
+            old_key: alice.public_key_to_der().unwrap(),
+            new_key: next.public_key_to_der().unwrap(),
+            since: 2,
+            old_signature: Vec::new(),
+            new_signature: Vec::new(),
+        };
+        rotation.old_signature = statement(&alice, &rotation.signed_data());
+        rotation.new_signature = statement(&next, &rotation.signed_data());
+        storage.rotate_key(rotation.clone()).unwrap();
+
+        let mut revocations = Vec::new();
+        for since in &[1, 0] {
+            let mut revocation = Revocation {
+                username: b"alice".to_vec(),
+                key: alice.public_key_to_der().unwrap(),
+                since: *since,
+                signature: Vec::new(),
+            };
+            revocation.signature = statement(&next, &revocation.signed_data());
+            storage.add_revocation(revocation.clone()).unwrap();
+            revocations.push(revocation);
+        }
+
+        let withdrawals = vec![
+            withdrawal(&alice, &second, b"superseded"),
+            withdrawal(&alice, &first, b""),
+        ];
+        for w in &withdrawals {
+            storage.add_withdrawal(w.clone()).unwrap();
+        }
+
+        for _ in 0..2 {
+            assert_eq!(storage.get_user(&hash).unwrap(), Some(user("alice", &next)));
+            assert_eq!(
+                storage.get_key_history(&hash).unwrap(),
+                Some(vec![rotation.clone()])
+            );
+            assert_eq!(
+                storage.get_revocations(&hash).unwrap(),
+                Some(revocations.clone())
+            );
+            assert_eq!(
+                storage.get_withdrawals(&[1; 32]).unwrap(),
+                Some(withdrawals.clone())
+            );
+            assert_eq!(storage.get_sig(&first.hash()).unwrap(), Some(first.clone()));
+            assert_eq!(storage.get_sig(&[9; 32]).unwrap(), None);
+            let obj = storage.get_obj(&[1; 32]).unwrap().unwrap();
+            assert_eq!(obj.sigs, vec![first.hash(), second.hash()]);
+            assert_eq!(storage.log().size(), 2);
+            assert_eq!(storage.log().position(&second.hash()), Some(1));
+            storage = open(backend, &dir).unwrap();
+        }
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn local_storage() {
+        contract(Backend::Filesystem, "contract-fs");
+    }
+
+    #[test]
+    fn sqlite_storage() {
+        contract(Backend::Sqlite, "contract-sqlite");
+    }
+}
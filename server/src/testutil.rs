@@ -0,0 +1,65 @@
+// Keys and records signed the way clients sign them, for the tests
+
+use std::env::temp_dir;
+use std::fs::{create_dir_all, remove_dir_all};
+use std::path::PathBuf;
+
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+
+use crate::storage::{Signature, User, Withdrawal};
+
+/// Empty directory unique to the test process and `name`
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = temp_dir().join(format!("fver-server-{}-{}", name, std::process::id()));
+    let _ = remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+pub fn user(username: &str, key: &PKey<Private>) -> User {
+    User {
+        username: username.as_bytes().to_vec(),
+        key: key.public_key_to_der().unwrap(),
+    }
+}
+
+pub fn sign(key: &PKey<Private>, username: &str, obj: [u8; 32], prev_sig: [u8; 32]) -> Signature {
+    let mut signer = Signer::new_without_digest(key).unwrap();
+    signer.update(&obj).unwrap();
+    signer.update(&prev_sig).unwrap();
+    Signature {
+        obj,
+        user: sha256(username.as_bytes()),
+        prev_sig,
+        signature: signer.sign_to_vec().unwrap(),
+    }
+}
+
+/// Signature over `data` as made for rotations, revocations and withdrawals
+pub fn statement(key: &PKey<Private>, data: &[u8]) -> Vec<u8> {
+    let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+    signer.update(data).unwrap();
+    signer.sign_to_vec().unwrap()
+}
+
+pub fn withdrawal(key: &PKey<Private>, sig: &Signature, reason: &[u8]) -> Withdrawal {
+    let mut withdrawal = Withdrawal {
+        sig: sig.hash(),
+        obj: sig.obj,
+        user: sig.user,
+        reason: reason.to_vec(),
+        signature: Vec::new(),
+    };
+    withdrawal.signature = statement(key, &withdrawal.signed_data());
+    withdrawal
+}
@@ -1,53 +1,82 @@
-use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
 use std::path::Path;
+use std::slice::from_ref;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread::spawn;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use simpletcp::simpletcp::{Message, TcpStream};
-use simpletcp::utils::{get_fd_array, poll_set_timeout, EV_POLLIN};
+use fver_proto::{
+    capability, unsupported, AddSigStatus, Checkpoint, Connection, HelloResponse, HelloStatus,
+    InclusionProof, KeyRotation, Lookup, Payload, Request, Revocation, RevokeKeyStatus,
+    RotateKeyStatus, SetUserStatus, SignResponse, SignedCheckpoint, TreeHead, WithdrawSigStatus,
+    Withdrawal, MAX_FIELD_LEN,
+};
+use openssl::pkey::{PKey, Private};
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use simpletcp::simpletcp::Message;
+use simpletcp::utils::{get_fd_array, poll_set_ev_timeout, EV_POLLIN};
 
+use crate::checkpoint::load_key;
 use crate::error::Error;
-use crate::error::Error::CorruptedStorage;
-use crate::localstorage::{LocalStorage, Signature, User};
+use crate::error::Error::{
+    CorruptedStorage, InvalidSignature, RevokedKey, StaleChainHead, UnknownUser,
+};
+use crate::storage;
+use crate::storage::{Backend, Signature, Storage};
 use crate::threadpool::ClientAction::{Disconnect, Enqueue, Respond};
 use crate::threadpool::ThreadMessage::Accept;
 
+const CAPABILITIES: u64 = capability::TRANSPARENCY_LOG
+    | capability::SIGN_REQUEST
+    | capability::LEGACY_ENQUEUE
+    | capability::KEY_ROTATION
+    | capability::KEY_REVOCATION
+    | capability::SIG_WITHDRAWAL;
+
 pub struct Server {
     threads: Vec<Thread>,
-    next_accept: usize,
+    next_accept: AtomicUsize,
 }
 
 impl Server {
-    pub fn new<P: AsRef<Path>>(n: usize, path: P) -> Self {
+    pub fn new<P: AsRef<Path>>(
+        n: usize,
+        backend: Backend,
+        path: P,
+        enqueue_timeout: i32,
+    ) -> Result<Self, Error> {
         let (queue_tx, queue_rx) = channel();
         let mut threads = Vec::new();
-        let storage = Arc::new(Mutex::new(LocalStorage::new(path).unwrap()));
+        let storage = Arc::new(Mutex::new(storage::open(backend, &path)?));
+        let key = Arc::new(load_key(path.as_ref().join("key"))?);
         let storage_clone = storage.clone();
-        spawn(|| {
-            queue_loop(queue_rx, storage_clone);
+        spawn(move || {
+            queue_loop(queue_rx, storage_clone, enqueue_timeout);
         });
-        threads.resize_with(n, || Thread::new(storage.clone(), queue_tx.clone()));
-        Self {
-            threads,
-            next_accept: 0,
+        for _ in 0..n {
+            threads.push(Thread::new(storage.clone(), key.clone(), queue_tx.clone())?);
         }
+        Ok(Self {
+            threads,
+            next_accept: AtomicUsize::new(0),
+        })
     }
 
-    pub fn accept(&mut self, client: TcpStream) {
-        self.threads[self.next_accept]
-            .tx
-            .send(Accept(client))
-            .unwrap();
-        self.next_accept += 1;
-        if self.next_accept == self.threads.len() {
-            self.next_accept = 0;
-        }
+    pub fn accept(&self, client: Connection) {
+        let n = self.next_accept.fetch_add(1, Ordering::Relaxed) % self.threads.len();
+        let thread = &self.threads[n];
+        thread.tx.send(Accept(client)).unwrap();
+        // WouldBlock means the thread has not consumed the previous wakeup yet
+        let _ = (&thread.wake).write(&[0]);
     }
 }
 
 enum ThreadMessage {
-    Accept(TcpStream),
+    Accept(Connection),
 }
 
 enum ClientAction {
@@ -57,58 +86,80 @@ enum ClientAction {
     None,
 }
 
-macro_rules! c_try {
-    ($expr:expr) => {
-        match $expr {
-            Ok(v) => v,
-            Err(_) => {
-                return Disconnect;
-            }
-        }
-    };
-}
-
 struct Thread {
     tx: Sender<ThreadMessage>,
+    wake: UnixStream,
 }
 
 impl Thread {
-    fn new(storage: Arc<Mutex<LocalStorage>>, queue_tx: Sender<ThreadMessage>) -> Self {
+    fn new(
+        storage: Arc<Mutex<Box<dyn Storage>>>,
+        key: Arc<PKey<Private>>,
+        queue_tx: Sender<ThreadMessage>,
+    ) -> Result<Self, Error> {
         let (tx, rx) = channel();
+        let (wake, wake_rx) = wake_pair()?;
         spawn(|| {
-            thread_loop(rx, storage, queue_tx);
+            thread_loop(rx, wake_rx, storage, key, queue_tx);
         });
-        Self { tx }
+        Ok(Self { tx, wake })
     }
 }
 
+// Socket pair used to interrupt a worker blocked in poll
+// when a new client is sent to it.
+fn wake_pair() -> Result<(UnixStream, UnixStream), Error> {
+    let (tx, rx) = UnixStream::pair()?;
+    tx.set_nonblocking(true)?;
+    rx.set_nonblocking(true)?;
+    Ok((tx, rx))
+}
+
 fn thread_loop(
     rx: Receiver<ThreadMessage>,
-    storage: Arc<Mutex<LocalStorage>>,
+    wake: UnixStream,
+    storage: Arc<Mutex<Box<dyn Storage>>>,
+    key: Arc<PKey<Private>>,
     queue_tx: Sender<ThreadMessage>,
 ) {
+    let fd_set = |clients: &Vec<Connection>| {
+        let mut fds = get_fd_array(clients);
+        fds.append(&mut get_fd_array(from_ref(&wake)));
+        fds
+    };
     let mut clients = Vec::new();
-    let mut fds = Vec::new();
+    let mut challenges = Vec::new();
+    let mut fds = fd_set(&clients);
     loop {
-        match rx.try_recv() {
-            Ok(m) => match m {
-                Accept(client) => {
+        // Messages OpenSSL has buffered already don't wake up poll
+        let ready = match clients.iter().position(Connection::pending) {
+            Some(n) => Some(n as i32),
+            None => {
+                let mut events: Vec<_> = clients.iter().map(Connection::event).collect();
+                events.push(EV_POLLIN);
+                poll_set_ev_timeout(&mut fds, &mut events, -1)
+            }
+        };
+        match ready {
+            None => {}
+            Some(n) if n as usize == clients.len() => {
+                let mut buf = [0; 64];
+                while let Ok(1..) = (&wake).read(&mut buf) {}
+                while let Ok(Accept(client)) = rx.try_recv() {
                     clients.push(client);
-                    fds = get_fd_array(&clients);
+                    challenges.push(None);
                 }
-            },
-            Err(_) => {}
-        }
-        match poll_set_timeout(&mut fds, EV_POLLIN, 50) {
-            None => {}
+                fds = fd_set(&clients);
+            }
             Some(n) => {
+                let n = n as usize;
                 let mut action = ClientAction::None;
-                let client = &mut clients[n as usize];
+                let client = &mut clients[n];
                 match client.read() {
                     Ok(m) => match m {
                         None => {}
                         Some(m) => {
-                            action = process_message(m, &storage);
+                            action = process_message(m, &mut challenges[n], &storage, &key);
                         }
                     },
                     Err(e) => match e {
@@ -124,26 +175,26 @@ fn thread_loop(
                     },
                 }
                 match action {
-                    Respond(m) => match client.write(&m) {
+                    Respond(m) => match client.write(m) {
                         Ok(_) => {}
                         Err(_) => {
-                            clients.remove(n as usize);
-                            fds = get_fd_array(&clients);
+                            clients.remove(n);
+                            challenges.remove(n);
+                            fds = fd_set(&clients);
                         }
                     },
                     Enqueue => {
-                        let client = clients.remove(n as usize);
-                        fds = get_fd_array(&clients);
-                        match queue_tx.send(Accept(client)) {
-                            Err(e) => {
-                                println!("Failed to send to queue thread: {}", e);
-                            }
-                            _ => {}
+                        let client = clients.remove(n);
+                        challenges.remove(n);
+                        fds = fd_set(&clients);
+                        if let Err(e) = queue_tx.send(Accept(client)) {
+                            error!("Failed to send to queue thread: {}", e);
                         }
                     }
                     Disconnect => {
-                        clients.remove(n as usize);
-                        fds = get_fd_array(&clients);
+                        clients.remove(n);
+                        challenges.remove(n);
+                        fds = fd_set(&clients);
                     }
                     ClientAction::None => {}
                 }
@@ -152,178 +203,624 @@ fn thread_loop(
     }
 }
 
-fn process_message(mut m: Message, storage: &Mutex<LocalStorage>) -> ClientAction {
-    return match c_try!(m.read_u8()) {
-        // set_user
-        0 => {
-            let mut storage = storage.lock().unwrap();
-            let key = c_try!(m.read_buffer()).to_vec();
-            let username = c_try!(m.read_buffer()).to_vec();
+fn process_message(
+    mut m: Message,
+    challenge: &mut Option<[u8; 32]>,
+    storage: &Mutex<Box<dyn Storage>>,
+    key: &PKey<Private>,
+) -> ClientAction {
+    let request = match Request::decode(&mut m) {
+        Ok(request) => request,
+        // Newer clients may send requests this server does not know yet
+        Err(fver_proto::Error::UnknownOpcode(op)) => {
+            debug!("Unsupported opcode {}", op);
+            return Respond(unsupported());
+        }
+        Err(_) => return Disconnect,
+    };
+    match request {
+        Request::SetUser { user, proof } => {
+            // Challenge is single-use, a failed attempt has to request a new one
+            let status = match challenge.take() {
+                None => SetUserStatus::NoChallenge,
+                Some(challenge) => match user.verify_proof(&challenge, &proof) {
+                    Ok(true) => match storage.lock().unwrap().set_user(user) {
+                        Ok(_) => SetUserStatus::Ok,
+                        Err(_) => SetUserStatus::Failed,
+                    },
+                    _ => SetUserStatus::InvalidProof,
+                },
+            };
+            Respond(status.encode())
+        }
+
+        Request::GetUser { hash } => {
+            let user = storage.lock().unwrap().get_user(&hash);
+            Respond(lookup(user).encode())
+        }
+
+        Request::GetObj { hash } => {
+            let obj = storage.lock().unwrap().get_obj(&hash);
+            Respond(lookup(obj.map(|obj| obj.map(|obj| obj.sigs))).encode())
+        }
+
+        Request::GetSig { hash } => {
+            let sig = storage.lock().unwrap().get_sig(&hash);
+            Respond(lookup(sig).encode())
+        }
+
+        Request::RotateKey { rotation } => Respond(rotate_key(storage, rotation).encode()),
 
-            let u = User { username, key };
-            let mut resp = Message::new();
-            match storage.set_user(u) {
+        Request::GetKeyHistory { hash } => {
+            let history = storage.lock().unwrap().get_key_history(&hash);
+            Respond(lookup(history).encode())
+        }
+
+        Request::RevokeKey { revocation } => Respond(revoke_key(storage, revocation).encode()),
+
+        Request::GetRevocations { hash } => {
+            let revocations = storage.lock().unwrap().get_revocations(&hash);
+            Respond(lookup(revocations).encode())
+        }
+
+        Request::WithdrawSig { withdrawal } => Respond(withdraw_sig(storage, withdrawal).encode()),
+
+        Request::GetWithdrawals { hash } => {
+            let withdrawals = storage.lock().unwrap().get_withdrawals(&hash);
+            Respond(lookup(withdrawals).encode())
+        }
+
+        Request::RequestEnqueue => Enqueue,
+
+        Request::GetChallenge => {
+            let mut nonce = [0; 32];
+            let resp = match rand_bytes(&mut nonce) {
                 Ok(_) => {
-                    resp.write_i8(0);
+                    *challenge = Some(nonce);
+                    Lookup::Found(nonce)
                 }
-                Err(_) => {
-                    resp.write_i8(-1);
-                }
-            }
-            Respond(resp)
+                Err(_) => Lookup::Failed,
+            };
+            Respond(resp.encode())
         }
 
-        // get_user
-        1 => {
-            let mut storage = storage.lock().unwrap();
-            let hash = c_try!(m.read_buffer()).to_vec();
-
-            let mut resp = Message::new();
-            match storage.get_user(&hash) {
-                Ok(opt) => match opt {
-                    None => {
-                        resp.write_i8(0);
-                    }
-                    Some(u) => {
-                        resp.write_i8(1);
-                        resp.write_buffer(&u.username);
-                        resp.write_buffer(&u.key);
-                    }
+        Request::GetInclusionProof { hash, size } => {
+            let storage = storage.lock().unwrap();
+            let log = storage.log();
+            let size = size.map_or(log.size(), |size| size as usize);
+            let resp = match log.position(&hash).filter(|index| *index < size) {
+                None => Lookup::NotFound,
+                Some(index) => match (log.root(size), log.inclusion_proof(index, size)) {
+                    (Ok(root), Ok(path)) => Lookup::Found(InclusionProof {
+                        index: index as u64,
+                        size: size as u64,
+                        root,
+                        path,
+                    }),
+                    _ => Lookup::Failed,
                 },
-                Err(_) => {
-                    resp.write_i8(-1);
-                }
-            }
-            Respond(resp)
+            };
+            Respond(resp.encode())
         }
 
-        // get_obj
-        2 => {
-            let mut storage = storage.lock().unwrap();
-            let hash = c_try!(m.read_buffer()).to_vec();
+        Request::GetConsistencyProof { first, second } => {
+            let storage = storage.lock().unwrap();
+            let proof = storage
+                .log()
+                .consistency_proof(first as usize, second as usize);
+            Respond(lookup(proof.map(Some)).encode())
+        }
 
-            let mut resp = Message::new();
-            match storage.get_obj(&hash) {
-                Ok(obj) => match obj {
-                    None => {
-                        resp.write_i8(0);
-                    }
-                    Some(obj) => {
-                        resp.write_i8(1);
-                        for s in obj.sigs {
-                            resp.write_buffer(&s);
-                        }
-                    }
-                },
-                Err(_) => {
-                    resp.write_i8(-1);
-                }
-            }
-            Respond(resp)
+        Request::GetTreeHead { size } => {
+            let storage = storage.lock().unwrap();
+            let log = storage.log();
+            let size = size.map_or(log.size(), |size| size as usize);
+            let resp = match log.root(size) {
+                Ok(root) => Lookup::Found(TreeHead {
+                    size: size as u64,
+                    root,
+                }),
+                Err(_) => Lookup::Failed,
+            };
+            Respond(resp.encode())
         }
 
-        // get_sig
-        3 => {
+        Request::GetCheckpoint => {
             let storage = storage.lock().unwrap();
-            let hash = c_try!(m.read_buffer()).to_vec();
+            let checkpoint = signed_checkpoint(storage.as_ref(), key);
+            Respond(lookup(checkpoint.map(Some)).encode())
+        }
 
-            let mut resp = Message::new();
-            match storage.get_sig(&hash) {
-                Ok(sig) => match sig {
-                    None => {
-                        resp.write_i8(0);
-                    }
-                    Some(sig) => {
-                        resp.write_i8(1);
-                        resp.write_buffer(&sig.obj);
-                        resp.write_buffer(&sig.user);
-                        resp.write_buffer(&sig.prev_sig);
-                        resp.write_buffer(&sig.signature);
-                    }
-                },
-                Err(_) => {
-                    resp.write_i8(-1);
+        Request::GetHead => {
+            let head = storage.lock().unwrap().get_prev();
+            Respond(lookup(head).encode())
+        }
+
+        Request::AddSig {
+            id,
+            expected_prev,
+            sig,
+        } => {
+            let result = add_sig(storage, expected_prev, sig);
+            // Lets the client re-sign right away if the head has moved
+            let head = storage.lock().unwrap().get_prev();
+            Respond(
+                SignResponse {
+                    id,
+                    status: sign_status(&result),
+                    head: head.ok().flatten().unwrap_or([0; 32]),
                 }
+                .encode(),
+            )
+        }
+
+        Request::Hello {
+            min_version,
+            max_version,
+            capabilities,
+        } => {
+            let resp = HelloResponse::negotiate(min_version, max_version, CAPABILITIES);
+            if resp.status == HelloStatus::IncompatibleVersion {
+                info!(
+                    "Client speaks protocol {}-{}, incompatible",
+                    min_version, max_version
+                );
+            } else {
+                debug!(
+                    "Client speaks protocol {} with capabilities {:#x}",
+                    resp.version, capabilities
+                );
             }
-            Respond(resp)
+            Respond(resp.encode())
         }
+    }
+}
 
-        // request_enqueue
-        4 => Enqueue,
-        _ => Disconnect,
-    };
+// The client only learns that the server failed, details go to the log
+fn lookup<T>(r: Result<Option<T>, Error>) -> Lookup<T> {
+    match r {
+        Ok(Some(v)) => Lookup::Found(v),
+        Ok(None) => Lookup::NotFound,
+        Err(e) => {
+            debug!("Request failed: {:?}", e);
+            Lookup::Failed
+        }
+    }
 }
 
-#[allow(unused_must_use)]
-fn queue_loop(rx: Receiver<ThreadMessage>, storage: Arc<Mutex<LocalStorage>>) {
-    loop {
-        match rx.try_recv() {
-            Ok(m) => match m {
-                Accept(client) => {
-                    // We don't care if the enqueue was successful or not
-                    handle_enqueue(client, &storage);
-                }
-            },
-            Err(_) => {}
+fn queue_loop(rx: Receiver<ThreadMessage>, storage: Arc<Mutex<Box<dyn Storage>>>, timeout: i32) {
+    while let Ok(Accept(client)) = rx.recv() {
+        // We don't care if the enqueue was successful or not
+        if let Err(e) = handle_enqueue(client, &storage, timeout) {
+            debug!("Enqueue failed: {:?}", e);
         }
     }
 }
 
-fn handle_enqueue(mut client: TcpStream, storage: &Arc<Mutex<LocalStorage>>) -> Result<(), Error> {
-    let mut storage = storage.lock().unwrap();
-    match storage.get_prev() {
-        Ok(prev) => match prev {
-            Some(hash) => {
-                let mut m = Message::new();
-                m.write_i8(1);
-                m.write_buffer(&hash);
-                client.write(&m)?;
-            }
-            None => {
-                let mut m = Message::new();
-                m.write_i8(0);
-                client.write(&m)?;
-            }
-        },
+fn handle_enqueue(
+    mut client: Connection,
+    storage: &Arc<Mutex<Box<dyn Storage>>>,
+    timeout: i32,
+) -> Result<(), Error> {
+    // Lock is held only for the storage operations themselves, never while
+    // waiting for the client. The head is checked again when adding the
+    // signature, so a concurrent change is reported as a stale head.
+    let prev = storage.lock().unwrap().get_prev();
+    let head = match prev {
+        Ok(prev) => {
+            client.write(lookup(Ok(prev)).encode())?;
+            prev.unwrap_or([0; 32])
+        }
         Err(_) => {
-            let mut m = Message::new();
-            m.write_i8(-1);
-            client.write(&m)?;
+            client.write(Lookup::<[u8; 32]>::Failed.encode())?;
             return Err(CorruptedStorage);
         }
-    }
+    };
 
-    match client.read_timeout(1000) {
+    match client.read_timeout(timeout) {
         Ok(m) => match m {
             None => {}
             Some(mut m) => {
-                let obj = m.read_buffer()?.to_vec()[..].try_into()?;
-                let user = m.read_buffer()?.to_vec()[..].try_into()?;
-                let prev_sig = m.read_buffer()?.to_vec()[..].try_into()?;
-                let signature = m.read_buffer()?.to_vec()[..].try_into()?;
-
-                let sig = Signature {
-                    obj,
-                    user,
-                    prev_sig,
-                    signature,
-                };
-
-                let mut resp = Message::new();
-                match storage.add_sig(sig) {
-                    Ok(_) => {
-                        resp.write_i8(0);
-                    }
-                    Err(_) => {
-                        resp.write_i8(-1);
-                    }
-                }
-                client.write(&resp)?;
+                let sig = Signature::read_message(&mut m)?;
+                let result = add_sig(storage, head, sig);
+
+                client.write(sign_status(&result).encode())?;
             }
         },
-        Err(_) => {
-            println!("e");
+        Err(e) => {
+            debug!("Failed to read signature: {:?}", e);
         }
     }
     Ok(())
 }
+
+fn add_sig(
+    storage: &Mutex<Box<dyn Storage>>,
+    expected_prev: [u8; 32],
+    sig: Signature,
+) -> Result<(), Error> {
+    // Held from the check on, so the key can't be rotated before the signature is added
+    let mut storage = storage.lock().unwrap();
+    let result = check_sig(storage.as_mut(), expected_prev, &sig)
+        .and_then(|_| storage.add_sig(expected_prev, sig));
+    match &result {
+        // Expected when clients race for the head, they retry
+        Err(StaleChainHead) => debug!("Rejected signature: StaleChainHead"),
+        Err(e) => warn!("Rejected signature: {:?}", e),
+        Ok(_) => {}
+    }
+    result
+}
+
+fn sign_status(result: &Result<(), Error>) -> AddSigStatus {
+    match result {
+        Ok(_) => AddSigStatus::Accepted,
+        Err(StaleChainHead) => AddSigStatus::StaleChainHead,
+        Err(UnknownUser) => AddSigStatus::UnknownUser,
+        Err(InvalidSignature) => AddSigStatus::InvalidSignature,
+        Err(RevokedKey) => AddSigStatus::RevokedKey,
+        Err(_) => AddSigStatus::StorageError,
+    }
+}
+
+fn check_sig(storage: &mut dyn Storage, head: [u8; 32], sig: &Signature) -> Result<(), Error> {
+    if sig.prev_sig != head {
+        return Err(StaleChainHead);
+    }
+    let user = storage.get_user(&sig.user)?;
+    let user = user.ok_or(UnknownUser)?;
+    if !sig.verify(&user.key)? {
+        return Err(InvalidSignature);
+    }
+    if is_revoked(storage, &sig.user, &user.key)? {
+        return Err(RevokedKey);
+    }
+    Ok(())
+}
+
+// Revocations are checked when stored, so they are trusted here
+fn is_revoked(storage: &mut dyn Storage, hash: &[u8], key: &[u8]) -> Result<bool, Error> {
+    let index = storage.log().size() as u64;
+    let revocations = storage.get_revocations(hash)?.unwrap_or_default();
+    Ok(revocations.iter().any(|r| r.covers(key, index)))
+}
+
+// The rotation takes effect at the current log size, which can't move while the lock is held
+fn rotate_key(storage: &Mutex<Box<dyn Storage>>, rotation: KeyRotation) -> RotateKeyStatus {
+    let mut storage = storage.lock().unwrap();
+    let username = String::from_utf8_lossy(&rotation.username).into_owned();
+    let current = match storage.get_user(&sha256(&rotation.username)) {
+        Ok(Some(user)) => user.key,
+        Ok(None) => return RotateKeyStatus::UnknownUser,
+        Err(e) => {
+            warn!("Key rotation of {} failed: {:?}", username, e);
+            return RotateKeyStatus::Failed;
+        }
+    };
+    if current != rotation.old_key {
+        return RotateKeyStatus::KeyMismatch;
+    }
+    if rotation.since != storage.log().size() as u64 {
+        return RotateKeyStatus::StaleLog;
+    }
+    match is_revoked(storage.as_mut(), &sha256(&rotation.username), &current) {
+        Ok(false) => {}
+        Ok(true) => return RotateKeyStatus::RevokedKey,
+        Err(e) => {
+            warn!("Key rotation of {} failed: {:?}", username, e);
+            return RotateKeyStatus::Failed;
+        }
+    }
+    if !rotation.verify().unwrap_or(false) {
+        return RotateKeyStatus::InvalidSignature;
+    }
+    match storage.rotate_key(rotation) {
+        Ok(_) => {
+            info!("Rotated key of {}", username);
+            RotateKeyStatus::Ok
+        }
+        Err(e) => {
+            warn!("Key rotation of {} failed: {:?}", username, e);
+            RotateKeyStatus::Failed
+        }
+    }
+}
+
+// Any key the user has had can be revoked, by itself or by the current key
+fn revoke_key(storage: &Mutex<Box<dyn Storage>>, revocation: Revocation) -> RevokeKeyStatus {
+    let mut storage = storage.lock().unwrap();
+    let username = String::from_utf8_lossy(&revocation.username).into_owned();
+    let hash = sha256(&revocation.username);
+    let (current, history) = match (storage.get_user(&hash), storage.get_key_history(&hash)) {
+        (Ok(Some(user)), Ok(Some(history))) => (user.key, history),
+        (Ok(None), _) | (_, Ok(None)) => return RevokeKeyStatus::UnknownUser,
+        (Err(e), _) | (_, Err(e)) => {
+            warn!("Key revocation of {} failed: {:?}", username, e);
+            return RevokeKeyStatus::Failed;
+        }
+    };
+    if revocation.key != current && !history.iter().any(|r| r.old_key == revocation.key) {
+        return RevokeKeyStatus::UnknownKey;
+    }
+    if !revocation.verify(&current).unwrap_or(false) {
+        return RevokeKeyStatus::InvalidSignature;
+    }
+    match storage.add_revocation(revocation) {
+        Ok(_) => {
+            info!("Revoked a key of {}", username);
+            RevokeKeyStatus::Ok
+        }
+        Err(e) => {
+            warn!("Key revocation of {} failed: {:?}", username, e);
+            RevokeKeyStatus::Failed
+        }
+    }
+}
+
+// Only the signer's current key can withdraw, so a key compromised and
+// revoked since can't be used to withdraw signatures made with it
+fn withdraw_sig(storage: &Mutex<Box<dyn Storage>>, withdrawal: Withdrawal) -> WithdrawSigStatus {
+    let mut storage = storage.lock().unwrap();
+    let sig_hash = hex::encode(&withdrawal.sig[..8]);
+    match check_withdrawal(storage.as_mut(), &withdrawal) {
+        Ok(WithdrawSigStatus::Ok) => {}
+        Ok(status) => return status,
+        Err(e) => {
+            warn!("Withdrawal of {} failed: {:?}", sig_hash, e);
+            return WithdrawSigStatus::Failed;
+        }
+    }
+    match storage.add_withdrawal(withdrawal) {
+        Ok(_) => {
+            info!("Withdrew signature {}", sig_hash);
+            WithdrawSigStatus::Ok
+        }
+        Err(e) => {
+            warn!("Withdrawal of {} failed: {:?}", sig_hash, e);
+            WithdrawSigStatus::Failed
+        }
+    }
+}
+
+fn check_withdrawal(
+    storage: &mut dyn Storage,
+    withdrawal: &Withdrawal,
+) -> Result<WithdrawSigStatus, Error> {
+    // Longer fields could be stored but never read back
+    if withdrawal.reason.len() > MAX_FIELD_LEN {
+        return Ok(WithdrawSigStatus::ReasonTooLong);
+    }
+    if withdrawal.signature.len() > MAX_FIELD_LEN {
+        return Ok(WithdrawSigStatus::InvalidSignature);
+    }
+    let sig = match storage.get_sig(&withdrawal.sig)? {
+        Some(sig) if sig.obj == withdrawal.obj && sig.user == withdrawal.user => sig,
+        _ => return Ok(WithdrawSigStatus::UnknownSignature),
+    };
+    let user = storage.get_user(&sig.user)?.ok_or(CorruptedStorage)?;
+    if !withdrawal.verify(&user.key)? {
+        return Ok(WithdrawSigStatus::InvalidSignature);
+    }
+    if is_revoked(storage, &sig.user, &user.key)? {
+        return Ok(WithdrawSigStatus::RevokedKey);
+    }
+    let withdrawals = storage.get_withdrawals(&sig.obj)?.unwrap_or_default();
+    if withdrawals.iter().any(|w| w.sig == withdrawal.sig) {
+        return Ok(WithdrawSigStatus::AlreadyWithdrawn);
+    }
+    Ok(WithdrawSigStatus::Ok)
+}
+
+fn signed_checkpoint(
+    storage: &dyn Storage,
+    key: &PKey<Private>,
+) -> Result<SignedCheckpoint, Error> {
+    let size = storage.log().size();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let checkpoint = Checkpoint {
+        size: size as u64,
+        root: storage.log().root(size)?,
+        head: storage.get_prev()?.unwrap_or([0; 32]),
+        timestamp,
+    };
+    Ok(SignedCheckpoint {
+        key: key.public_key_to_der()?,
+        signature: checkpoint.sign(key)?,
+        checkpoint,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::User;
+    use crate::testutil::{key, sign, statement, test_dir, user, withdrawal};
+    use std::fs::remove_dir_all;
+
+    // Response of the server to `request` from a client that holds `challenge`
+    fn respond(
+        request: Request,
+        challenge: &mut Option<[u8; 32]>,
+        storage: &Mutex<Box<dyn Storage>>,
+    ) -> Message {
+        match process_message(request.encode(), challenge, storage, &key()) {
+            Respond(m) => m,
+            _ => panic!("no response"),
+        }
+    }
+
+    fn get_challenge(
+        challenge: &mut Option<[u8; 32]>,
+        storage: &Mutex<Box<dyn Storage>>,
+    ) -> [u8; 32] {
+        let mut m = respond(Request::GetChallenge, challenge, storage);
+        match Lookup::decode(&mut m).unwrap() {
+            Lookup::Found(nonce) => nonce,
+            _ => panic!("no challenge"),
+        }
+    }
+
+    fn set_user(
+        user: User,
+        proof: Vec<u8>,
+        challenge: &mut Option<[u8; 32]>,
+        storage: &Mutex<Box<dyn Storage>>,
+    ) -> SetUserStatus {
+        let mut m = respond(Request::SetUser { user, proof }, challenge, storage);
+        SetUserStatus::decode(&mut m).unwrap()
+    }
+
+    fn add(
+        storage: &Mutex<Box<dyn Storage>>,
+        expected_prev: [u8; 32],
+        sig: Signature,
+    ) -> SignResponse {
+        let request = Request::AddSig {
+            id: 7,
+            expected_prev,
+            sig,
+        };
+        let mut m = respond(request, &mut None, storage);
+        SignResponse::decode(&mut m).unwrap()
+    }
+
+    // Proof of possession of `key` for alice, as the client sends it
+    fn proof(key: &PKey<Private>, nonce: [u8; 32]) -> Vec<u8> {
+        statement(key, &[&nonce[..], b"alice"].concat())
+    }
+
+    #[test]
+    fn registration_needs_a_fresh_proof() {
+        let dir = test_dir("register");
+        let storage = Mutex::new(storage::open(Backend::Filesystem, &dir).unwrap());
+        let alice = key();
+        let mut challenge = None;
+
+        let status = set_user(user("alice", &alice), vec![1; 64], &mut challenge, &storage);
+        assert_eq!(status, SetUserStatus::NoChallenge);
+
+        // Proof made with another key than the one registered
+        let nonce = get_challenge(&mut challenge, &storage);
+        let forged = proof(&key(), nonce);
+        let status = set_user(user("alice", &alice), forged, &mut challenge, &storage);
+        assert_eq!(status, SetUserStatus::InvalidProof);
+        // The challenge is used up by the failed attempt
+        let status = set_user(
+            user("alice", &alice),
+            proof(&alice, nonce),
+            &mut challenge,
+            &storage,
+        );
+        assert_eq!(status, SetUserStatus::NoChallenge);
+
+        // A proof for an earlier challenge does not count for a new one
+        let old = proof(&alice, nonce);
+        get_challenge(&mut challenge, &storage);
+        let status = set_user(user("alice", &alice), old, &mut challenge, &storage);
+        assert_eq!(status, SetUserStatus::InvalidProof);
+
+        let nonce = get_challenge(&mut challenge, &storage);
+        let status = set_user(
+            user("alice", &alice),
+            proof(&alice, nonce),
+            &mut challenge,
+            &storage,
+        );
+        assert_eq!(status, SetUserStatus::Ok);
+        assert!(storage
+            .lock()
+            .unwrap()
+            .get_user(&sha256(b"alice"))
+            .unwrap()
+            .is_some());
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn forged_signatures_are_rejected() {
+        let dir = test_dir("forged");
+        let storage = Mutex::new(storage::open(Backend::Filesystem, &dir).unwrap());
+        let alice = key();
+        storage
+            .lock()
+            .unwrap()
+            .set_user(user("alice", &alice))
+            .unwrap();
+
+        // Signed by a key alice never had
+        let resp = add(&storage, [0; 32], sign(&key(), "alice", [1; 32], [0; 32]));
+        assert_eq!(resp.status, AddSigStatus::InvalidSignature);
+        // Signed by alice, but for another object
+        let mut sig = sign(&alice, "alice", [1; 32], [0; 32]);
+        sig.obj = [2; 32];
+        let resp = add(&storage, [0; 32], sig);
+        assert_eq!(resp.status, AddSigStatus::InvalidSignature);
+        let mut sig = sign(&alice, "alice", [1; 32], [0; 32]);
+        let last = sig.signature.len() - 1;
+        sig.signature[last] ^= 1;
+        let resp = add(&storage, [0; 32], sig);
+        assert_eq!(resp.status, AddSigStatus::InvalidSignature);
+        let resp = add(&storage, [0; 32], sign(&alice, "bob", [1; 32], [0; 32]));
+        assert_eq!(resp.status, AddSigStatus::UnknownUser);
+        assert_eq!(storage.lock().unwrap().get_prev().unwrap(), None);
+
+        let sig = sign(&alice, "alice", [1; 32], [0; 32]);
+        let resp = add(&storage, [0; 32], sig.clone());
+        assert_eq!(resp.status, AddSigStatus::Accepted);
+        assert_eq!(resp.id, 7);
+        assert_eq!(resp.head, sig.hash());
+        // Replaying it, or signing against the old head, finds the head moved
+        let resp = add(&storage, [0; 32], sig.clone());
+        assert_eq!(resp.status, AddSigStatus::StaleChainHead);
+        assert_eq!(resp.head, sig.hash());
+        let resp = add(
+            &storage,
+            sig.hash(),
+            sign(&alice, "alice", [2; 32], [0; 32]),
+        );
+        assert_eq!(resp.status, AddSigStatus::StaleChainHead);
+        assert_eq!(storage.lock().unwrap().log().size(), 1);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn oversized_reasons_are_refused() {
+        for (name, backend) in &[
+            ("reason-fs", Backend::Filesystem),
+            ("reason-sqlite", Backend::Sqlite),
+        ] {
+            let dir = test_dir(name);
+            let storage = Mutex::new(storage::open(*backend, &dir).unwrap());
+            let key = key();
+            let sig = sign(&key, "alice", [1; 32], [0; 32]);
+            storage
+                .lock()
+                .unwrap()
+                .set_user(user("alice", &key))
+                .unwrap();
+            add_sig(&storage, [0; 32], sig.clone()).unwrap();
+
+            let reason = vec![b'x'; MAX_FIELD_LEN + 1];
+            let status = withdraw_sig(&storage, withdrawal(&key, &sig, &reason));
+            assert_eq!(status, WithdrawSigStatus::ReasonTooLong);
+            let reason = &reason[..MAX_FIELD_LEN];
+            let status = withdraw_sig(&storage, withdrawal(&key, &sig, reason));
+            assert_eq!(status, WithdrawSigStatus::Ok);
+
+            // Read back from storage, the object still verifies
+            let mut storage = storage.lock().unwrap();
+            let withdrawals = storage.get_withdrawals(&[1; 32]).unwrap().unwrap();
+            assert_eq!(withdrawals.len(), 1);
+            assert_eq!(withdrawals[0].reason, reason);
+            let alice = storage.get_user(&sig.user).unwrap().unwrap();
+            assert!(withdrawals[0].verify(&alice.key).unwrap());
+            let stored = storage.get_sig(&sig.hash()).unwrap().unwrap();
+            assert!(stored.verify(&alice.key).unwrap());
+            drop(storage);
+            remove_dir_all(&dir).unwrap();
+        }
+    }
+}